@@ -0,0 +1,67 @@
+//! Measures the throughput of `Interpreter::run()`, which records a history entry for
+//! every executed instruction.
+//!
+//! Sharing instructions with the program and keeping small value lists inline,
+//! rather than allocating for every history entry, took the throughput from:
+//!
+//! ```text
+//! run/countdown  5.19 -> 8.13 Melem/s
+//! run/effects    4.33 -> 9.38 Melem/s
+//! run/straight   5.76 -> 5.75 Melem/s
+//! ```
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use reversible_interpreter::assembler::assemble;
+use reversible_interpreter::interpreter::{Instruction, Interpreter};
+
+/// The number of loop iterations each benchmark runs.
+const ITERATIONS: i32 = 10_000;
+
+/// Benchmarks running `program` to completion, reporting instructions per second.
+fn bench_program(c: &mut Criterion, name: &str, program: &[Instruction]) {
+    let interpreter = || {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(program);
+        interpreter
+    };
+    let mut probe = interpreter();
+    probe.run().unwrap();
+
+    let mut group = c.benchmark_group("run");
+    group.throughput(Throughput::Elements(probe.history_len() as u64));
+    group.bench_function(name, |b| {
+        b.iter_batched(
+            interpreter,
+            |mut interpreter| interpreter.run().unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn bench_run(c: &mut Criterion) {
+    // Arithmetic and stack shuffling around conditional jumps.
+    let countdown = assemble(&format!(
+        "PUSH 0\nPUSH {ITERATIONS}\nloop:\nDUP\nJZ end\nTUCK\nADD\nSWAP\nPUSH 1\nSUB\nJMP loop\n\
+         end:\nPOP"
+    ))
+    .unwrap();
+    bench_program(c, "countdown", countdown.instructions());
+
+    // Variables, memory and subroutine calls.
+    let effects = assemble(&format!(
+        "PUSH {ITERATIONS}\nSTORE n\nloop:\nLOAD n\nDUP\nPUSH 7\n!\nCALL dec\nDUP\nSTORE n\n\
+         JNZ loop\nJMP end\ndec:\nPUSH 1\nSUB\nRET\nend:"
+    ))
+    .unwrap();
+    bench_program(c, "effects", effects.instructions());
+
+    // Straight-line code without jumps.
+    let straight: Vec<Instruction> = (0..ITERATIONS)
+        .flat_map(|i| [Instruction::Push(i), Instruction::Pop])
+        .collect();
+    bench_program(c, "straight", &straight);
+}
+
+criterion_group!(benches, bench_run);
+criterion_main!(benches);
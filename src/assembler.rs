@@ -0,0 +1,1026 @@
+//! This module implements the assembler that turns program text into a list of
+//! interpreter instructions. It is independent of the command-line interface, so
+//! library users can assemble programs without going through the shell.
+//!
+//! Program text consists of statements separated by newlines or semicolons (`;`).
+//! Each statement is an instruction mnemonic, matched case-insensitively, followed
+//! by its argument if it takes one. A label can be defined either with `LABEL name`
+//! or by writing `name:` on its own. Comments start with `#` or `;;` and run to the
+//! end of the line, and blank lines are ignored:
+//!
+//! ```text
+//! ;; Count down from 3
+//! PUSH 3
+//! loop:
+//!     PUSH 1; SUB     # decrement
+//!     DUP
+//!     JNZ loop
+//! ```
+//!
+//! `format_source` rewrites program text in canonical form: one uppercase statement
+//! per line, labels written as `name:`, and the instructions after a label indented.
+//! It keeps comments, and the `Display` implementation of `Program` prints assembled
+//! instructions the same way. Assembling formatted text always gives back the same
+//! instructions.
+//!
+//! Every assembled instruction keeps the `Span` of the statement it came from, so
+//! that tools can map a program counter back to the source. Assembly does not stop
+//! at the first error: all malformed statements, labels defined more than once and
+//! jumps or calls to undefined labels are reported as `Diagnostic`s, which render
+//! with the offending source line and a caret underline. `assemble_fragment`
+//! assembles part of a program, whose labels may be defined elsewhere.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use reversible_interpreter::assembler::assemble;
+//! use reversible_interpreter::interpreter::Instruction;
+//! let program = assemble("PUSH 2; PUSH 3\nADD").unwrap();
+//! assert_eq!(
+//!     program.instructions(),
+//!     &[Instruction::Push(2), Instruction::Push(3), Instruction::Add]
+//! );
+//! assert_eq!(program.span(2).unwrap().line, 2);
+//!
+//! let error = assemble("PUSH 1; FOO\nPUSH x").unwrap_err();
+//! assert_eq!(error.diagnostics().len(), 2);
+//! assert_eq!(
+//!     error.diagnostics()[0].to_string(),
+//!     "error: unknown instruction `FOO`\n --> 1:9\n  |\n1 | PUSH 1; FOO\n  |         ^^^"
+//! );
+//! ```
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::bytecode::{self, DecodeError};
+use crate::interpreter::Instruction;
+
+/// The location of a piece of program text: a 1-based `line` and `column`, and the
+/// length `len` in characters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+/// An assembled program: the instructions together with the source span of the
+/// statement each instruction was assembled from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Program {
+    instructions: Vec<Instruction>,
+    spans: Vec<Span>,
+}
+
+impl Program {
+    /// Constructs a program from instructions that have no source text.
+    pub fn new(instructions: Vec<Instruction>) -> Self {
+        Self {
+            instructions,
+            spans: Vec::new(),
+        }
+    }
+
+    /// Returns the instructions of the program.
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// Consumes the program and returns its instructions.
+    pub fn into_instructions(self) -> Vec<Instruction> {
+        self.instructions
+    }
+
+    /// Returns the source span of the instruction at `index`, or `None` if the
+    /// index is out of range or the program was not assembled from text.
+    pub fn span(&self, index: usize) -> Option<Span> {
+        self.spans.get(index).copied()
+    }
+
+    /// Returns the number of instructions in the program.
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    /// Returns `true` if the program has no instructions.
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    /// Encodes the program's instructions as bytecode. Source spans are not
+    /// included in the encoding.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bytecode::encode(&self.instructions)
+    }
+
+    /// Decodes a program from bytecode produced by `to_bytes`. The decoded program
+    /// has no source spans.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        bytecode::decode(bytes).map(Self::new)
+    }
+}
+
+/// Formats the program as canonical program text with one instruction per line.
+/// Instructions that follow a label definition are indented, so assembling the
+/// output gives back the same instructions.
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines: Vec<Line> = self
+            .instructions
+            .iter()
+            .map(|instruction| (Some(instruction), None))
+            .collect();
+        f.write_str(&render(&lines))
+    }
+}
+
+/// An error found while assembling a statement: a `message` about the text at
+/// `span`, and a copy of the `source_line` it occurred on for rendering.
+///
+/// The `Display` implementation renders the diagnostic with the source line and a
+/// caret underline, in the style of the Rust compiler.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+    pub source_line: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let line_number = self.span.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        // Keep tabs so the carets line up with the source line as displayed.
+        let indent: String = self
+            .source_line
+            .chars()
+            .take(self.span.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        writeln!(f, "error: {}", self.message)?;
+        writeln!(f, "{}--> {}:{}", gutter, self.span.line, self.span.column)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", line_number, self.source_line)?;
+        write!(
+            f,
+            "{} | {}{}",
+            gutter,
+            indent,
+            "^".repeat(self.span.len.max(1))
+        )
+    }
+}
+
+/// The error returned by `assemble`: every `Diagnostic` found in the program text,
+/// in source order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssemblyError {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl AssemblyError {
+    /// Returns the diagnostics, in source order.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, diagnostic) in self.diagnostics.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AssemblyError {}
+
+/// Assembles program text into a `Program`. If any statement is malformed, a label
+/// is defined more than once, or a jump or call refers to a label the program does
+/// not define, returns an `AssemblyError` with a diagnostic for each of them.
+pub fn assemble(source: &str) -> Result<Program, AssemblyError> {
+    assemble_statements(source, true)
+}
+
+/// Assembles part of a program, such as the instructions the shell adds to the
+/// program of a running interpreter. Like `assemble`, but labels are not checked,
+/// as they may be defined elsewhere in the program.
+pub fn assemble_fragment(source: &str) -> Result<Program, AssemblyError> {
+    assemble_statements(source, false)
+}
+
+fn assemble_statements(source: &str, check_labels: bool) -> Result<Program, AssemblyError> {
+    let mut program = Program::default();
+    let mut diagnostics = Vec::new();
+    // The statements of the instructions that define or refer to a label.
+    let mut label_statements = Vec::new();
+
+    for (line_index, line) in source.lines().enumerate() {
+        for statement in statements(line, line_index + 1) {
+            match statement.parse() {
+                Ok(instruction) => {
+                    if label_name(&instruction).is_some() {
+                        label_statements.push((program.instructions.len(), statement.clone()));
+                    }
+                    program.instructions.push(instruction);
+                    program.spans.push(statement.span());
+                }
+                Err(diagnostic) => diagnostics.push(diagnostic),
+            }
+        }
+    }
+
+    if check_labels {
+        diagnostics.extend(label_diagnostics(&program.instructions, &label_statements));
+        diagnostics.sort_by_key(|diagnostic| (diagnostic.span.line, diagnostic.span.column));
+    }
+
+    if diagnostics.is_empty() {
+        Ok(program)
+    } else {
+        Err(AssemblyError { diagnostics })
+    }
+}
+
+/// Returns the label an instruction defines or refers to, if any.
+fn label_name(instruction: &Instruction) -> Option<&str> {
+    match instruction {
+        Instruction::Label(name)
+        | Instruction::Jmp(name)
+        | Instruction::Jz(name)
+        | Instruction::Jnz(name)
+        | Instruction::Jeq(name)
+        | Instruction::Jne(name)
+        | Instruction::Jlt(name)
+        | Instruction::Jgt(name)
+        | Instruction::Jle(name)
+        | Instruction::Jge(name)
+        | Instruction::Call(name) => Some(name),
+        _ => None,
+    }
+}
+
+/// Reports the labels of `instructions` that are defined more than once, and the
+/// references to labels that are not defined, given the index and statement of
+/// each instruction that defines or refers to a label.
+fn label_diagnostics(
+    instructions: &[Instruction],
+    statements: &[(usize, Statement)],
+) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut defined = BTreeSet::new();
+    for (index, statement) in statements {
+        if let Instruction::Label(name) = &instructions[*index] {
+            if !defined.insert(name.as_str()) {
+                diagnostics.push(statement.error(
+                    statement.label_token(),
+                    format!("label `{}` is defined more than once", name),
+                ));
+            }
+        }
+    }
+    for (index, statement) in statements {
+        let instruction = &instructions[*index];
+        let Some(name) = label_name(instruction) else {
+            continue;
+        };
+        if !matches!(instruction, Instruction::Label(_)) && !defined.contains(name) {
+            diagnostics.push(statement.error(
+                statement.label_token(),
+                format!("undefined label `{}`", name),
+            ));
+        }
+    }
+    diagnostics
+}
+
+/// Formats program text: each statement is rewritten in canonical syntax on a line
+/// of its own, instructions that follow a label definition are indented, and runs
+/// of blank lines are collapsed. Comments are kept, a trailing comment staying with
+/// the last statement of its line. If any statement is malformed, returns an
+/// `AssemblyError` with a diagnostic for each of them, as `assemble` does.
+pub fn format_source(source: &str) -> Result<String, AssemblyError> {
+    let mut lines: Vec<(Option<Instruction>, Option<&str>)> = Vec::new();
+    let mut diagnostics = Vec::new();
+
+    for (line_index, line) in source.lines().enumerate() {
+        let comment = Some(line[strip_comment(line).len()..].trim()).filter(|c| !c.is_empty());
+        let mut instructions = Vec::new();
+        for statement in statements(line, line_index + 1) {
+            match statement.parse() {
+                Ok(instruction) => instructions.push(instruction),
+                Err(diagnostic) => diagnostics.push(diagnostic),
+            }
+        }
+        if instructions.is_empty() {
+            lines.push((None, comment));
+        }
+        let last = instructions.len().saturating_sub(1);
+        for (index, instruction) in instructions.into_iter().enumerate() {
+            lines.push((Some(instruction), comment.filter(|_| index == last)));
+        }
+    }
+
+    if !diagnostics.is_empty() {
+        return Err(AssemblyError { diagnostics });
+    }
+    let lines: Vec<Line> = lines
+        .iter()
+        .map(|(instruction, comment)| (instruction.as_ref(), *comment))
+        .collect();
+    Ok(render(&lines))
+}
+
+/// A line of formatted program text: the instruction on it and its comment. A
+/// line with neither is blank.
+type Line<'a> = (Option<&'a Instruction>, Option<&'a str>);
+
+/// The indentation of instructions that follow a label definition.
+const INDENT: &str = "    ";
+
+/// Renders formatted lines, indenting instructions after the first label and
+/// comment-only lines like the instruction that follows them.
+fn render(lines: &[Line]) -> String {
+    let mut indents = vec![""; lines.len()];
+    let mut after_label = false;
+    for (index, (instruction, _)) in lines.iter().enumerate() {
+        if let Some(instruction) = instruction {
+            let is_label = matches!(instruction, Instruction::Label(_));
+            after_label |= is_label;
+            if after_label && !is_label {
+                indents[index] = INDENT;
+            }
+        }
+    }
+    let mut next_indent = "";
+    for (index, (instruction, _)) in lines.iter().enumerate().rev() {
+        if instruction.is_some() {
+            next_indent = indents[index];
+        } else {
+            indents[index] = next_indent;
+        }
+    }
+
+    let mut output = String::new();
+    let mut pending_blank = false;
+    for (line, indent) in lines.iter().zip(indents) {
+        let text = match line {
+            (None, None) => {
+                pending_blank = !output.is_empty();
+                continue;
+            }
+            (Some(instruction), Some(comment)) => format!("{} {}", instruction, comment),
+            (Some(instruction), None) => instruction.to_string(),
+            (None, Some(comment)) => comment.to_string(),
+        };
+        if pending_blank {
+            output.push('\n');
+            pending_blank = false;
+        }
+        output.push_str(indent);
+        output.push_str(&text);
+        output.push('\n');
+    }
+    output
+}
+
+/// A whitespace-separated word of a statement and the byte offset it starts at
+/// within its line.
+#[derive(Clone, Copy, Debug)]
+struct Token<'a> {
+    text: &'a str,
+    offset: usize,
+}
+
+/// The tokens of a single non-empty statement, with the line they are on.
+#[derive(Clone, Debug)]
+struct Statement<'a> {
+    tokens: Vec<Token<'a>>,
+    line: &'a str,
+    line_number: usize,
+}
+
+/// Returns `line` without its trailing comment, if any. Comments start with `#`
+/// or `;;`.
+fn strip_comment(line: &str) -> &str {
+    let end = [line.find('#'), line.find(";;")]
+        .into_iter()
+        .flatten()
+        .min()
+        .unwrap_or(line.len());
+    &line[..end]
+}
+
+/// Splits a line into its non-empty, semicolon-separated statements, ignoring
+/// any comment.
+fn statements(line: &str, line_number: usize) -> Vec<Statement<'_>> {
+    let mut statements = Vec::new();
+    let mut offset = 0;
+    for part in strip_comment(line).split(';') {
+        let tokens: Vec<Token> = tokenize(part, offset);
+        if !tokens.is_empty() {
+            statements.push(Statement {
+                tokens,
+                line,
+                line_number,
+            });
+        }
+        offset += part.len() + 1;
+    }
+    statements
+}
+
+/// Splits `text`, which starts at byte `offset` of its line, into tokens.
+fn tokenize(text: &str, offset: usize) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (index, c) in text.char_indices().chain([(text.len(), ' ')]) {
+        match (start, c.is_whitespace()) {
+            (None, false) => start = Some(index),
+            (Some(begin), true) => {
+                tokens.push(Token {
+                    text: &text[begin..index],
+                    offset: offset + begin,
+                });
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    tokens
+}
+
+impl<'a> Statement<'a> {
+    /// Returns the span from the start of the first token to the end of the last.
+    fn span(&self) -> Span {
+        let first = self.tokens[0];
+        let last = self.tokens[self.tokens.len() - 1];
+        let end = last.offset + last.text.len();
+        Span {
+            line: self.line_number,
+            column: self.line[..first.offset].chars().count() + 1,
+            len: self.line[first.offset..end].chars().count(),
+        }
+    }
+
+    /// Returns the token naming the label of a statement that defines or refers to
+    /// one, which is always its last. The `:` of a `name:` definition is left out.
+    fn label_token(&self) -> Token<'a> {
+        let token = self.tokens[self.tokens.len() - 1];
+        Token {
+            text: token.text.strip_suffix(':').unwrap_or(token.text),
+            offset: token.offset,
+        }
+    }
+
+    /// Builds a diagnostic about `token`.
+    fn error(&self, token: Token, message: String) -> Diagnostic {
+        Diagnostic {
+            message,
+            span: Span {
+                line: self.line_number,
+                column: self.line[..token.offset].chars().count() + 1,
+                len: token.text.chars().count(),
+            },
+            source_line: self.line.to_string(),
+        }
+    }
+
+    /// Parses the statement into an instruction.
+    fn parse(&self) -> Result<Instruction, Diagnostic> {
+        if let Some(name) = self.tokens[0].text.strip_suffix(':') {
+            return self.label_definition(name);
+        }
+        let mnemonic = self.tokens[0].text.to_uppercase();
+        let instruction = match mnemonic.as_str() {
+            "PUSH" => Instruction::Push(self.integer_argument()?),
+            "POP" => self.no_argument(Instruction::Pop)?,
+            "DUP" => self.no_argument(Instruction::Dup)?,
+            "SWAP" => self.no_argument(Instruction::Swap)?,
+            "OVER" => self.no_argument(Instruction::Over)?,
+            "ROT" => self.no_argument(Instruction::Rot)?,
+            "NIP" => self.no_argument(Instruction::Nip)?,
+            "TUCK" => self.no_argument(Instruction::Tuck)?,
+            "DROPN" => Instruction::DropN(self.count_argument()?),
+            "PICK" => Instruction::Pick(self.count_argument()?),
+            "ROLL" => Instruction::Roll(self.count_argument()?),
+            "ADD" => self.no_argument(Instruction::Add)?,
+            "SUB" => self.no_argument(Instruction::Sub)?,
+            "MUL" => self.no_argument(Instruction::Mul)?,
+            "DIV" => self.no_argument(Instruction::Div)?,
+            "MOD" => self.no_argument(Instruction::Mod)?,
+            "NEG" => self.no_argument(Instruction::Neg)?,
+            "ABS" => self.no_argument(Instruction::Abs)?,
+            "MIN" => self.no_argument(Instruction::Min)?,
+            "MAX" => self.no_argument(Instruction::Max)?,
+            "POW" => self.no_argument(Instruction::Pow)?,
+            "DIVMOD" => self.no_argument(Instruction::DivMod)?,
+            "EQ" => self.no_argument(Instruction::Eq)?,
+            "NE" => self.no_argument(Instruction::Ne)?,
+            "LT" => self.no_argument(Instruction::Lt)?,
+            "GT" => self.no_argument(Instruction::Gt)?,
+            "LE" => self.no_argument(Instruction::Le)?,
+            "GE" => self.no_argument(Instruction::Ge)?,
+            "AND" => self.no_argument(Instruction::And)?,
+            "OR" => self.no_argument(Instruction::Or)?,
+            "NOT" => self.no_argument(Instruction::Not)?,
+            "BAND" => self.no_argument(Instruction::BitAnd)?,
+            "BOR" => self.no_argument(Instruction::BitOr)?,
+            "BXOR" => self.no_argument(Instruction::BitXor)?,
+            "BNOT" => self.no_argument(Instruction::BitNot)?,
+            "SHL" => self.no_argument(Instruction::Shl)?,
+            "SHR" => self.no_argument(Instruction::Shr)?,
+            "USHR" => self.no_argument(Instruction::Ushr)?,
+            "ROTL" => self.no_argument(Instruction::Rotl)?,
+            "ROTR" => self.no_argument(Instruction::Rotr)?,
+            "LOAD" => Instruction::Load(self.name_argument()?),
+            "STORE" => Instruction::Store(self.name_argument()?),
+            "@" => self.no_argument(Instruction::FetchCell)?,
+            "!" => self.no_argument(Instruction::StoreCell)?,
+            "LABEL" => Instruction::Label(self.name_argument()?),
+            "JMP" => Instruction::Jmp(self.name_argument()?),
+            "JZ" => Instruction::Jz(self.name_argument()?),
+            "JNZ" => Instruction::Jnz(self.name_argument()?),
+            "JEQ" => Instruction::Jeq(self.name_argument()?),
+            "JNE" => Instruction::Jne(self.name_argument()?),
+            "JLT" => Instruction::Jlt(self.name_argument()?),
+            "JGT" => Instruction::Jgt(self.name_argument()?),
+            "JLE" => Instruction::Jle(self.name_argument()?),
+            "JGE" => Instruction::Jge(self.name_argument()?),
+            "CALL" => Instruction::Call(self.name_argument()?),
+            "RET" => self.no_argument(Instruction::Ret)?,
+            _ => {
+                return Err(self.error(
+                    self.tokens[0],
+                    format!("unknown instruction `{}`", self.tokens[0].text),
+                ))
+            }
+        };
+        Ok(instruction)
+    }
+
+    /// Parses a `name:` label definition, which must be a statement of its own.
+    fn label_definition(&self, name: &str) -> Result<Instruction, Diagnostic> {
+        if name.is_empty() {
+            return Err(self.error(self.tokens[0], "missing label name before `:`".to_string()));
+        }
+        match self.tokens.get(1) {
+            Some(&token) => Err(self.error(
+                token,
+                format!(
+                    "unexpected `{}` after label `{}`: a label must be on its own line",
+                    token.text, self.tokens[0].text
+                ),
+            )),
+            None => Ok(Instruction::Label(name.to_string())),
+        }
+    }
+
+    /// Checks that the statement has no argument and returns `instruction`.
+    fn no_argument(&self, instruction: Instruction) -> Result<Instruction, Diagnostic> {
+        match self.tokens.get(1) {
+            Some(&token) => Err(self.error(
+                token,
+                format!("{} takes no argument", instruction.mnemonic()),
+            )),
+            None => Ok(instruction),
+        }
+    }
+
+    /// Returns the single argument of the statement.
+    fn argument(&self, description: &str) -> Result<Token<'a>, Diagnostic> {
+        let mnemonic = self.tokens[0].text.to_uppercase();
+        match self.tokens[..] {
+            [_, argument] => Ok(argument),
+            [command] => Err(self.error(
+                command,
+                format!("{} requires one argument: {}", mnemonic, description),
+            )),
+            _ => Err(self.error(
+                self.tokens[2],
+                format!("{} takes only one argument", mnemonic),
+            )),
+        }
+    }
+
+    /// Parses the single `i32` argument of instructions such as `PUSH <value>`.
+    fn integer_argument(&self) -> Result<i32, Diagnostic> {
+        let token = self.argument("an integer")?;
+        token.text.parse::<i32>().map_err(|_| {
+            self.error(
+                token,
+                format!(
+                    "expected an integer in the i32 range, found `{}`",
+                    token.text
+                ),
+            )
+        })
+    }
+
+    /// Parses the single non-negative count argument of instructions such as `PICK <n>`.
+    fn count_argument(&self) -> Result<usize, Diagnostic> {
+        let token = self.argument("a non-negative count")?;
+        token.text.parse::<usize>().map_err(|_| {
+            self.error(
+                token,
+                format!("expected a non-negative count, found `{}`", token.text),
+            )
+        })
+    }
+
+    /// Parses the single name argument of instructions such as `JMP <label>` or
+    /// `STORE <name>`.
+    fn name_argument(&self) -> Result<String, Diagnostic> {
+        self.argument("a name").map(|token| token.text.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn assemble_one(source: &str) -> Result<Instruction, String> {
+        match assemble_fragment(source) {
+            Ok(program) => Ok(program.instructions()[0].clone()),
+            Err(error) => Err(error.diagnostics()[0].message.clone()),
+        }
+    }
+
+    #[test]
+    fn test_assemble_separators_and_spans() {
+        let program = assemble("  push 5;PUSH 3 ;\n\nADD\t").unwrap();
+        assert_eq!(
+            program.instructions(),
+            &[Instruction::Push(5), Instruction::Push(3), Instruction::Add]
+        );
+        assert_eq!(
+            program.span(0),
+            Some(Span {
+                line: 1,
+                column: 3,
+                len: 6
+            })
+        );
+        assert_eq!(
+            program.span(1),
+            Some(Span {
+                line: 1,
+                column: 10,
+                len: 6
+            })
+        );
+        assert_eq!(
+            program.span(2),
+            Some(Span {
+                line: 3,
+                column: 1,
+                len: 3
+            })
+        );
+        assert_eq!(program.span(3), None);
+    }
+
+    #[test]
+    fn test_assemble_empty() {
+        assert!(assemble("").unwrap().is_empty());
+        assert!(assemble(" ; ;\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_stack_shuffling_instructions() {
+        assert_eq!(assemble_one("dup"), Ok(Instruction::Dup));
+        assert_eq!(assemble_one("TUCK"), Ok(Instruction::Tuck));
+        assert_eq!(assemble_one("PICK 2"), Ok(Instruction::Pick(2)));
+        assert_eq!(assemble_one("roll 3"), Ok(Instruction::Roll(3)));
+        assert_eq!(assemble_one("DROPN 4"), Ok(Instruction::DropN(4)));
+        assert_eq!(
+            assemble_one("PICK -1"),
+            Err("expected a non-negative count, found `-1`".to_string())
+        );
+        assert_eq!(
+            assemble_one("ROLL"),
+            Err("ROLL requires one argument: a non-negative count".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_arithmetic_instructions() {
+        assert_eq!(assemble_one("MOD"), Ok(Instruction::Mod));
+        assert_eq!(assemble_one("neg"), Ok(Instruction::Neg));
+        assert_eq!(assemble_one("Pow"), Ok(Instruction::Pow));
+        assert_eq!(assemble_one("DIVMOD"), Ok(Instruction::DivMod));
+        assert_eq!(assemble_one("PUSH -7"), Ok(Instruction::Push(-7)));
+        assert_eq!(
+            assemble_one("PUSH 2147483648"),
+            Err("expected an integer in the i32 range, found `2147483648`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_comparison_instructions() {
+        assert_eq!(assemble_one("eq"), Ok(Instruction::Eq));
+        assert_eq!(assemble_one("LE"), Ok(Instruction::Le));
+        assert_eq!(assemble_one("And"), Ok(Instruction::And));
+        assert_eq!(assemble_one("NOT"), Ok(Instruction::Not));
+    }
+
+    #[test]
+    fn test_parse_bitwise_instructions() {
+        assert_eq!(assemble_one("BAND"), Ok(Instruction::BitAnd));
+        assert_eq!(assemble_one("bxor"), Ok(Instruction::BitXor));
+        assert_eq!(assemble_one("USHR"), Ok(Instruction::Ushr));
+        assert_eq!(assemble_one("rotr"), Ok(Instruction::Rotr));
+    }
+
+    #[test]
+    fn test_parse_memory_and_variable_instructions() {
+        assert_eq!(assemble_one("@"), Ok(Instruction::FetchCell));
+        assert_eq!(assemble_one("!"), Ok(Instruction::StoreCell));
+        assert_eq!(
+            assemble_one("store x"),
+            Ok(Instruction::Store("x".to_string()))
+        );
+        assert_eq!(
+            assemble_one("STORE"),
+            Err("STORE requires one argument: a name".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_control_flow_instructions() {
+        assert_eq!(
+            assemble_one("LABEL loop"),
+            Ok(Instruction::Label("loop".to_string()))
+        );
+        assert_eq!(
+            assemble_one("jnz loop"),
+            Ok(Instruction::Jnz("loop".to_string()))
+        );
+        assert_eq!(
+            assemble_one("JGE done"),
+            Ok(Instruction::Jge("done".to_string()))
+        );
+        assert_eq!(
+            assemble_one("CALL square"),
+            Ok(Instruction::Call("square".to_string()))
+        );
+        assert_eq!(assemble_one("ret"), Ok(Instruction::Ret));
+        assert_eq!(
+            assemble_one("JMP a b"),
+            Err("JMP takes only one argument".to_string())
+        );
+    }
+
+    #[test]
+    fn test_comments_and_blank_lines() {
+        let source = "\
+;; Sum 1 + 2
+# using a comment of each kind
+
+PUSH 1   # first
+PUSH 2 ;; second ; not a statement
+ADD; # trailing separator
+";
+        let program = assemble(source).unwrap();
+        assert_eq!(
+            program.instructions(),
+            &[Instruction::Push(1), Instruction::Push(2), Instruction::Add]
+        );
+        assert_eq!(program.span(0).unwrap().line, 4);
+        assert_eq!(program.span(2).unwrap().line, 6);
+    }
+
+    #[test]
+    fn test_label_definitions() {
+        let program = assemble("start:\n  JMP start\nend: # done").unwrap();
+        assert_eq!(
+            program.instructions(),
+            &[
+                Instruction::Label("start".to_string()),
+                Instruction::Jmp("start".to_string()),
+                Instruction::Label("end".to_string()),
+            ]
+        );
+        assert_eq!(
+            assemble_one("loop: PUSH 1"),
+            Err(
+                "unexpected `PUSH` after label `loop:`: a label must be on its own line"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            assemble_one(":"),
+            Err("missing label name before `:`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_instruction_invalid() {
+        assert_eq!(
+            assemble_one("INVALID"),
+            Err("unknown instruction `INVALID`".to_string())
+        );
+        assert_eq!(
+            assemble_one("POP 5"),
+            Err("POP takes no argument".to_string())
+        );
+    }
+
+    #[test]
+    fn test_collects_all_diagnostics() {
+        let error = assemble("PUSH 1; FOO\nPUSH\nADD 3; JMP").unwrap_err();
+        let spans: Vec<(usize, usize, usize)> = error
+            .diagnostics()
+            .iter()
+            .map(|d| (d.span.line, d.span.column, d.span.len))
+            .collect();
+        assert_eq!(spans, vec![(1, 9, 3), (2, 1, 4), (3, 5, 1), (3, 8, 3)]);
+    }
+
+    #[test]
+    fn test_label_diagnostics() {
+        let error =
+            assemble("start:\nJMP end; CALL sub\nLABEL start; start:\nJZ start").unwrap_err();
+        let diagnostics: Vec<(&str, usize, usize, usize)> = error
+            .diagnostics()
+            .iter()
+            .map(|d| (d.message.as_str(), d.span.line, d.span.column, d.span.len))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                ("undefined label `end`", 2, 5, 3),
+                ("undefined label `sub`", 2, 15, 3),
+                ("label `start` is defined more than once", 3, 7, 5),
+                ("label `start` is defined more than once", 3, 14, 5),
+            ]
+        );
+
+        // Labels may be defined after they are used, or elsewhere in a fragment
+        assert!(assemble("JMP end\nend:").is_ok());
+        assert!(assemble_fragment("JMP end; start:; start:").is_ok());
+    }
+
+    #[test]
+    fn test_diagnostic_rendering() {
+        let error = assemble("PUSH 1\n\tPUSH x").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "error: expected an integer in the i32 range, found `x`\n --> 2:7\n  |\n2 | \tPUSH x\n  | \t     ^"
+        );
+
+        let error = assemble("PUSH 1; FOO\nBAR").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "error: unknown instruction `FOO`\n --> 1:9\n  |\n1 | PUSH 1; FOO\n  |         ^^^\n\n\
+             error: unknown instruction `BAR`\n --> 2:1\n  |\n2 | BAR\n  | ^^^"
+        );
+    }
+
+    #[test]
+    fn test_program_bytes_round_trip() {
+        let program = assemble("PUSH 3\nloop:\nPUSH 1; SUB; DUP; JNZ loop").unwrap();
+        let decoded = Program::from_bytes(&program.to_bytes()).unwrap();
+        assert_eq!(decoded.instructions(), program.instructions());
+        assert_eq!(decoded.span(0), None);
+        assert_eq!(
+            Program::from_bytes(b"PUSH 3"),
+            Err(DecodeError::InvalidMagic)
+        );
+    }
+
+    fn name_strategy() -> impl Strategy<Value = String> {
+        "[a-z_][a-z0-9_]{0,8}"
+    }
+
+    fn instruction_strategy() -> impl Strategy<Value = Instruction> {
+        let simple = [
+            Instruction::Pop,
+            Instruction::Dup,
+            Instruction::Swap,
+            Instruction::Over,
+            Instruction::Rot,
+            Instruction::Nip,
+            Instruction::Tuck,
+            Instruction::Add,
+            Instruction::Sub,
+            Instruction::Mul,
+            Instruction::Div,
+            Instruction::Mod,
+            Instruction::Neg,
+            Instruction::Abs,
+            Instruction::Min,
+            Instruction::Max,
+            Instruction::Pow,
+            Instruction::DivMod,
+            Instruction::Eq,
+            Instruction::Ne,
+            Instruction::Lt,
+            Instruction::Gt,
+            Instruction::Le,
+            Instruction::Ge,
+            Instruction::And,
+            Instruction::Or,
+            Instruction::Not,
+            Instruction::BitAnd,
+            Instruction::BitOr,
+            Instruction::BitXor,
+            Instruction::BitNot,
+            Instruction::Shl,
+            Instruction::Shr,
+            Instruction::Ushr,
+            Instruction::Rotl,
+            Instruction::Rotr,
+            Instruction::FetchCell,
+            Instruction::StoreCell,
+            Instruction::Ret,
+        ];
+        let named: [fn(String) -> Instruction; 13] = [
+            Instruction::Load,
+            Instruction::Store,
+            Instruction::Label,
+            Instruction::Jmp,
+            Instruction::Jz,
+            Instruction::Jnz,
+            Instruction::Jeq,
+            Instruction::Jne,
+            Instruction::Jlt,
+            Instruction::Jgt,
+            Instruction::Jle,
+            Instruction::Jge,
+            Instruction::Call,
+        ];
+        let counted: [fn(usize) -> Instruction; 3] =
+            [Instruction::DropN, Instruction::Pick, Instruction::Roll];
+        prop_oneof![
+            any::<i32>().prop_map(Instruction::Push),
+            prop::sample::select(simple.to_vec()),
+            (prop::sample::select(named.to_vec()), name_strategy())
+                .prop_map(|(instruction, name)| instruction(name)),
+            (prop::sample::select(counted.to_vec()), any::<usize>())
+                .prop_map(|(instruction, count)| instruction(count)),
+        ]
+    }
+
+    #[test]
+    fn test_property_format_round_trip() {
+        proptest!(|(instructions in prop::collection::vec(instruction_strategy(), 0..50))| {
+            let text = Program::new(instructions.clone()).to_string();
+            prop_assert_eq!(assemble_fragment(&text).unwrap().into_instructions(), instructions.clone());
+
+            let formatted = format_source(&text).unwrap();
+            prop_assert_eq!(&formatted, &text);
+            prop_assert_eq!(assemble_fragment(&formatted).unwrap().into_instructions(), instructions);
+        });
+    }
+
+    #[test]
+    fn test_program_display() {
+        let program = assemble("push 3; LABEL loop\npush 1; sub; dup; jnz loop").unwrap();
+        assert_eq!(
+            program.to_string(),
+            "PUSH 3\nloop:\n    PUSH 1\n    SUB\n    DUP\n    JNZ loop\n"
+        );
+        assert_eq!(Program::default().to_string(), "");
+    }
+
+    #[test]
+    fn test_format_source_keeps_comments() {
+        let source = "\n\n;; Count down\npush 3   # start\n\n\n\
+                      # the loop\nloop:   ;; top\n  push 1; sub # decrement\n\
+                      \tdup ; jnz loop\n\n";
+        let formatted = format_source(source).unwrap();
+        assert_eq!(
+            formatted,
+            ";; Count down\nPUSH 3 # start\n\n# the loop\nloop: ;; top\n    PUSH 1\n    \
+             SUB # decrement\n    DUP\n    JNZ loop\n"
+        );
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+        assert_eq!(
+            assemble(&formatted).unwrap().instructions(),
+            assemble(source).unwrap().instructions()
+        );
+
+        let error = format_source("PUSH 1; FOO # comment\nBAR").unwrap_err();
+        assert_eq!(error.diagnostics().len(), 2);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_program() {
+        let program = assemble("PUSH 3\nloop:\n    PUSH 1; SUB\n    DUP; JNZ loop").unwrap();
+        let json = serde_json::to_string(&program).unwrap();
+        let restored: Program = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, program);
+        assert_eq!(restored.span(4).unwrap().line, 4);
+    }
+}
@@ -0,0 +1,509 @@
+//! This module implements a compact binary encoding of interpreter instructions,
+//! so that programs can be assembled once and shipped without their source text.
+//!
+//! An encoded program starts with a header: the four magic bytes `RSBC`, a format
+//! version byte, and the number of instructions as a varint. Each instruction
+//! follows as a one-byte opcode and its operand, if it has one:
+//!
+//! - `PUSH` values are 4-byte little-endian `i32`s.
+//! - Counts (`DROPN`, `PICK`, `ROLL`) are unsigned LEB128 varints.
+//! - Names (variables and labels) are a varint byte length followed by UTF-8 bytes.
+//!
+//! Decoding validates the whole input and rejects anything that is not exactly one
+//! encoded program, so `decode(&encode(p)) == p` for every program `p`.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use reversible_interpreter::bytecode::{decode, encode};
+//! use reversible_interpreter::interpreter::Instruction;
+//! let instructions = vec![Instruction::Push(-1), Instruction::Store("x".to_string())];
+//! let bytes = encode(&instructions);
+//! assert_eq!(&bytes[..4], b"RSBC");
+//! assert_eq!(decode(&bytes).unwrap(), instructions);
+//! ```
+
+use std::fmt;
+
+use crate::interpreter::Instruction;
+
+/// The magic bytes every encoded program starts with.
+pub const MAGIC: [u8; 4] = *b"RSBC";
+
+/// The version of the encoding written by `encode`, and the only one `decode` reads.
+pub const VERSION: u8 = 1;
+
+/// An error found while decoding bytecode. Offsets are byte positions in the input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input does not start with the `RSBC` magic bytes.
+    InvalidMagic,
+    /// The header names a format version this decoder does not understand.
+    UnsupportedVersion(u8),
+    /// The input ended in the middle of the header or of an instruction.
+    UnexpectedEnd { offset: usize },
+    /// A byte in opcode position does not name any instruction.
+    UnknownOpcode { opcode: u8, offset: usize },
+    /// A varint is too long or does not fit in a `usize`.
+    InvalidVarint { offset: usize },
+    /// A name operand is not valid UTF-8.
+    InvalidName { offset: usize },
+    /// There are bytes after the last instruction.
+    TrailingBytes { offset: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::InvalidMagic => write!(f, "not a bytecode program (bad magic number)"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "unsupported bytecode version {} (expected {})",
+                    version, VERSION
+                )
+            }
+            DecodeError::UnexpectedEnd { offset } => {
+                write!(f, "unexpected end of bytecode at byte {}", offset)
+            }
+            DecodeError::UnknownOpcode { opcode, offset } => {
+                write!(f, "unknown opcode 0x{:02x} at byte {}", opcode, offset)
+            }
+            DecodeError::InvalidVarint { offset } => {
+                write!(f, "invalid varint at byte {}", offset)
+            }
+            DecodeError::InvalidName { offset } => {
+                write!(f, "name at byte {} is not valid UTF-8", offset)
+            }
+            DecodeError::TrailingBytes { offset } => {
+                write!(f, "unexpected trailing bytes at byte {}", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Returns `true` if `bytes` starts with the bytecode magic number, i.e. looks
+/// like an encoded program rather than program text.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// Encodes instructions into bytecode, including the header.
+pub fn encode(instructions: &[Instruction]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(MAGIC.len() + 1 + instructions.len() * 2);
+    bytes.extend_from_slice(&MAGIC);
+    bytes.push(VERSION);
+    write_varint(&mut bytes, instructions.len());
+    for instruction in instructions {
+        encode_instruction(&mut bytes, instruction);
+    }
+    bytes
+}
+
+/// Decodes bytecode produced by `encode` back into instructions.
+pub fn decode(bytes: &[u8]) -> Result<Vec<Instruction>, DecodeError> {
+    let mut reader = Reader::new(bytes);
+
+    if !is_bytecode(bytes) {
+        return Err(DecodeError::InvalidMagic);
+    }
+    reader.offset = MAGIC.len();
+    let version = reader.byte()?;
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+
+    let count = reader.varint()?;
+    // Every instruction takes at least one byte, which bounds the allocation for
+    // corrupt counts.
+    let mut instructions = Vec::with_capacity(count.min(reader.remaining()));
+    for _ in 0..count {
+        instructions.push(reader.instruction()?);
+    }
+
+    if reader.remaining() > 0 {
+        return Err(DecodeError::TrailingBytes {
+            offset: reader.offset,
+        });
+    }
+    Ok(instructions)
+}
+
+/// Appends the opcode and operand of one instruction.
+pub(crate) fn encode_instruction(bytes: &mut Vec<u8>, instruction: &Instruction) {
+    bytes.push(opcode(instruction));
+    match instruction {
+        Instruction::Push(value) => bytes.extend_from_slice(&value.to_le_bytes()),
+        Instruction::DropN(count) | Instruction::Pick(count) | Instruction::Roll(count) => {
+            write_varint(bytes, *count)
+        }
+        Instruction::Load(name)
+        | Instruction::Store(name)
+        | Instruction::Label(name)
+        | Instruction::Jmp(name)
+        | Instruction::Jz(name)
+        | Instruction::Jnz(name)
+        | Instruction::Jeq(name)
+        | Instruction::Jne(name)
+        | Instruction::Jlt(name)
+        | Instruction::Jgt(name)
+        | Instruction::Jle(name)
+        | Instruction::Jge(name)
+        | Instruction::Call(name) => {
+            write_varint(bytes, name.len());
+            bytes.extend_from_slice(name.as_bytes());
+        }
+        _ => {}
+    }
+}
+
+/// Returns the opcode of an instruction. Opcodes are stable across releases of the
+/// same format version; new instructions must be given new opcodes.
+fn opcode(instruction: &Instruction) -> u8 {
+    match instruction {
+        Instruction::Push(_) => 0x00,
+        Instruction::Pop => 0x01,
+        Instruction::Dup => 0x02,
+        Instruction::Swap => 0x03,
+        Instruction::Over => 0x04,
+        Instruction::Rot => 0x05,
+        Instruction::Nip => 0x06,
+        Instruction::Tuck => 0x07,
+        Instruction::DropN(_) => 0x08,
+        Instruction::Pick(_) => 0x09,
+        Instruction::Roll(_) => 0x0a,
+        Instruction::Add => 0x10,
+        Instruction::Sub => 0x11,
+        Instruction::Mul => 0x12,
+        Instruction::Div => 0x13,
+        Instruction::Mod => 0x14,
+        Instruction::Neg => 0x15,
+        Instruction::Abs => 0x16,
+        Instruction::Min => 0x17,
+        Instruction::Max => 0x18,
+        Instruction::Pow => 0x19,
+        Instruction::DivMod => 0x1a,
+        Instruction::Eq => 0x20,
+        Instruction::Ne => 0x21,
+        Instruction::Lt => 0x22,
+        Instruction::Gt => 0x23,
+        Instruction::Le => 0x24,
+        Instruction::Ge => 0x25,
+        Instruction::And => 0x26,
+        Instruction::Or => 0x27,
+        Instruction::Not => 0x28,
+        Instruction::BitAnd => 0x30,
+        Instruction::BitOr => 0x31,
+        Instruction::BitXor => 0x32,
+        Instruction::BitNot => 0x33,
+        Instruction::Shl => 0x34,
+        Instruction::Shr => 0x35,
+        Instruction::Ushr => 0x36,
+        Instruction::Rotl => 0x37,
+        Instruction::Rotr => 0x38,
+        Instruction::Load(_) => 0x40,
+        Instruction::Store(_) => 0x41,
+        Instruction::FetchCell => 0x42,
+        Instruction::StoreCell => 0x43,
+        Instruction::Label(_) => 0x50,
+        Instruction::Jmp(_) => 0x51,
+        Instruction::Jz(_) => 0x52,
+        Instruction::Jnz(_) => 0x53,
+        Instruction::Jeq(_) => 0x54,
+        Instruction::Jne(_) => 0x55,
+        Instruction::Jlt(_) => 0x56,
+        Instruction::Jgt(_) => 0x57,
+        Instruction::Jle(_) => 0x58,
+        Instruction::Jge(_) => 0x59,
+        Instruction::Call(_) => 0x5a,
+        Instruction::Ret => 0x5b,
+    }
+}
+
+/// Appends `value` as an unsigned LEB128 varint.
+pub(crate) fn write_varint(bytes: &mut Vec<u8>, value: usize) {
+    let mut value = value as u64;
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// A cursor over the bytes being decoded.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, offset: 0 }
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.bytes.len() - self.offset
+    }
+
+    pub(crate) fn byte(&mut self) -> Result<u8, DecodeError> {
+        let byte = *self
+            .bytes
+            .get(self.offset)
+            .ok_or(DecodeError::UnexpectedEnd {
+                offset: self.offset,
+            })?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&[u8], DecodeError> {
+        if self.remaining() < len {
+            return Err(DecodeError::UnexpectedEnd {
+                offset: self.bytes.len(),
+            });
+        }
+        let bytes = &self.bytes[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn varint(&mut self) -> Result<usize, DecodeError> {
+        let start = self.offset;
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = u64::from(byte & 0x7f);
+            if shift == 63 && bits > 1 {
+                return Err(DecodeError::InvalidVarint { offset: start });
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return usize::try_from(value)
+                    .map_err(|_| DecodeError::InvalidVarint { offset: start });
+            }
+        }
+        Err(DecodeError::InvalidVarint { offset: start })
+    }
+
+    fn i32(&mut self) -> Result<i32, DecodeError> {
+        let bytes = self.take(4)?;
+        Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn name(&mut self) -> Result<String, DecodeError> {
+        let len = self.varint()?;
+        let start = self.offset;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidName { offset: start })
+    }
+
+    pub(crate) fn instruction(&mut self) -> Result<Instruction, DecodeError> {
+        let offset = self.offset;
+        let instruction = match self.byte()? {
+            0x00 => Instruction::Push(self.i32()?),
+            0x01 => Instruction::Pop,
+            0x02 => Instruction::Dup,
+            0x03 => Instruction::Swap,
+            0x04 => Instruction::Over,
+            0x05 => Instruction::Rot,
+            0x06 => Instruction::Nip,
+            0x07 => Instruction::Tuck,
+            0x08 => Instruction::DropN(self.varint()?),
+            0x09 => Instruction::Pick(self.varint()?),
+            0x0a => Instruction::Roll(self.varint()?),
+            0x10 => Instruction::Add,
+            0x11 => Instruction::Sub,
+            0x12 => Instruction::Mul,
+            0x13 => Instruction::Div,
+            0x14 => Instruction::Mod,
+            0x15 => Instruction::Neg,
+            0x16 => Instruction::Abs,
+            0x17 => Instruction::Min,
+            0x18 => Instruction::Max,
+            0x19 => Instruction::Pow,
+            0x1a => Instruction::DivMod,
+            0x20 => Instruction::Eq,
+            0x21 => Instruction::Ne,
+            0x22 => Instruction::Lt,
+            0x23 => Instruction::Gt,
+            0x24 => Instruction::Le,
+            0x25 => Instruction::Ge,
+            0x26 => Instruction::And,
+            0x27 => Instruction::Or,
+            0x28 => Instruction::Not,
+            0x30 => Instruction::BitAnd,
+            0x31 => Instruction::BitOr,
+            0x32 => Instruction::BitXor,
+            0x33 => Instruction::BitNot,
+            0x34 => Instruction::Shl,
+            0x35 => Instruction::Shr,
+            0x36 => Instruction::Ushr,
+            0x37 => Instruction::Rotl,
+            0x38 => Instruction::Rotr,
+            0x40 => Instruction::Load(self.name()?),
+            0x41 => Instruction::Store(self.name()?),
+            0x42 => Instruction::FetchCell,
+            0x43 => Instruction::StoreCell,
+            0x50 => Instruction::Label(self.name()?),
+            0x51 => Instruction::Jmp(self.name()?),
+            0x52 => Instruction::Jz(self.name()?),
+            0x53 => Instruction::Jnz(self.name()?),
+            0x54 => Instruction::Jeq(self.name()?),
+            0x55 => Instruction::Jne(self.name()?),
+            0x56 => Instruction::Jlt(self.name()?),
+            0x57 => Instruction::Jgt(self.name()?),
+            0x58 => Instruction::Jle(self.name()?),
+            0x59 => Instruction::Jge(self.name()?),
+            0x5a => Instruction::Call(self.name()?),
+            0x5b => Instruction::Ret,
+            opcode => return Err(DecodeError::UnknownOpcode { opcode, offset }),
+        };
+        Ok(instruction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn name_strategy() -> impl Strategy<Value = String> {
+        "[a-z_][a-z0-9_]{0,8}|[\\PC]{0,4}"
+    }
+
+    fn instruction_strategy() -> impl Strategy<Value = Instruction> {
+        prop_oneof![
+            any::<i32>().prop_map(Instruction::Push),
+            Just(Instruction::Pop),
+            Just(Instruction::Rot),
+            any::<usize>().prop_map(Instruction::DropN),
+            any::<usize>().prop_map(Instruction::Pick),
+            any::<usize>().prop_map(Instruction::Roll),
+            Just(Instruction::DivMod),
+            Just(Instruction::Ge),
+            Just(Instruction::Rotr),
+            name_strategy().prop_map(Instruction::Load),
+            name_strategy().prop_map(Instruction::Store),
+            Just(Instruction::FetchCell),
+            Just(Instruction::StoreCell),
+            name_strategy().prop_map(Instruction::Label),
+            name_strategy().prop_map(Instruction::Jge),
+            name_strategy().prop_map(Instruction::Call),
+            Just(Instruction::Ret),
+        ]
+    }
+
+    #[test]
+    fn test_property_round_trip() {
+        proptest!(|(instructions in prop::collection::vec(instruction_strategy(), 0..50))| {
+            prop_assert_eq!(decode(&encode(&instructions)), Ok(instructions));
+        });
+    }
+
+    #[test]
+    fn test_opcodes_are_unique() {
+        let mut opcodes: Vec<u8> = [
+            "PUSH 0", "POP", "DUP", "SWAP", "OVER", "ROT", "NIP", "TUCK", "DROPN 0", "PICK 0",
+            "ROLL 0", "ADD", "SUB", "MUL", "DIV", "MOD", "NEG", "ABS", "MIN", "MAX", "POW",
+            "DIVMOD", "EQ", "NE", "LT", "GT", "LE", "GE", "AND", "OR", "NOT", "BAND", "BOR",
+            "BXOR", "BNOT", "SHL", "SHR", "USHR", "ROTL", "ROTR", "LOAD a", "STORE a", "@", "!",
+            "LABEL a", "JMP a", "JZ a", "JNZ a", "JEQ a", "JNE a", "JLT a", "JGT a", "JLE a",
+            "JGE a", "CALL a", "RET",
+        ]
+        .iter()
+        .map(|source| {
+            let program = crate::assembler::assemble_fragment(source).unwrap();
+            let instruction = &program.instructions()[0];
+            let bytes = encode(std::slice::from_ref(instruction));
+            assert_eq!(decode(&bytes).as_ref(), Ok(&vec![instruction.clone()]));
+            opcode(instruction)
+        })
+        .collect();
+        let total = opcodes.len();
+        opcodes.sort_unstable();
+        opcodes.dedup();
+        assert_eq!(opcodes.len(), total);
+    }
+
+    #[test]
+    fn test_encoding_layout() {
+        let bytes = encode(&[
+            Instruction::Push(-2),
+            Instruction::Pick(300),
+            Instruction::Jmp("end".to_string()),
+        ]);
+        assert_eq!(
+            bytes,
+            [
+                b'R', b'S', b'B', b'C', VERSION, 3, // header
+                0x00, 0xfe, 0xff, 0xff, 0xff, // PUSH -2
+                0x09, 0xac, 0x02, // PICK 300
+                0x51, 3, b'e', b'n', b'd', // JMP end
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let bytes = encode(&[Instruction::Push(7), Instruction::Load("x".to_string())]);
+
+        assert_eq!(decode(b"PUSH 1"), Err(DecodeError::InvalidMagic));
+        assert_eq!(decode(b"RSB"), Err(DecodeError::InvalidMagic));
+        assert_eq!(
+            decode(b"RSBC"),
+            Err(DecodeError::UnexpectedEnd { offset: 4 })
+        );
+
+        let mut future = bytes.clone();
+        future[4] = VERSION + 1;
+        assert_eq!(
+            decode(&future),
+            Err(DecodeError::UnsupportedVersion(VERSION + 1))
+        );
+
+        for len in 5..bytes.len() {
+            assert!(
+                matches!(
+                    decode(&bytes[..len]),
+                    Err(DecodeError::UnexpectedEnd { .. })
+                ),
+                "truncated to {} bytes",
+                len
+            );
+        }
+
+        let mut unknown = bytes.clone();
+        unknown[6] = 0xff;
+        assert_eq!(
+            decode(&unknown),
+            Err(DecodeError::UnknownOpcode {
+                opcode: 0xff,
+                offset: 6
+            })
+        );
+
+        let mut invalid_name = bytes.clone();
+        *invalid_name.last_mut().unwrap() = 0xff;
+        assert_eq!(
+            decode(&invalid_name),
+            Err(DecodeError::InvalidName { offset: 13 })
+        );
+
+        let mut trailing = bytes.clone();
+        trailing.push(0x01);
+        assert_eq!(
+            decode(&trailing),
+            Err(DecodeError::TrailingBytes { offset: 14 })
+        );
+
+        let mut overlong = MAGIC.to_vec();
+        overlong.push(VERSION);
+        overlong.extend_from_slice(&[0xff; 11]);
+        assert_eq!(
+            decode(&overlong),
+            Err(DecodeError::InvalidVarint { offset: 5 })
+        );
+    }
+}
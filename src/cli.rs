@@ -1,90 +1,425 @@
 //! This module implements a command-line interface (CLI) for interacting with a
-//! reversible stack-based interpreter. The CLI supports two modes of operation:
+//! reversible stack-based interpreter. The CLI supports three modes of operation:
 //!
+//! - **Run Mode**: Assembles and executes a program file, one instruction per line,
+//!   or executes a bytecode file produced by the `assemble` subcommand.
 //! - **Script Mode**: Executes a sequence of instructions provided from a file or
 //!   standard input.
 //! - **Shell Mode**: Launches an interactive shell where users can input commands
 //!   to manipulate the stack and interpreter in real-time.
 //!
 //! The CLI allows users to add, execute, and reverse stack-based instructions such as
-//! `PUSH`, `POP`, `ADD`, `SUB`, `MUL`, and `DIV`. It also provides helpful commands
+//! `PUSH`, `POP`, `DUP`, `SWAP`, `ADD`, `SUB`, `MUL`, and `DIV`. It also provides helpful commands
 //! like displaying the current stack, viewing the instruction queue, and undoing previous
 //! operations.
 //!
 //! This module uses `clap` for argument parsing and `rustyline` for interactive shell
-//! support. It communicates with an underlying interpreter to manage the execution and
-//! history of instructions, and handles errors like division by zero or stack underflow
-//! gracefully.
+//! support. Program files and instructions given to the `add` command are parsed by
+//! the `assembler` module, whose diagnostics are printed if any instruction is
+//! malformed. It communicates with an underlying interpreter to manage the execution
+//! and history of instructions, and handles errors like division by zero or stack
+//! underflow gracefully.
+//!
+//! The `assemble` and `disassemble` subcommands convert between program text and
+//! the binary encoding implemented by the `bytecode` module, and the `format`
+//! subcommand rewrites a program file in canonical syntax. The `trace` subcommand
+//! runs a program file like `run`, and writes every executed step as recorded by
+//! the `trace` module.
 
-use clap::{Parser, Subcommand};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader};
+use std::sync::{Arc, Mutex};
 
-use crate::interpreter::{Instruction, Interpreter, RuntimeError};
+use crate::assembler::{assemble, assemble_fragment, format_source, Program};
+use crate::bytecode;
+use crate::interpreter::{
+    Breakpoint, ErrorKind, ExecutionLimits, HistoryLimit, Instruction, Interpreter, OverflowMode,
+    RuntimeError, Watchpoint, DEFAULT_MEMORY_SIZE,
+};
+use crate::trace::{TraceFormat, Tracer};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// How arithmetic overflow is handled: checked, wrapping or saturating
+    #[arg(long, global = true, default_value_t = OverflowMode::Checked)]
+    overflow: OverflowMode,
+
+    /// Number of cells in the interpreter's linear memory
+    #[arg(long, global = true, default_value_t = DEFAULT_MEMORY_SIZE)]
+    memory_size: usize,
+
+    /// How much history to keep for reversing: unbounded, a number of instructions,
+    /// or a size such as 64KB
+    #[arg(long, global = true, default_value_t = HistoryLimit::Unbounded)]
+    history_limit: HistoryLimit,
+}
+
+impl Cli {
+    /// Constructs an interpreter configured from the command-line options.
+    fn interpreter(&self) -> Interpreter {
+        let mut interpreter = Interpreter::with_memory_size(self.memory_size);
+        interpreter.set_overflow_mode(self.overflow);
+        interpreter.set_history_limit(self.history_limit);
+        interpreter
+    }
+}
+
+/// Limits for running untrusted programs, see `ExecutionLimits`.
+#[derive(Args, Debug)]
+struct LimitArgs {
+    /// Maximum number of executed instructions
+    #[arg(long)]
+    max_steps: Option<usize>,
+
+    /// Maximum number of values on the stack
+    #[arg(long)]
+    max_stack: Option<usize>,
+}
+
+impl LimitArgs {
+    /// Sets the limits given on the command line, keeping the interpreter's others.
+    fn apply(&self, interpreter: &mut Interpreter) {
+        let limits = interpreter.limits();
+        interpreter.set_limits(ExecutionLimits {
+            max_steps: self.max_steps.or(limits.max_steps),
+            max_stack: self.max_stack.or(limits.max_stack),
+            ..limits
+        });
+    }
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
+    /// Assemble and run a program file with one instruction per line, or run a
+    /// bytecode file
+    Run {
+        /// File containing the program text or bytecode
+        file: String,
+    },
+    /// Assemble a program file into bytecode
+    Assemble {
+        /// File containing the program text
+        file: String,
+        /// File to write the bytecode to
+        #[arg(short, long)]
+        output: String,
+    },
+    /// Convert a bytecode file back into program text
+    Disassemble {
+        /// File containing the bytecode
+        file: String,
+        /// File to write the program text to. If not provided, writes to standard output.
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Run a program file like `run`, writing the instruction, stack before and stack
+    /// after of every executed step
+    Trace {
+        /// File containing the program text or bytecode
+        file: String,
+        /// Output format: text, jsonl or csv
+        #[arg(long, default_value_t = TraceFormat::Text)]
+        format: TraceFormat,
+        /// File to write the trace to. If not provided, writes to standard output.
+        #[arg(short, long)]
+        output: Option<String>,
+        #[command(flatten)]
+        limits: LimitArgs,
+    },
+    /// Rewrite a program file in canonical syntax, keeping its comments
+    Format {
+        /// File containing the program text
+        file: String,
+        /// File to write the formatted program to. If not provided, writes to standard output.
+        #[arg(short, long)]
+        output: Option<String>,
+    },
     /// Run a sequence of interpreter commands from a file or standard input
     Script {
         /// File containing interpreter commands. If not provided, reads from standard input.
         #[arg(short, long)]
         file: Option<String>,
+        #[command(flatten)]
+        limits: LimitArgs,
     },
     /// Enters interactive shell mode
-    Shell,
+    Shell {
+        /// Session file written by the `save` command to continue from
+        #[arg(long)]
+        resume: Option<String>,
+        #[command(flatten)]
+        limits: LimitArgs,
+    },
 }
 
 pub fn run_cli() {
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
     match &cli.command {
-        Commands::Script { file } => {
-            run_script(file.as_deref());
+        Commands::Run { file } => {
+            run_program(file, cli.interpreter());
+        }
+        Commands::Assemble { file, output } => {
+            assemble_program(file, output);
+        }
+        Commands::Disassemble { file, output } => {
+            disassemble_program(file, output.as_deref());
+        }
+        Commands::Format { file, output } => {
+            format_program(file, output.as_deref());
+        }
+        Commands::Trace {
+            file,
+            format,
+            output,
+            limits,
+        } => {
+            let mut interpreter = cli.interpreter();
+            limits.apply(&mut interpreter);
+            trace_program(file, *format, output.as_deref(), interpreter);
         }
-        Commands::Shell => {
-            run_shell();
+        Commands::Script { file, limits } => {
+            let mut interpreter = cli.interpreter();
+            limits.apply(&mut interpreter);
+            run_script(file.as_deref(), interpreter);
+        }
+        Commands::Shell { resume, limits } => {
+            let mut interpreter = match resume {
+                Some(file) => {
+                    reject_with_resume(&matches);
+                    let interpreter = load_session(file).unwrap_or_else(|e| fail(e));
+                    println!("Resumed session from '{}'.", file);
+                    interpreter
+                }
+                None => cli.interpreter(),
+            };
+            limits.apply(&mut interpreter);
+            run_shell(interpreter);
+        }
+    }
+}
+
+/// Exits if an option that configures a new interpreter is given together with
+/// `--resume`, as the resumed session keeps the configuration it was saved with.
+fn reject_with_resume(matches: &ArgMatches) {
+    for (id, option) in [
+        ("overflow", "--overflow"),
+        ("memory_size", "--memory-size"),
+        ("history_limit", "--history-limit"),
+    ] {
+        if matches.value_source(id) == Some(ValueSource::CommandLine) {
+            fail(format!(
+                "{} cannot be combined with --resume, which keeps the saved session's setting",
+                option
+            ));
+        }
+    }
+}
+
+/// Prints an error message and exits with a failure status.
+fn fail(message: String) -> ! {
+    println!("Error: {}", message);
+    std::process::exit(1);
+}
+
+/// Assembles the program text read from `file`, exiting with its diagnostics if
+/// it is malformed.
+fn assemble_source(source: &str, file: &str) -> Program {
+    assemble(source).unwrap_or_else(|e| {
+        println!("{}\n", e);
+        fail(format!(
+            "could not assemble '{}' due to {} previous error(s)",
+            file,
+            e.diagnostics().len()
+        ))
+    })
+}
+
+/// Decodes the bytecode read from `file`, exiting if it is malformed.
+fn decode_bytecode(bytes: &[u8], file: &str) -> Program {
+    Program::from_bytes(bytes)
+        .unwrap_or_else(|e| fail(format!("could not decode '{}': {}", file, e)))
+}
+
+fn assemble_program(file: &str, output: &str) {
+    let source = std::fs::read_to_string(file)
+        .unwrap_or_else(|e| fail(format!("failed to read '{}': {}", file, e)));
+    let program = assemble_source(&source, file);
+    if let Err(e) = std::fs::write(output, program.to_bytes()) {
+        fail(format!("failed to write '{}': {}", output, e));
+    }
+    println!(
+        "Assembled {} instruction(s) into '{}'.",
+        program.len(),
+        output
+    );
+}
+
+fn disassemble_program(file: &str, output: Option<&str>) {
+    let bytes =
+        std::fs::read(file).unwrap_or_else(|e| fail(format!("failed to read '{}': {}", file, e)));
+    let program = decode_bytecode(&bytes, file);
+    write_text(&program.to_string(), output);
+}
+
+fn format_program(file: &str, output: Option<&str>) {
+    let source = std::fs::read_to_string(file)
+        .unwrap_or_else(|e| fail(format!("failed to read '{}': {}", file, e)));
+    let text = format_source(&source).unwrap_or_else(|e| {
+        println!("{}\n", e);
+        fail(format!(
+            "could not format '{}' due to {} previous error(s)",
+            file,
+            e.diagnostics().len()
+        ))
+    });
+    write_text(&text, output);
+}
+
+/// Writes program text to the `output` file, or to standard output if not given.
+fn write_text(text: &str, output: Option<&str>) {
+    match output {
+        Some(output) => {
+            if let Err(e) = std::fs::write(output, text) {
+                fail(format!("failed to write '{}': {}", output, e));
+            }
         }
+        None => print!("{}", text),
+    }
+}
+
+/// Writes the interpreter, including its pending instructions and undo history, to
+/// `file` as JSON.
+#[cfg(feature = "cli")]
+fn save_session(interpreter: &Interpreter, file: &str) -> Result<(), String> {
+    let json = serde_json::to_string(interpreter).map_err(|e| e.to_string())?;
+    std::fs::write(file, json).map_err(|e| format!("failed to write '{}': {}", file, e))
+}
+
+/// Reads an interpreter written by `save_session` from `file`.
+#[cfg(feature = "cli")]
+fn load_session(file: &str) -> Result<Interpreter, String> {
+    let json =
+        std::fs::read_to_string(file).map_err(|e| format!("failed to read '{}': {}", file, e))?;
+    serde_json::from_str(&json).map_err(|e| format!("'{}' is not a saved session: {}", file, e))
+}
+
+#[cfg(not(feature = "cli"))]
+fn save_session(_interpreter: &Interpreter, _file: &str) -> Result<(), String> {
+    Err("saving sessions requires the `cli` feature".to_string())
+}
+
+#[cfg(not(feature = "cli"))]
+fn load_session(_file: &str) -> Result<Interpreter, String> {
+    Err("loading sessions requires the `cli` feature".to_string())
+}
+
+/// Reads a program file containing either program text or bytecode, exiting if it
+/// cannot be read or is malformed.
+fn load_program(file: &str) -> Program {
+    let bytes =
+        std::fs::read(file).unwrap_or_else(|e| fail(format!("failed to read '{}': {}", file, e)));
+    if bytecode::is_bytecode(&bytes) {
+        decode_bytecode(&bytes, file)
+    } else {
+        let source = String::from_utf8(bytes)
+            .unwrap_or_else(|_| fail(format!("'{}' is neither program text nor bytecode", file)));
+        assemble_source(&source, file)
     }
 }
 
-fn run_script(file: Option<&str>) {
-    let mut interpreter = Interpreter::new();
+/// Reports a runtime error of `program` against the line of the failing instruction,
+/// along with the stack, and exits with a failure status.
+fn fail_at_line(e: RuntimeError, program: &Program, interpreter: &Interpreter) -> ! {
+    let e = match e.context().and_then(|context| program.span(context.pc)) {
+        Some(span) => e.with_line(span.line),
+        None => e,
+    };
+    println!("Error: {}", e);
+    println!("Stack: {:?}", interpreter.stack());
+    std::process::exit(1);
+}
 
+fn run_program(file: &str, mut interpreter: Interpreter) {
+    let program = load_program(file);
+    interpreter.add_instructions(program.instructions());
+    match interpreter.run() {
+        Ok(()) => println!("Program finished. Stack: {:?}", interpreter.stack()),
+        Err(e) => fail_at_line(e, &program, &interpreter),
+    }
+}
+
+fn trace_program(
+    file: &str,
+    format: TraceFormat,
+    output: Option<&str>,
+    mut interpreter: Interpreter,
+) {
+    let program = load_program(file);
+    let tracer = Arc::new(Mutex::new(Tracer::new()));
+    interpreter.add_observer(Arc::clone(&tracer));
+    interpreter.add_instructions(program.instructions());
+    let result = interpreter.run();
+
+    let trace = tracer
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .render(format);
+    write_text(&trace, output);
+    if let Err(e) = result {
+        fail_at_line(e, &program, &interpreter);
+    }
+}
+
+fn run_script(file: Option<&str>, mut interpreter: Interpreter) {
     let reader: Box<dyn BufRead> = if let Some(filename) = file {
-        let file = File::open(filename).expect("Failed to open file");
+        let file = File::open(filename)
+            .unwrap_or_else(|e| fail(format!("failed to read '{}': {}", filename, e)));
         Box::new(BufReader::new(file))
     } else {
         Box::new(BufReader::new(io::stdin()))
     };
 
-    for line_result in reader.lines() {
-        let line = line_result.expect("Failed to read line");
-        match parse_and_execute_command(&mut interpreter, &line) {
+    // The script line each instruction of the program was added on, used to
+    // report errors against the script rather than the program counter.
+    let mut instruction_lines = Vec::new();
+
+    for (line_index, line_result) in reader.lines().enumerate() {
+        let line = line_result.unwrap_or_else(|e| fail(format!("failed to read script: {}", e)));
+        let result = parse_and_execute_command(&mut interpreter, &line);
+        instruction_lines.resize(interpreter.instructions().len(), line_index + 1);
+        match result {
             Ok(should_continue) => {
                 if !should_continue {
                     break;
                 }
             }
             Err(e) => {
-                println!("Error: {:?}", e);
+                let e = match e
+                    .context()
+                    .and_then(|context| instruction_lines.get(context.pc))
+                {
+                    Some(&line) => e.with_line(line),
+                    None => e,
+                };
+                println!("Error: {}", e);
                 std::process::exit(1); // Exit with non-zero code on error
             }
         }
     }
 }
 
-fn run_shell() {
+fn run_shell(mut interpreter: Interpreter) {
     let mut rl = Editor::<()>::new();
-    let mut interpreter = Interpreter::new();
 
     println!("Reversible Stack-Based Interpreter Shell");
     println!(
@@ -104,7 +439,7 @@ fn run_shell() {
                         }
                     }
                     Err(e) => {
-                        println!("Error: {:?}", e);
+                        println!("Error: {}", e);
                         // Continue the shell session
                     }
                 }
@@ -129,11 +464,31 @@ fn run_shell() {
 enum Command {
     AddInstruction(Vec<Instruction>),
     CurrentInstruction,
+    Replace(Instruction),
     InstructionQueue,
-    Forward,
+    CallStack,
+    Variables,
+    Memory(usize, usize),
+    Forward(usize),
     Run,
-    Back,
+    Back(usize),
+    Redo(usize),
+    Checkpoint(String),
+    Restore(String),
+    Checkpoints,
+    Branches,
+    Switch(usize),
+    Diff(usize, usize),
+    Break(Breakpoint),
+    Unbreak(Breakpoint),
+    Watch(Watchpoint),
+    Unwatch(Watchpoint),
+    Breakpoints,
+    Continue,
+    ReverseContinue,
     PrintStack,
+    Save(String),
+    Load(String),
     Help,
     Exit,
 }
@@ -152,21 +507,117 @@ fn parse_command(input: &str) -> Result<Command, String> {
 
     match command_str.as_str() {
         "add" | "add-instruction" => {
-            let instructions = parse_instructions_shell(args)?;
-            Ok(Command::AddInstruction(instructions))
+            let program = assemble_fragment(args).map_err(|e| e.to_string())?;
+            if program.is_empty() {
+                return Err("No instructions provided".to_string());
+            }
+            Ok(Command::AddInstruction(program.into_instructions()))
         }
         "current" | "current-instruction" => Ok(Command::CurrentInstruction),
+        "replace" => {
+            let program = assemble_fragment(args).map_err(|e| e.to_string())?;
+            match program.instructions() {
+                [instruction] => Ok(Command::Replace(instruction.clone())),
+                _ => Err("replace requires exactly one instruction".to_string()),
+            }
+        }
         "queue" => Ok(Command::InstructionQueue),
-        "forward" => Ok(Command::Forward),
+        "calls" | "call-stack" => Ok(Command::CallStack),
+        "vars" | "variables" => Ok(Command::Variables),
+        "mem" | "memory" => {
+            let bounds: Vec<usize> = args
+                .split_whitespace()
+                .map(|arg| arg.parse::<usize>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| "mem requires a numeric start and length".to_string())?;
+            match bounds[..] {
+                [start, len] => Ok(Command::Memory(start, len)),
+                _ => Err("mem requires two arguments: <start> <len>".to_string()),
+            }
+        }
+        "forward" => Ok(Command::Forward(parse_count(&command_str, args)?)),
         "run" => Ok(Command::Run),
-        "back" => Ok(Command::Back),
+        "back" => Ok(Command::Back(parse_count(&command_str, args)?)),
+        "redo" => Ok(Command::Redo(parse_count(&command_str, args)?)),
+        "checkpoint" | "restore" if args.is_empty() => {
+            Err(format!("{} requires one argument: <name>", command_str))
+        }
+        "checkpoint" => Ok(Command::Checkpoint(args.to_string())),
+        "restore" => Ok(Command::Restore(args.to_string())),
+        "checkpoints" => Ok(Command::Checkpoints),
+        "branches" => Ok(Command::Branches),
+        "switch" => args
+            .parse::<usize>()
+            .map(Command::Switch)
+            .map_err(|_| "switch requires one argument: <branch>".to_string()),
+        "diff" => {
+            let ids: Vec<usize> = args
+                .split_whitespace()
+                .map(|arg| arg.parse::<usize>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| "diff requires numeric branch ids".to_string())?;
+            match ids[..] {
+                [a, b] => Ok(Command::Diff(a, b)),
+                _ => Err("diff requires two arguments: <branch> <branch>".to_string()),
+            }
+        }
+        "break" => Ok(Command::Break(parse_breakpoint(args)?)),
+        "unbreak" => Ok(Command::Unbreak(parse_breakpoint(args)?)),
+        "watch" => Ok(Command::Watch(parse_watchpoint(args)?)),
+        "unwatch" => Ok(Command::Unwatch(parse_watchpoint(args)?)),
+        "breakpoints" | "watchpoints" => Ok(Command::Breakpoints),
+        "continue" | "c" => Ok(Command::Continue),
+        "reverse-continue" | "rc" => Ok(Command::ReverseContinue),
         "print" | "stack" => Ok(Command::PrintStack),
+        "save" | "load" if args.is_empty() => {
+            Err(format!("{} requires one argument: <file>", command_str))
+        }
+        "save" => Ok(Command::Save(args.to_string())),
+        "load" => Ok(Command::Load(args.to_string())),
         "help" => Ok(Command::Help),
         "exit" => Ok(Command::Exit),
         _ => Err(format!("Unknown command: '{}'", command_str)),
     }
 }
 
+/// Parses the optional step count of commands such as `forward [n]`, which
+/// defaults to 1.
+fn parse_count(command: &str, args: &str) -> Result<usize, String> {
+    if args.is_empty() {
+        return Ok(1);
+    }
+    args.parse::<usize>()
+        .map_err(|_| format!("{} takes an optional numeric count", command))
+}
+
+/// Parses the argument of `break <index|label>`.
+fn parse_breakpoint(args: &str) -> Result<Breakpoint, String> {
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [target] => Ok(target
+            .parse::<usize>()
+            .map(Breakpoint::Instruction)
+            .unwrap_or_else(|_| Breakpoint::Label(target.to_string()))),
+        _ => Err("break requires one argument: <index> or <label>".to_string()),
+    }
+}
+
+/// Parses the arguments of `watch depth <n>`, `watch top <value>` and `watch var <name>`.
+fn parse_watchpoint(args: &str) -> Result<Watchpoint, String> {
+    let usage = "watch requires a kind and an argument: depth <n>, top <value> or var <name>";
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        ["depth", depth] => depth
+            .parse::<usize>()
+            .map(Watchpoint::StackDepth)
+            .map_err(|_| usage.to_string()),
+        ["top", value] => value
+            .parse::<i32>()
+            .map(Watchpoint::TopOfStack)
+            .map_err(|_| usage.to_string()),
+        ["var", name] => Ok(Watchpoint::Variable(name.to_string())),
+        _ => Err(usage.to_string()),
+    }
+}
+
 fn parse_and_execute_command(
     interpreter: &mut Interpreter,
     input: &str,
@@ -193,19 +644,71 @@ fn execute_command(interpreter: &mut Interpreter, command: Command) -> Result<()
         }
         Command::CurrentInstruction => {
             if let Some(instr) = interpreter.current_instruction() {
-                println!("Current instruction: {instr:?}");
+                println!("Current instruction: {instr}");
+            } else {
+                println!("No instructions in the queue.");
+            }
+            Ok(())
+        }
+        Command::Replace(instruction) => {
+            let pc = interpreter.pc();
+            if let Some(current) = interpreter.current_instruction() {
+                println!("Replaced instruction {pc} ({current}) with {instruction}.");
+                *current = instruction;
             } else {
                 println!("No instructions in the queue.");
             }
             Ok(())
         }
         Command::InstructionQueue => {
-            println!("Instruction queue: {:?}", interpreter.instructions());
+            if interpreter.instructions().is_empty() {
+                println!("No instructions in the program.");
+            }
+            for (index, instr) in interpreter.instructions().iter().enumerate() {
+                let marker = if index == interpreter.pc() { ">" } else { " " };
+                println!("{marker} {index:>4}: {instr}");
+            }
+            Ok(())
+        }
+        Command::CallStack => {
+            println!("Call stack: {:?}", interpreter.call_stack());
             Ok(())
         }
-        Command::Forward => {
+        Command::Variables => {
+            if interpreter.variables().is_empty() {
+                println!("No variables are set.");
+            }
+            for (name, value) in interpreter.variables() {
+                println!("{name} = {value}");
+            }
+            Ok(())
+        }
+        Command::Memory(start, len) => {
+            let memory = interpreter.memory();
+            if start.saturating_add(len) > memory.len() {
+                println!(
+                    "Memory region {}..{} is out of bounds (memory size: {}).",
+                    start,
+                    start.saturating_add(len),
+                    memory.len()
+                );
+            }
+            for (address, value) in memory.iter().enumerate().skip(start).take(len) {
+                println!("{address:>6}: {value}");
+            }
+            Ok(())
+        }
+        Command::Forward(1) => {
             let instruction = interpreter.forward()?;
-            println!("Executed {instruction:?}. Stack: {:?}", interpreter.stack());
+            println!("Executed {instruction}. Stack: {:?}", interpreter.stack());
+            Ok(())
+        }
+        Command::Forward(n) => {
+            interpreter.forward_n(n)?;
+            println!(
+                "Executed {n} instructions. Stack: {:?}",
+                interpreter.stack()
+            );
             Ok(())
         }
         Command::Run => {
@@ -216,7 +719,7 @@ fn execute_command(interpreter: &mut Interpreter, command: Command) -> Result<()
             );
             Ok(())
         }
-        Command::Back => {
+        Command::Back(1) => {
             interpreter.back()?;
             println!(
                 "Reversed last instruction. Stack: {:?}",
@@ -224,29 +727,304 @@ fn execute_command(interpreter: &mut Interpreter, command: Command) -> Result<()
             );
             Ok(())
         }
+        Command::Back(n) => {
+            interpreter.back_n(n)?;
+            println!(
+                "Reversed {n} instructions. Stack: {:?}",
+                interpreter.stack()
+            );
+            Ok(())
+        }
+        Command::Redo(n) => {
+            for _ in 0..n {
+                let instruction = interpreter.redo()?;
+                println!("Redid {instruction}. Stack: {:?}", interpreter.stack());
+            }
+            Ok(())
+        }
+        Command::Checkpoint(name) => {
+            interpreter.checkpoint(&name);
+            println!(
+                "Checkpoint '{}' set after {} executed instruction(s).",
+                name,
+                interpreter.history_len()
+            );
+            Ok(())
+        }
+        Command::Restore(name) => {
+            interpreter.restore(&name)?;
+            println!(
+                "Restored checkpoint '{}'. Stack: {:?}",
+                name,
+                interpreter.stack()
+            );
+            Ok(())
+        }
+        Command::Break(breakpoint) => {
+            println!("Breakpoint set at {}.", breakpoint);
+            interpreter.add_breakpoint(breakpoint);
+            Ok(())
+        }
+        Command::Unbreak(breakpoint) => {
+            if interpreter.remove_breakpoint(&breakpoint) {
+                println!("Breakpoint at {} removed.", breakpoint);
+            } else {
+                println!("No breakpoint at {}.", breakpoint);
+            }
+            Ok(())
+        }
+        Command::Watch(watchpoint) => {
+            println!("Watchpoint set on {}.", watchpoint);
+            interpreter.add_watchpoint(watchpoint);
+            Ok(())
+        }
+        Command::Unwatch(watchpoint) => {
+            if interpreter.remove_watchpoint(&watchpoint) {
+                println!("Watchpoint on {} removed.", watchpoint);
+            } else {
+                println!("No watchpoint on {}.", watchpoint);
+            }
+            Ok(())
+        }
+        Command::Breakpoints => {
+            if interpreter.breakpoints().is_empty() && interpreter.watchpoints().is_empty() {
+                println!("No breakpoints or watchpoints are set.");
+            }
+            for breakpoint in interpreter.breakpoints() {
+                println!("breakpoint at {breakpoint}");
+            }
+            for watchpoint in interpreter.watchpoints() {
+                println!("watchpoint on {watchpoint}");
+            }
+            Ok(())
+        }
+        Command::Continue => {
+            match interpreter.continue_forward()? {
+                Some(stop) => println!(
+                    "Stopped at {} before instruction {}. Stack: {:?}",
+                    stop,
+                    interpreter.pc(),
+                    interpreter.stack()
+                ),
+                None => println!(
+                    "All instructions executed. Stack: {:?}",
+                    interpreter.stack()
+                ),
+            }
+            Ok(())
+        }
+        Command::ReverseContinue => {
+            match interpreter.continue_backward()? {
+                Some(stop) => println!(
+                    "Stopped at {} before instruction {}. Stack: {:?}",
+                    stop,
+                    interpreter.pc(),
+                    interpreter.stack()
+                ),
+                None => println!(
+                    "Reversed all executed instructions. Stack: {:?}",
+                    interpreter.stack()
+                ),
+            }
+            Ok(())
+        }
+        Command::Checkpoints => {
+            if interpreter.checkpoints().is_empty() {
+                println!("No checkpoints are set.");
+            }
+            for (name, checkpoint) in interpreter.checkpoints() {
+                println!(
+                    "{name}: after {} executed instruction(s) on branch {}",
+                    checkpoint.position, checkpoint.branch
+                );
+            }
+            Ok(())
+        }
+        Command::Branches => {
+            for branch in interpreter.branches() {
+                let marker = if branch.id == interpreter.current_branch() {
+                    '*'
+                } else {
+                    ' '
+                };
+                let origin = match branch.parent {
+                    Some(parent) => format!("forked from {} after {}", parent, branch.fork),
+                    None => "initial".to_string(),
+                };
+                println!(
+                    "{} {}: {}, at {} of {} instruction(s), final stack {:?}",
+                    marker, branch.id, origin, branch.position, branch.len, branch.stack
+                );
+            }
+            Ok(())
+        }
+        Command::Switch(id) => {
+            interpreter.switch_branch(id)?;
+            println!(
+                "Switched to branch {} after {} executed instruction(s). Stack: {:?}",
+                id,
+                interpreter.history_len(),
+                interpreter.stack()
+            );
+            Ok(())
+        }
+        Command::Diff(a, b) => {
+            let Some(shared) = interpreter.shared_history_len(a, b) else {
+                return Err(ErrorKind::UndefinedBranch.into());
+            };
+            let branches = interpreter.branches();
+            let stack = |id| {
+                branches
+                    .iter()
+                    .find(|branch| branch.id == id)
+                    .map(|branch| &branch.stack)
+                    .ok_or(ErrorKind::UndefinedBranch)
+            };
+            let (stack_a, stack_b) = (stack(a)?, stack(b)?);
+            println!("Branches {a} and {b} share {shared} executed instruction(s).");
+            println!("  {a}: {:?}", stack_a);
+            println!("  {b}: {:?}", stack_b);
+            match (0..stack_a.len().max(stack_b.len())).find(|&i| stack_a.get(i) != stack_b.get(i))
+            {
+                Some(index) => println!("Final stacks first differ at index {index}."),
+                None => println!("Final stacks are identical."),
+            }
+            Ok(())
+        }
         Command::PrintStack => {
             println!("Stack: {:?}", interpreter.stack());
             Ok(())
         }
+        // A session that cannot be written or read leaves the interpreter as it was,
+        // so the failure is reported like a malformed command and the session goes on.
+        Command::Save(file) => {
+            match save_session(interpreter, &file) {
+                Ok(()) => println!("Session saved to '{}'.", file),
+                Err(e) => println!("Error: {}", e),
+            }
+            Ok(())
+        }
+        Command::Load(file) => {
+            match load_session(&file) {
+                Ok(loaded) => {
+                    *interpreter = loaded;
+                    println!("Session loaded from '{}'.", file);
+                }
+                Err(e) => println!("Error: {}", e),
+            }
+            Ok(())
+        }
         Command::Help => {
             println!("Available commands:");
             println!("  add <instructions>      - Add instructions to the interpreter's queue");
             println!("                           Instructions are separated by semicolons (;)");
             println!("  current                 - Show the current instruction in the queue");
-            println!("  queue                   - Show the instruction queue");
-            println!("  forward                 - Execute the next instruction");
+            println!("  replace <instruction>   - Replace the current instruction");
+            println!(
+                "  queue                   - Show the program, marking the current instruction"
+            );
+            println!("  calls                   - Show the return addresses of the active calls");
+            println!("  vars                    - Show the current variables");
+            println!("  mem <start> <len>       - Show len memory cells starting at address start");
+            println!("  forward [n]             - Execute the next instruction, or the next n");
             println!("  run                     - Execute all instructions");
-            println!("  back                    - Reverse the last executed instruction");
+            println!(
+                "  back [n]                - Reverse the last executed instruction, or the last n"
+            );
+            println!(
+                "  redo [n]                - Replay the last reversed instruction, or the last n"
+            );
+            println!(
+                "  checkpoint <name>       - Record the current point of execution as a checkpoint"
+            );
+            println!(
+                "  restore <name>          - Reverse or replay instructions back to a checkpoint"
+            );
+            println!("  checkpoints             - List the checkpoints");
+            println!("  branches                - List the branches of execution, marking the current one");
+            println!("  switch <branch>         - Continue on another branch of execution");
+            println!("  diff <branch> <branch>  - Compare the final stacks of two branches");
+            println!("  break <index|label>     - Stop continuing before an instruction or label");
+            println!("  watch depth <n>         - Stop continuing when the stack depth becomes n");
+            println!("  watch top <value>       - Stop continuing when the top of the stack becomes value");
+            println!("  watch var <name>        - Stop continuing when a variable changes");
+            println!("  unbreak, unwatch        - Remove a breakpoint or watchpoint");
+            println!("  breakpoints             - List the breakpoints and watchpoints");
+            println!(
+                "  continue                - Execute until a breakpoint or watchpoint triggers"
+            );
+            println!(
+                "  reverse-continue        - Reverse until a breakpoint or watchpoint triggers"
+            );
             println!("  print                   - Display the current state of the stack");
+            println!(
+                "  save <file>             - Save the session, including its history, to a file"
+            );
+            println!("  load <file>             - Replace the session with one saved to a file");
             println!("  help                    - Display this help message");
             println!("  exit                    - Exit the shell");
             println!("\nInstructions:");
             println!("  PUSH <value>            - Push a value onto the stack");
             println!("  POP                     - Pop a value from the stack");
+            println!("  DUP                     - Duplicate the top value (a -- a a)");
+            println!("  SWAP                    - Swap the top two values (a b -- b a)");
+            println!("  OVER                    - Copy the second value to the top (a b -- a b a)");
+            println!(
+                "  ROT                     - Rotate the third value to the top (a b c -- b c a)"
+            );
+            println!("  NIP                     - Remove the second value (a b -- b)");
+            println!(
+                "  TUCK                    - Copy the top value below the second (a b -- b a b)"
+            );
+            println!("  DROPN <n>               - Pop the top n values from the stack");
+            println!("  PICK <n>                - Copy the nth value (0 = top) to the top");
+            println!("  ROLL <n>                - Move the nth value (0 = top) to the top");
             println!("  ADD                     - Add the top two values on the stack");
             println!("  SUB                     - Subtract the top two values on the stack");
             println!("  MUL                     - Multiply the top two values on the stack");
             println!("  DIV                     - Divide the top two values on the stack");
+            println!(
+                "  MOD                     - Remainder of dividing the top two values on the stack"
+            );
+            println!(
+                "  NEG, ABS                - Negate / take the absolute value of the top value"
+            );
+            println!("  MIN, MAX                - Keep the smaller / larger of the top two values");
+            println!(
+                "  POW                     - Pop two values a b and push a to the power b (b >= 0)"
+            );
+            println!("  DIVMOD                  - Pop two values a b and push a / b and a % b");
+            println!(
+                "  EQ, NE, LT, GT, LE, GE  - Pop two values a b and push 1 if a == b, a != b,"
+            );
+            println!("                            a < b, a > b, a <= b or a >= b, 0 otherwise");
+            println!("  AND, OR                 - Pop two values and push their logical and / or (1 or 0)");
+            println!(
+                "  NOT                     - Pop a value and push 1 if it is zero, 0 otherwise"
+            );
+            println!(
+                "  BAND, BOR, BXOR         - Pop two values and push their bitwise and / or / xor"
+            );
+            println!("  BNOT                    - Pop a value and push its bitwise complement");
+            println!("  SHL, SHR, USHR          - Pop two values a b and shift a by b bits: left,");
+            println!("                            arithmetic right or logical right (0 <= b < 32)");
+            println!("  ROTL, ROTR              - Pop two values a b and rotate a left / right by b bits");
+            println!("  LOAD <name>             - Push the value of a variable");
+            println!("  STORE <name>            - Pop a value and store it in a variable");
+            println!("  @                       - Pop an address and push the memory cell at it");
+            println!(
+                "  !                       - Pop an address and a value and store the value at it"
+            );
+            println!("  LABEL <name>            - Mark a jump target");
+            println!("  JMP <label>             - Jump to a label");
+            println!("  JZ <label>              - Pop a value and jump to a label if it is zero");
+            println!(
+                "  JNZ <label>             - Pop a value and jump to a label if it is non-zero"
+            );
+            println!("  JEQ, JNE, JLT, JGT,     - Pop two values a b and jump to a label if");
+            println!("  JLE, JGE <label>          a == b, a != b, a < b, a > b, a <= b or a >= b");
+            println!("  CALL <label>            - Call the subroutine at a label");
+            println!("  RET                     - Return from the current subroutine");
             Ok(())
         }
         Command::Exit => {
@@ -256,65 +1034,6 @@ fn execute_command(interpreter: &mut Interpreter, command: Command) -> Result<()
     }
 }
 
-fn parse_instructions_shell(input: &str) -> Result<Vec<Instruction>, String> {
-    let mut instructions = Vec::new();
-    let mut errors = Vec::new();
-
-    for s in input.split(';') {
-        let s = s.trim();
-        if s.is_empty() {
-            continue;
-        }
-        match parse_instruction(s) {
-            Ok(instr) => instructions.push(instr),
-            Err(e) => {
-                errors.push(format!("Error parsing instruction '{}': {:?}", s, e));
-            }
-        }
-    }
-
-    if !errors.is_empty() {
-        for error in errors {
-            println!("{}", error);
-        }
-    }
-
-    if instructions.is_empty() {
-        Err("No valid instructions provided".to_string())
-    } else {
-        Ok(instructions)
-    }
-}
-
-fn parse_instruction(s: &str) -> Result<Instruction, RuntimeError> {
-    let tokens: Vec<&str> = s.split_whitespace().collect();
-    if tokens.is_empty() {
-        return Err(RuntimeError::NoInstructions);
-    }
-    let command = tokens[0].to_uppercase();
-    match command.as_str() {
-        "PUSH" => {
-            if tokens.len() != 2 {
-                println!("PUSH requires one argument.");
-                return Err(RuntimeError::InvalidCommand);
-            }
-            let value = tokens[1]
-                .parse::<i32>()
-                .map_err(|_| RuntimeError::InvalidCommand)?;
-            Ok(Instruction::Push(value))
-        }
-        "POP" => Ok(Instruction::Pop),
-        "ADD" => Ok(Instruction::Add),
-        "SUB" => Ok(Instruction::Sub),
-        "MUL" => Ok(Instruction::Mul),
-        "DIV" => Ok(Instruction::Div),
-        _ => {
-            println!("Invalid instruction: {}", command);
-            Err(RuntimeError::InvalidCommand)
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -330,12 +1049,12 @@ mod tests {
         let input = "forward";
         parse_and_execute_command(&mut interpreter, input).unwrap();
         assert_eq!(*interpreter.stack(), vec![5]);
-        assert_eq!(interpreter.instructions().len(), 1);
+        assert_eq!(interpreter.pc(), 1);
 
         let input = "forward";
         parse_and_execute_command(&mut interpreter, input).unwrap();
         assert_eq!(*interpreter.stack(), vec![5, 3]);
-        assert_eq!(interpreter.instructions().len(), 0);
+        assert_eq!(interpreter.pc(), 2);
     }
 
     #[test]
@@ -360,8 +1079,8 @@ mod tests {
         // Stack should now be [5]
         assert_eq!(*interpreter.stack(), vec![5]);
 
-        // The instruction should be back in the interpreter's instructions
-        assert_eq!(interpreter.instructions().len(), 1);
+        // The program counter should point at the reversed instruction again
+        assert_eq!(interpreter.pc(), 1);
         assert_eq!(
             *interpreter.current_instruction().unwrap(),
             Instruction::Push(3)
@@ -377,8 +1096,208 @@ mod tests {
     }
 
     #[test]
-    fn test_parse_instruction_invalid() {
-        let result = parse_instruction("INVALID");
-        assert_eq!(result.unwrap_err(), RuntimeError::InvalidCommand);
+    fn test_variable_commands() {
+        let mut interpreter = Interpreter::new();
+        let input = "add PUSH 7; STORE x; LOAD x; LOAD x; MUL";
+        parse_and_execute_command(&mut interpreter, input).unwrap();
+        parse_and_execute_command(&mut interpreter, "run").unwrap();
+        assert_eq!(*interpreter.stack(), vec![49]);
+        parse_and_execute_command(&mut interpreter, "vars").unwrap();
+        assert_eq!(interpreter.variables().get("x"), Some(&7));
+    }
+
+    #[test]
+    fn test_memory_commands() {
+        let mut interpreter = Interpreter::with_memory_size(8);
+        let input = "add PUSH 42; PUSH 5; !; PUSH 5; @";
+        parse_and_execute_command(&mut interpreter, input).unwrap();
+        parse_and_execute_command(&mut interpreter, "run").unwrap();
+        assert_eq!(*interpreter.stack(), vec![42]);
+        assert_eq!(interpreter.memory()[5], 42);
+
+        assert!(matches!(
+            parse_command("mem 4 2"),
+            Ok(Command::Memory(4, 2))
+        ));
+        assert!(parse_command("mem 4").is_err());
+        assert!(parse_command("mem -1 2").is_err());
+        parse_and_execute_command(&mut interpreter, "mem 6 4").unwrap();
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn test_save_and_load_commands() {
+        let file = std::env::temp_dir().join(format!("session-{}.json", std::process::id()));
+        let file = file.to_str().unwrap();
+
+        let mut interpreter = Interpreter::new();
+        let input = "add PUSH 6; STORE x; PUSH 7; LOAD x; MUL";
+        parse_and_execute_command(&mut interpreter, input).unwrap();
+        parse_and_execute_command(&mut interpreter, "forward").unwrap();
+        parse_and_execute_command(&mut interpreter, "forward").unwrap();
+        parse_and_execute_command(&mut interpreter, &format!("save {}", file)).unwrap();
+
+        let mut restored = Interpreter::new();
+        parse_and_execute_command(&mut restored, &format!("load {}", file)).unwrap();
+        std::fs::remove_file(file).unwrap();
+        assert_eq!(restored.pc(), 2);
+        assert_eq!(restored.variables().get("x"), Some(&6));
+
+        // The pending queue and the history both survive the round trip
+        parse_and_execute_command(&mut restored, "run").unwrap();
+        assert_eq!(*restored.stack(), vec![42]);
+        for _ in 0..5 {
+            parse_and_execute_command(&mut restored, "back").unwrap();
+        }
+        assert_eq!(*restored.stack(), Vec::<i32>::new());
+        assert!(restored.variables().is_empty());
+
+        // A session that cannot be read leaves the interpreter as it was
+        parse_and_execute_command(&mut restored, &format!("load {}", file)).unwrap();
+        assert_eq!(*restored.stack(), Vec::<i32>::new());
+
+        assert!(parse_command("save").is_err());
+        assert!(matches!(parse_command("load a b"), Ok(Command::Load(f)) if f == "a b"));
+    }
+
+    #[test]
+    fn test_redo_command() {
+        let mut interpreter = Interpreter::new();
+        parse_and_execute_command(&mut interpreter, "add PUSH 4; DUP; MUL").unwrap();
+        parse_and_execute_command(&mut interpreter, "run").unwrap();
+        parse_and_execute_command(&mut interpreter, "back 2").unwrap();
+        parse_and_execute_command(&mut interpreter, "redo").unwrap();
+        assert_eq!(*interpreter.stack(), vec![4, 4]);
+        assert_eq!(interpreter.redo_len(), 1);
+
+        // Adding to the program after an undo keeps the undone instructions
+        parse_and_execute_command(&mut interpreter, "add PUSH 1; ADD").unwrap();
+        parse_and_execute_command(&mut interpreter, "redo 1").unwrap();
+        assert_eq!(*interpreter.stack(), vec![16]);
+        assert!(parse_and_execute_command(&mut interpreter, "redo").is_err());
+        parse_and_execute_command(&mut interpreter, "run").unwrap();
+        assert_eq!(*interpreter.stack(), vec![17]);
+    }
+
+    #[test]
+    fn test_branch_commands() {
+        let mut interpreter = Interpreter::new();
+        parse_and_execute_command(&mut interpreter, "add PUSH 6; PUSH 2; MUL").unwrap();
+        parse_and_execute_command(&mut interpreter, "run").unwrap();
+        parse_and_execute_command(&mut interpreter, "back").unwrap();
+        parse_and_execute_command(&mut interpreter, "replace ADD").unwrap();
+        parse_and_execute_command(&mut interpreter, "forward").unwrap();
+        assert_eq!(*interpreter.stack(), vec![8]);
+        assert_eq!(interpreter.current_branch(), 1);
+        parse_and_execute_command(&mut interpreter, "branches").unwrap();
+        parse_and_execute_command(&mut interpreter, "diff 0 1").unwrap();
+
+        // Switching back restores the instruction the original branch executed
+        parse_and_execute_command(&mut interpreter, "switch 0").unwrap();
+        assert_eq!(*interpreter.stack(), vec![6, 2]);
+        parse_and_execute_command(&mut interpreter, "redo").unwrap();
+        assert_eq!(*interpreter.stack(), vec![12]);
+        assert_eq!(interpreter.instructions()[2], Instruction::Mul);
+        parse_and_execute_command(&mut interpreter, "switch 1").unwrap();
+        assert_eq!(*interpreter.stack(), vec![8]);
+        assert_eq!(interpreter.instructions()[2], Instruction::Add);
+
+        assert!(matches!(parse_command("diff 0 1"), Ok(Command::Diff(0, 1))));
+        assert!(parse_command("diff 0").is_err());
+        assert!(parse_command("switch").is_err());
+        assert!(parse_command("replace PUSH 1; POP").is_err());
+        assert!(parse_and_execute_command(&mut interpreter, "switch 2").is_err());
+        assert!(parse_and_execute_command(&mut interpreter, "diff 0 2").is_err());
+    }
+
+    #[test]
+    fn test_checkpoint_commands() {
+        let mut interpreter = Interpreter::new();
+        let input = "add PUSH 1; PUSH 2; ADD; PUSH 3; MUL";
+        parse_and_execute_command(&mut interpreter, input).unwrap();
+        parse_and_execute_command(&mut interpreter, "forward 2").unwrap();
+        parse_and_execute_command(&mut interpreter, "checkpoint operands").unwrap();
+        parse_and_execute_command(&mut interpreter, "run").unwrap();
+        assert_eq!(*interpreter.stack(), vec![9]);
+
+        parse_and_execute_command(&mut interpreter, "restore operands").unwrap();
+        assert_eq!(*interpreter.stack(), vec![1, 2]);
+        parse_and_execute_command(&mut interpreter, "back 2").unwrap();
+        assert_eq!(interpreter.pc(), 0);
+        parse_and_execute_command(&mut interpreter, "restore operands").unwrap();
+        assert_eq!(interpreter.pc(), 2);
+
+        assert!(matches!(parse_command("forward"), Ok(Command::Forward(1))));
+        assert!(matches!(parse_command("back 10"), Ok(Command::Back(10))));
+        assert!(parse_command("back -1").is_err());
+        assert!(parse_command("checkpoint").is_err());
+        assert!(parse_and_execute_command(&mut interpreter, "restore other").is_err());
+        assert!(parse_and_execute_command(&mut interpreter, "back 3").is_err());
+        assert_eq!(interpreter.pc(), 2);
+    }
+
+    #[test]
+    fn test_breakpoint_commands() {
+        let mut interpreter = Interpreter::new();
+        let input = "add PUSH 3; loop:; PUSH 1; SUB; DUP; STORE n; DUP; JNZ loop";
+        parse_and_execute_command(&mut interpreter, input).unwrap();
+        parse_and_execute_command(&mut interpreter, "break loop").unwrap();
+        parse_and_execute_command(&mut interpreter, "break 7").unwrap();
+        parse_and_execute_command(&mut interpreter, "watch var n").unwrap();
+        assert_eq!(interpreter.breakpoints().len(), 2);
+        assert_eq!(interpreter.watchpoints().len(), 1);
+
+        parse_and_execute_command(&mut interpreter, "continue").unwrap();
+        assert_eq!(interpreter.pc(), 1);
+        parse_and_execute_command(&mut interpreter, "c").unwrap();
+        assert_eq!(interpreter.variables().get("n"), Some(&2));
+        parse_and_execute_command(&mut interpreter, "unwatch var n").unwrap();
+        parse_and_execute_command(&mut interpreter, "continue").unwrap();
+        assert_eq!(interpreter.pc(), 7);
+        parse_and_execute_command(&mut interpreter, "reverse-continue").unwrap();
+        assert_eq!(interpreter.pc(), 1);
+        parse_and_execute_command(&mut interpreter, "unbreak loop").unwrap();
+        parse_and_execute_command(&mut interpreter, "rc").unwrap();
+        assert_eq!(interpreter.pc(), 0);
+
+        assert!(matches!(
+            parse_command("watch top -4"),
+            Ok(Command::Watch(Watchpoint::TopOfStack(-4)))
+        ));
+        assert!(matches!(
+            parse_command("watch depth 2"),
+            Ok(Command::Watch(Watchpoint::StackDepth(2)))
+        ));
+        assert!(parse_command("watch depth x").is_err());
+        assert!(parse_command("watch size 2").is_err());
+        assert!(parse_command("break").is_err());
+    }
+
+    #[test]
+    fn test_loop_commands() {
+        let mut interpreter = Interpreter::new();
+        let input = "add PUSH 3; LABEL loop; PUSH 1; SUB; DUP; JNZ loop";
+        parse_and_execute_command(&mut interpreter, input).unwrap();
+        parse_and_execute_command(&mut interpreter, "run").unwrap();
+        assert_eq!(*interpreter.stack(), vec![0]);
+
+        // Step back over the final, not taken, JNZ and the DUP before it
+        parse_and_execute_command(&mut interpreter, "back").unwrap();
+        parse_and_execute_command(&mut interpreter, "back").unwrap();
+        assert_eq!(*interpreter.stack(), vec![0]);
+        assert_eq!(interpreter.pc(), 4);
+    }
+
+    #[test]
+    fn test_add_reports_all_invalid_instructions() {
+        let mut interpreter = Interpreter::new();
+        let result = parse_command("add PUSH 1; FOO; PUSH x");
+        let message = result.unwrap_err();
+        assert!(message.contains("unknown instruction `FOO`"));
+        assert!(message.contains("expected an integer in the i32 range, found `x`"));
+
+        // Nothing is added if any instruction is invalid
+        parse_and_execute_command(&mut interpreter, "add PUSH 1; FOO").unwrap();
+        assert!(interpreter.instructions().is_empty());
     }
 }
@@ -0,0 +1,898 @@
+//! This module implements the history that makes the interpreter reversible: the
+//! record of every executed instruction, kept within an optional `HistoryLimit`.
+//!
+//! Entries are grouped in blocks of `BLOCK_LEN`. The most recent entries are kept
+//! as they are, so that stepping back and forth near the present stays cheap; older
+//! blocks are sealed into a compact delta encoding, where each program counter is
+//! stored as its offset from the one the previous instruction moved on to, and
+//! each value as a zigzag varint of its difference from the previous value of the
+//! entry. Most instructions take a few bytes.
+//!
+//! A full snapshot of the stack is kept before the oldest retained instruction, and
+//! before every `SNAPSHOT_INTERVAL`th block, so that the stack at any retained
+//! position can be reconstructed by decoding at most that many blocks. When the
+//! limit is exceeded the oldest sealed blocks are discarded, and the oldest snapshot
+//! is advanced past them.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::bytecode::{encode_instruction, write_varint, DecodeError, Reader};
+use crate::interpreter::Instruction;
+
+/// The number of entries in a sealed block. Up to twice as many of the most recent
+/// entries are kept unsealed.
+const BLOCK_LEN: usize = 64;
+
+/// The number of blocks between the snapshots of the stack kept by a `History`.
+const SNAPSHOT_INTERVAL: usize = 16;
+
+/// Bounds the memory used by the history of an `Interpreter`:
+/// - `Unbounded`: Every executed instruction is kept, so `.back()` can reverse all
+///   the way to the start.
+/// - `Entries(n)`: At least the last `n` executed instructions are kept.
+/// - `Bytes(n)`: The history is kept within about `n` bytes.
+///
+/// Older instructions are discarded a block at a time and the most recent ones are
+/// never discarded, so a few more instructions than the limit may be kept.
+///
+/// Only the executed instructions leading to the current state count towards the
+/// limit. Reversed instructions waiting to be redone and the entries kept for other
+/// branches are excluded: they are neither counted nor discarded to stay within the
+/// limit, only dropped along with branches that diverge before the oldest retained
+/// instruction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HistoryLimit {
+    #[default]
+    Unbounded,
+    Entries(usize),
+    Bytes(usize),
+}
+
+impl fmt::Display for HistoryLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryLimit::Unbounded => f.write_str("unbounded"),
+            HistoryLimit::Entries(entries) => write!(f, "{}", entries),
+            HistoryLimit::Bytes(bytes) => write!(f, "{}B", bytes),
+        }
+    }
+}
+
+/// Parses `unbounded`, a number of entries such as `10000`, or a size in bytes such
+/// as `65536B`, `64KB` or `16MB` (where a kilobyte is 1024 bytes).
+impl std::str::FromStr for HistoryLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || {
+            format!(
+                "Invalid history limit '{}': expected unbounded, a number of entries or a size such as 64KB",
+                s
+            )
+        };
+        let lower = s.to_lowercase();
+        if lower == "unbounded" {
+            return Ok(HistoryLimit::Unbounded);
+        }
+        let (digits, unit) = match lower.find(|c: char| !c.is_ascii_digit()) {
+            Some(index) => lower.split_at(index),
+            None => (lower.as_str(), ""),
+        };
+        let value = digits.parse::<usize>().map_err(|_| error())?;
+        let scale = match unit {
+            "" => return Ok(HistoryLimit::Entries(value)),
+            "b" => 1,
+            "kb" => 1 << 10,
+            "mb" => 1 << 20,
+            _ => return Err(error()),
+        };
+        value
+            .checked_mul(scale)
+            .map(HistoryLimit::Bytes)
+            .ok_or_else(error)
+    }
+}
+
+/// The number of values a `Values` holds without allocating. No instruction but
+/// `DROPN` and `ROLL` pops or pushes more.
+const INLINE_VALUES: usize = 3;
+
+/// The values an instruction popped off or pushed onto the stack. Up to
+/// `INLINE_VALUES` values are stored inline, so that recording most instructions
+/// does not allocate.
+#[derive(Clone)]
+pub(crate) enum Values {
+    Inline {
+        len: u8,
+        values: [i32; INLINE_VALUES],
+    },
+    Heap(Vec<i32>),
+}
+
+impl Values {
+    pub(crate) fn new() -> Self {
+        Values::Inline {
+            len: 0,
+            values: [0; INLINE_VALUES],
+        }
+    }
+
+    fn push(&mut self, value: i32) {
+        match self {
+            Values::Inline { len, values } if usize::from(*len) < INLINE_VALUES => {
+                values[usize::from(*len)] = value;
+                *len += 1;
+            }
+            Values::Inline { values, .. } => {
+                let mut heap = Vec::with_capacity(2 * INLINE_VALUES);
+                heap.extend_from_slice(values);
+                heap.push(value);
+                *self = Values::Heap(heap);
+            }
+            Values::Heap(values) => values.push(value),
+        }
+    }
+}
+
+impl Default for Values {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for Values {
+    type Target = [i32];
+
+    fn deref(&self) -> &[i32] {
+        match self {
+            Values::Inline { len, values } => &values[..usize::from(*len)],
+            Values::Heap(values) => values,
+        }
+    }
+}
+
+impl FromIterator<i32> for Values {
+    fn from_iter<I: IntoIterator<Item = i32>>(iter: I) -> Self {
+        let mut values = Values::new();
+        for value in iter {
+            values.push(value);
+        }
+        values
+    }
+}
+
+impl<const N: usize> From<[i32; N]> for Values {
+    fn from(values: [i32; N]) -> Self {
+        values.into_iter().collect()
+    }
+}
+
+impl From<Vec<i32>> for Values {
+    fn from(values: Vec<i32>) -> Self {
+        if values.len() <= INLINE_VALUES {
+            values.into_iter().collect()
+        } else {
+            Values::Heap(values)
+        }
+    }
+}
+
+impl PartialEq for Values {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Values {}
+
+impl PartialEq<Vec<i32>> for Values {
+    fn eq(&self, other: &Vec<i32>) -> bool {
+        **self == **other
+    }
+}
+
+impl fmt::Debug for Values {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Values {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Values {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<i32>::deserialize(deserializer).map(Values::from)
+    }
+}
+
+/// Represents an entry in the execution history of the interpreter. Each entry records:
+/// - The `instruction` that was executed, shared with the program rather than copied.
+/// - The program counter `pc` at which the instruction was executed.
+/// - The program counter `next_pc` the instruction moved on to.
+/// - The values that were `popped_values` off the stack during the execution of the instruction.
+/// - The values that were `pushed_values` onto the stack as a result of executing the instruction.
+/// - The `effect` the instruction had on interpreter state other than the stack, if any.
+///
+/// This structure is used to enable undo functionality in the interpreter by reversing
+/// the stack changes and restoring the program counter for each executed instruction,
+/// and redo functionality by replaying them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct HistoryEntry {
+    pub(crate) instruction: Arc<Instruction>,
+    pub(crate) pc: usize,
+    pub(crate) next_pc: usize,
+    pub(crate) popped_values: Values,
+    pub(crate) pushed_values: Values,
+    pub(crate) effect: Option<Effect>,
+}
+
+/// A change to interpreter state outside the value stack, recorded in a
+/// `HistoryEntry` so that it can be reversed and replayed.
+///
+/// - `Call(address)`: The return `address` was pushed onto the call stack.
+/// - `Return(address)`: The return `address` was popped off the call stack.
+/// - `Store { previous, value }`: The variable named by the `Store` instruction was
+///   bound to `value`. `previous` holds the value it replaced, or `None` if the
+///   variable was unset.
+/// - `MemoryWrite { address, previous, value }`: The memory cell at `address` was
+///   overwritten with `value`. `previous` holds the value it replaced.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum Effect {
+    Call(usize),
+    Return(usize),
+    Store {
+        previous: Option<i32>,
+        value: i32,
+    },
+    MemoryWrite {
+        address: usize,
+        previous: i32,
+        value: i32,
+    },
+}
+
+/// Applies the stack changes of `entry` to `stack`, as executing it did.
+pub(crate) fn apply(stack: &mut Vec<i32>, entry: &HistoryEntry) {
+    stack.truncate(stack.len().saturating_sub(entry.popped_values.len()));
+    stack.extend_from_slice(&entry.pushed_values);
+}
+
+/// Reverses the stack changes of `entry` on `stack`, as undoing it does. The popped
+/// values are recorded top first.
+fn unapply(stack: &mut Vec<i32>, entry: &HistoryEntry) {
+    stack.truncate(stack.len().saturating_sub(entry.pushed_values.len()));
+    stack.extend(entry.popped_values.iter().rev());
+}
+
+/// The executed instructions of an interpreter, oldest first. Positions count every
+/// instruction executed since the start, including those discarded to stay within
+/// the limit, so they do not change when older entries are discarded.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "SavedHistory", into = "SavedHistory")
+)]
+pub(crate) struct History {
+    /// Sealed blocks of `BLOCK_LEN` encoded entries, oldest first.
+    blocks: VecDeque<Vec<u8>>,
+    /// The entries after the sealed blocks.
+    recent: Vec<HistoryEntry>,
+    /// The number of entries discarded from the start.
+    start: usize,
+    /// The stack before the first retained entry.
+    snapshot: Vec<i32>,
+    /// The stack before every sealed block starting at a multiple of
+    /// `SNAPSHOT_INTERVAL` blocks but the first, with its position, oldest first.
+    snapshots: VecDeque<(usize, Vec<i32>)>,
+    /// The stack before the recent entries.
+    recent_snapshot: Vec<i32>,
+    limit: HistoryLimit,
+    /// The sizes of the sealed blocks, of the recent entries and of the snapshots,
+    /// in bytes.
+    sealed_bytes: usize,
+    recent_bytes: usize,
+    snapshot_bytes: usize,
+}
+
+impl History {
+    /// Returns the position after the last entry.
+    pub(crate) fn len(&self) -> usize {
+        self.start + self.blocks.len() * BLOCK_LEN + self.recent.len()
+    }
+
+    /// Returns the position of the first retained entry.
+    pub(crate) fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Returns `true` if no entries are retained.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.blocks.is_empty() && self.recent.is_empty()
+    }
+
+    /// Returns the stack before the first retained entry.
+    pub(crate) fn snapshot(&self) -> &[i32] {
+        &self.snapshot
+    }
+
+    pub(crate) fn limit(&self) -> HistoryLimit {
+        self.limit
+    }
+
+    /// Sets the limit, discarding the oldest entries if it is exceeded.
+    pub(crate) fn set_limit(&mut self, limit: HistoryLimit) {
+        self.limit = limit;
+        self.trim();
+    }
+
+    /// Returns the approximate memory used by the retained entries and the snapshots,
+    /// in bytes.
+    pub(crate) fn bytes(&self) -> usize {
+        self.sealed_bytes
+            + self.recent_bytes
+            + self.snapshot_bytes
+            + (self.snapshot.len() + self.recent_snapshot.len()) * mem::size_of::<i32>()
+    }
+
+    /// Returns the position of the first recent entry.
+    fn recent_start(&self) -> usize {
+        self.start + self.blocks.len() * BLOCK_LEN
+    }
+
+    /// Appends an entry, sealing the oldest recent entries and discarding the oldest
+    /// blocks as needed.
+    pub(crate) fn push(&mut self, entry: HistoryEntry) {
+        self.recent_bytes += entry_size(&entry);
+        self.recent.push(entry);
+        if self.recent.len() == 2 * BLOCK_LEN {
+            let position = self.recent_start();
+            if position.is_multiple_of(SNAPSHOT_INTERVAL * BLOCK_LEN) && position > self.start {
+                self.snapshot_bytes += self.recent_snapshot.len() * mem::size_of::<i32>();
+                self.snapshots
+                    .push_back((position, self.recent_snapshot.clone()));
+            }
+            let block: Vec<HistoryEntry> = self.recent.drain(..BLOCK_LEN).collect();
+            self.recent_bytes -= block.iter().map(entry_size).sum::<usize>();
+            for entry in &block {
+                apply(&mut self.recent_snapshot, entry);
+            }
+            let bytes = encode_block(&block);
+            self.sealed_bytes += bytes.len();
+            self.blocks.push_back(bytes);
+            self.trim();
+        }
+    }
+
+    /// Removes and returns the last entry, or `None` if no entries are retained.
+    pub(crate) fn pop(&mut self) -> Option<HistoryEntry> {
+        self.unseal();
+        let entry = self.recent.pop()?;
+        self.recent_bytes -= entry_size(&entry);
+        Some(entry)
+    }
+
+    /// Returns the newest entry, the one `.pop()` would remove.
+    pub(crate) fn last(&mut self) -> Option<&HistoryEntry> {
+        self.unseal();
+        self.recent.last()
+    }
+
+    /// Decodes the newest block if there are no unsealed entries left.
+    fn unseal(&mut self) {
+        if !self.recent.is_empty() {
+            return;
+        }
+        if let Some(bytes) = self.blocks.pop_back() {
+            self.sealed_bytes -= bytes.len();
+            self.recent = decode_block(&bytes);
+            self.recent_bytes = self.recent.iter().map(entry_size).sum();
+            if self.blocks.is_empty() {
+                self.recent_snapshot = self.snapshot.clone();
+            } else if self
+                .snapshots
+                .back()
+                .is_some_and(|(position, _)| *position == self.recent_start())
+            {
+                let (_, snapshot) = self.snapshots.pop_back().unwrap();
+                self.snapshot_bytes -= snapshot.len() * mem::size_of::<i32>();
+                self.recent_snapshot = snapshot;
+            } else {
+                for entry in self.recent.iter().rev() {
+                    unapply(&mut self.recent_snapshot, entry);
+                }
+            }
+        }
+    }
+
+    /// Returns the retained entries, oldest first.
+    pub(crate) fn entries(&self) -> Vec<HistoryEntry> {
+        self.iter_from(self.start).collect()
+    }
+
+    /// Returns the retained entries from `position` on, oldest first, decoding the
+    /// sealed blocks as they are reached.
+    pub(crate) fn iter_from(&self, position: usize) -> impl Iterator<Item = HistoryEntry> + '_ {
+        let position = position.clamp(self.start, self.len());
+        let block = ((position - self.start) / BLOCK_LEN).min(self.blocks.len());
+        self.blocks
+            .range(block..)
+            .flat_map(|bytes| decode_block(bytes))
+            .chain(self.recent.iter().cloned())
+            .skip(position - self.start - block * BLOCK_LEN)
+    }
+
+    /// Returns the stack after the retained entries before `position`, from the
+    /// nearest snapshot before it.
+    pub(crate) fn stack_at(&self, position: usize) -> Vec<i32> {
+        let position = position.clamp(self.start, self.len());
+        let recent_start = self.recent_start();
+        let (from, mut stack) = if position >= recent_start {
+            (recent_start, self.recent_snapshot.clone())
+        } else {
+            match self.snapshots.partition_point(|(at, _)| *at <= position) {
+                0 => (self.start, self.snapshot.clone()),
+                index => self.snapshots[index - 1].clone(),
+            }
+        };
+        for entry in self.iter_from(from).take(position - from) {
+            apply(&mut stack, &entry);
+        }
+        stack
+    }
+
+    /// Discards the oldest blocks while the limit is exceeded.
+    fn trim(&mut self) {
+        while !self.blocks.is_empty() {
+            let exceeded = match self.limit {
+                HistoryLimit::Unbounded => false,
+                HistoryLimit::Entries(entries) => self.len() - self.start - BLOCK_LEN >= entries,
+                HistoryLimit::Bytes(bytes) => self.bytes() > bytes,
+            };
+            if !exceeded {
+                break;
+            }
+            let bytes = self.blocks.pop_front().unwrap();
+            self.sealed_bytes -= bytes.len();
+            self.start += BLOCK_LEN;
+            if self
+                .snapshots
+                .front()
+                .is_some_and(|(position, _)| *position == self.start)
+            {
+                let (_, snapshot) = self.snapshots.pop_front().unwrap();
+                self.snapshot_bytes -= snapshot.len() * mem::size_of::<i32>();
+                self.snapshot = snapshot;
+            } else {
+                for entry in decode_block(&bytes) {
+                    apply(&mut self.snapshot, &entry);
+                }
+            }
+        }
+    }
+}
+
+/// The approximate memory used by an unsealed entry, in bytes.
+fn entry_size(entry: &HistoryEntry) -> usize {
+    let heap = [&entry.popped_values, &entry.pushed_values]
+        .into_iter()
+        .filter(|values| matches!(values, Values::Heap(_)))
+        .map(|values| values.len() * mem::size_of::<i32>())
+        .sum::<usize>();
+    mem::size_of::<HistoryEntry>() + heap
+}
+
+fn encode_block(entries: &[HistoryEntry]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut pc = 0;
+    for entry in entries {
+        encode_instruction(&mut bytes, &entry.instruction);
+        write_offset(&mut bytes, entry.pc.wrapping_sub(pc));
+        write_offset(
+            &mut bytes,
+            entry.next_pc.wrapping_sub(entry.pc.wrapping_add(1)),
+        );
+        let mut previous = 0;
+        for values in [&entry.popped_values, &entry.pushed_values] {
+            write_varint(&mut bytes, values.len());
+            for &value in values.iter() {
+                write_value(&mut bytes, value.wrapping_sub(previous));
+                previous = value;
+            }
+        }
+        match &entry.effect {
+            None => bytes.push(0),
+            Some(Effect::Call(address)) => {
+                bytes.push(1);
+                write_varint(&mut bytes, *address);
+            }
+            Some(Effect::Return(address)) => {
+                bytes.push(2);
+                write_varint(&mut bytes, *address);
+            }
+            Some(Effect::Store { previous, value }) => {
+                bytes.push(if previous.is_some() { 4 } else { 3 });
+                if let Some(previous) = previous {
+                    write_value(&mut bytes, *previous);
+                }
+                write_value(&mut bytes, *value);
+            }
+            Some(Effect::MemoryWrite {
+                address,
+                previous,
+                value,
+            }) => {
+                bytes.push(5);
+                write_varint(&mut bytes, *address);
+                write_value(&mut bytes, *previous);
+                write_value(&mut bytes, *value);
+            }
+        }
+        pc = entry.next_pc;
+    }
+    bytes.shrink_to_fit();
+    bytes
+}
+
+/// Decodes a block written by `encode_block`. Blocks never leave the history, so
+/// they are always well-formed.
+fn decode_block(bytes: &[u8]) -> Vec<HistoryEntry> {
+    let mut reader = Reader::new(bytes);
+    let mut entries = Vec::with_capacity(BLOCK_LEN);
+    let mut pc = 0;
+    while reader.remaining() > 0 {
+        let entry = decode_entry(&mut reader, pc).expect("history blocks are well-formed");
+        pc = entry.next_pc;
+        entries.push(entry);
+    }
+    entries
+}
+
+fn decode_entry(reader: &mut Reader, previous_pc: usize) -> Result<HistoryEntry, DecodeError> {
+    let instruction = Arc::new(reader.instruction()?);
+    let pc = previous_pc.wrapping_add(read_offset(reader)?);
+    let next_pc = pc.wrapping_add(1).wrapping_add(read_offset(reader)?);
+    let mut previous: i32 = 0;
+    let mut read_values = |reader: &mut Reader| -> Result<Values, DecodeError> {
+        let len = reader.varint()?;
+        let mut values = Values::new();
+        for _ in 0..len {
+            previous = previous.wrapping_add(read_value(reader)?);
+            values.push(previous);
+        }
+        Ok(values)
+    };
+    let popped_values = read_values(reader)?;
+    let pushed_values = read_values(reader)?;
+    let effect = match reader.byte()? {
+        1 => Some(Effect::Call(reader.varint()?)),
+        2 => Some(Effect::Return(reader.varint()?)),
+        tag @ (3 | 4) => {
+            let previous = if tag == 4 {
+                Some(read_value(reader)?)
+            } else {
+                None
+            };
+            Some(Effect::Store {
+                previous,
+                value: read_value(reader)?,
+            })
+        }
+        5 => Some(Effect::MemoryWrite {
+            address: reader.varint()?,
+            previous: read_value(reader)?,
+            value: read_value(reader)?,
+        }),
+        _ => None,
+    };
+    Ok(HistoryEntry {
+        instruction,
+        pc,
+        next_pc,
+        popped_values,
+        pushed_values,
+        effect,
+    })
+}
+
+/// Appends a wrapping difference between two program counters as a zigzag varint,
+/// so that small backward offsets stay small.
+fn write_offset(bytes: &mut Vec<u8>, offset: usize) {
+    let offset = offset as isize;
+    write_varint(
+        bytes,
+        ((offset << 1) ^ (offset >> (isize::BITS - 1))) as usize,
+    );
+}
+
+fn read_offset(reader: &mut Reader) -> Result<usize, DecodeError> {
+    let zigzag = reader.varint()?;
+    Ok(((zigzag >> 1) as isize ^ -((zigzag & 1) as isize)) as usize)
+}
+
+/// Appends a value as a zigzag varint, so that values close to zero take few bytes.
+fn write_value(bytes: &mut Vec<u8>, value: i32) {
+    write_varint(bytes, ((value << 1) ^ (value >> 31)) as u32 as usize);
+}
+
+fn read_value(reader: &mut Reader) -> Result<i32, DecodeError> {
+    let zigzag = reader.varint()? as u32;
+    Ok((zigzag >> 1) as i32 ^ -((zigzag & 1) as i32))
+}
+
+/// The serialized form of a `History`, with its entries decoded so that saved
+/// sessions do not depend on the block encoding.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SavedHistory {
+    start: usize,
+    snapshot: Vec<i32>,
+    limit: HistoryLimit,
+    entries: Vec<HistoryEntry>,
+}
+
+#[cfg(feature = "serde")]
+impl From<History> for SavedHistory {
+    fn from(history: History) -> Self {
+        SavedHistory {
+            entries: history.entries(),
+            start: history.start,
+            snapshot: history.snapshot,
+            limit: history.limit,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<SavedHistory> for History {
+    fn from(saved: SavedHistory) -> Self {
+        let mut history = History {
+            start: saved.start,
+            recent_snapshot: saved.snapshot.clone(),
+            snapshot: saved.snapshot,
+            ..History::default()
+        };
+        for entry in saved.entries {
+            history.push(entry);
+        }
+        // The entries were retained within the limit when saved, so they are all kept.
+        history.limit = saved.limit;
+        history
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn entry_strategy() -> impl Strategy<Value = HistoryEntry> {
+        let effect = prop_oneof![
+            Just(None),
+            any::<usize>().prop_map(|address| Some(Effect::Call(address))),
+            any::<usize>().prop_map(|address| Some(Effect::Return(address))),
+            (any::<Option<i32>>(), any::<i32>())
+                .prop_map(|(previous, value)| Some(Effect::Store { previous, value })),
+            (any::<usize>(), any::<i32>(), any::<i32>()).prop_map(|(address, previous, value)| {
+                Some(Effect::MemoryWrite {
+                    address,
+                    previous,
+                    value,
+                })
+            }),
+        ];
+        (
+            prop_oneof![
+                any::<i32>().prop_map(Instruction::Push),
+                Just(Instruction::Add),
+                Just(Instruction::Jnz("loop".to_string())),
+            ],
+            any::<usize>(),
+            any::<usize>(),
+            prop::collection::vec(any::<i32>(), 0..6),
+            prop::collection::vec(any::<i32>(), 0..6),
+            effect,
+        )
+            .prop_map(
+                |(instruction, pc, next_pc, popped_values, pushed_values, effect)| HistoryEntry {
+                    instruction: Arc::new(instruction),
+                    pc,
+                    next_pc,
+                    popped_values: popped_values.into(),
+                    pushed_values: pushed_values.into(),
+                    effect,
+                },
+            )
+    }
+
+    /// An entry for `PUSH value` executed at `pc`.
+    fn push_entry(pc: usize, value: i32) -> HistoryEntry {
+        HistoryEntry {
+            instruction: Arc::new(Instruction::Push(value)),
+            pc,
+            next_pc: pc + 1,
+            popped_values: Values::new(),
+            pushed_values: Values::from([value]),
+            effect: None,
+        }
+    }
+
+    #[test]
+    fn test_property_block_round_trip() {
+        proptest!(|(entries in prop::collection::vec(entry_strategy(), 0..BLOCK_LEN))| {
+            prop_assert_eq!(decode_block(&encode_block(&entries)), entries);
+        });
+    }
+
+    #[test]
+    fn test_property_push_and_pop() {
+        proptest!(|(entries in prop::collection::vec(entry_strategy(), 0..4 * BLOCK_LEN))| {
+            let mut history = History::default();
+            for entry in &entries {
+                history.push(entry.clone());
+            }
+            prop_assert_eq!(history.len(), entries.len());
+            prop_assert_eq!(&history.entries(), &entries);
+            for entry in entries.iter().rev() {
+                prop_assert_eq!(history.pop(), Some(entry.clone()));
+            }
+            prop_assert!(history.pop().is_none());
+            prop_assert_eq!(history.bytes(), 0);
+        });
+    }
+
+    #[test]
+    fn test_sealed_entries_are_compact() {
+        let mut history = History::default();
+        for pc in 0..4 * BLOCK_LEN {
+            history.push(push_entry(pc, 1));
+        }
+        // PUSH 1 takes 11 bytes: opcode, 4-byte operand, two offsets, two counts, one
+        // value and no effect. The first entries of the last two blocks take one more
+        // byte for their offset from the start of the program.
+        assert_eq!(history.blocks.len(), 3);
+        assert_eq!(history.sealed_bytes, 3 * BLOCK_LEN * 11 + 2);
+    }
+
+    #[test]
+    fn test_entries_limit() {
+        let mut history = History::default();
+        history.set_limit(HistoryLimit::Entries(100));
+        for pc in 0..1000 {
+            history.push(push_entry(pc, pc as i32));
+        }
+        assert_eq!(history.len(), 1000);
+        let retained = history.len() - history.start();
+        assert!((100..100 + 2 * BLOCK_LEN).contains(&retained));
+
+        // The snapshot is the stack before the first retained entry
+        let expected: Vec<i32> = (0..history.start() as i32).collect();
+        assert_eq!(history.snapshot(), &expected[..]);
+        assert_eq!(
+            history.entries()[0],
+            push_entry(history.start(), history.start() as i32)
+        );
+
+        while history.pop().is_some() {}
+        assert_eq!(history.len(), history.start());
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn test_periodic_snapshots() {
+        let mut history = History::default();
+        history.set_limit(HistoryLimit::Entries(2000));
+        for pc in 0..5000 {
+            history.push(push_entry(pc, pc as i32));
+        }
+        let stack_at = |position: usize| (0..position as i32).collect::<Vec<_>>();
+        assert_eq!(history.snapshots.len(), 2);
+        for position in [history.start(), 4096, 4097, 4500, 5000] {
+            assert_eq!(history.stack_at(position), stack_at(position));
+        }
+        assert_eq!(history.iter_from(4500).next(), Some(push_entry(4500, 4500)));
+        assert_eq!(history.iter_from(4500).count(), 500);
+
+        // Reversing restores the stack before the recent entries
+        while history.len() > 4000 {
+            history.pop();
+        }
+        assert_eq!(history.snapshots.len(), 1);
+        assert_eq!(history.recent_snapshot, stack_at(history.recent_start()));
+        assert_eq!(history.stack_at(3000), stack_at(3000));
+    }
+
+    #[test]
+    fn test_reversing_restores_popped_values_in_order() {
+        let mut history = History::default();
+        let mut stack = Vec::new();
+        let mut stacks = vec![stack.clone()];
+        for pc in 0..300 {
+            let entry = if pc % 3 == 2 {
+                // SUB pops the two values pushed before it, top first
+                let (b, a) = (stack[stack.len() - 1], stack[stack.len() - 2]);
+                HistoryEntry {
+                    instruction: Arc::new(Instruction::Sub),
+                    pc,
+                    next_pc: pc + 1,
+                    popped_values: Values::from([b, a]),
+                    pushed_values: Values::from([a - b]),
+                    effect: None,
+                }
+            } else {
+                push_entry(pc, pc as i32)
+            };
+            apply(&mut stack, &entry);
+            stacks.push(stack.clone());
+            history.push(entry);
+        }
+
+        while history.len() > 100 {
+            history.pop();
+        }
+        assert_eq!(history.recent_snapshot, stacks[history.recent_start()]);
+        for position in [0, 64, 65, 100] {
+            assert_eq!(history.stack_at(position), stacks[position]);
+        }
+    }
+
+    #[test]
+    fn test_bytes_limit() {
+        let mut history = History::default();
+        for pc in 0..1000 {
+            history.push(push_entry(pc, 0));
+        }
+        assert_eq!(history.start(), 0);
+
+        // The recent entries are kept even if they alone exceed the limit
+        history.set_limit(HistoryLimit::Bytes(1));
+        assert_eq!(history.len() - history.start(), history.recent.len());
+        assert_eq!(history.snapshot().len(), history.start());
+    }
+
+    #[test]
+    fn test_values_stay_inline() {
+        let values = Values::from([1, 2, 3]);
+        assert!(matches!(values, Values::Inline { len: 3, .. }));
+        assert_eq!(values, vec![1, 2, 3]);
+
+        let values: Values = (1..=5).collect();
+        assert!(matches!(values, Values::Heap(_)));
+        assert_eq!(values, vec![1, 2, 3, 4, 5]);
+        assert_eq!(Values::from(vec![1, 2, 3, 4, 5]), values);
+        assert_eq!(Values::from(vec![1]), Values::from([1]));
+    }
+
+    #[test]
+    fn test_history_limit_from_str() {
+        assert_eq!("unbounded".parse(), Ok(HistoryLimit::Unbounded));
+        assert_eq!("5000".parse(), Ok(HistoryLimit::Entries(5000)));
+        assert_eq!("100b".parse(), Ok(HistoryLimit::Bytes(100)));
+        assert_eq!("64KB".parse(), Ok(HistoryLimit::Bytes(64 * 1024)));
+        assert_eq!("2mb".parse(), Ok(HistoryLimit::Bytes(2 * 1024 * 1024)));
+        assert!("".parse::<HistoryLimit>().is_err());
+        assert!("KB".parse::<HistoryLimit>().is_err());
+        assert!("10GB".parse::<HistoryLimit>().is_err());
+        for limit in [
+            HistoryLimit::Unbounded,
+            HistoryLimit::Entries(7),
+            HistoryLimit::Bytes(9),
+        ] {
+            assert_eq!(limit.to_string().parse(), Ok(limit));
+        }
+    }
+}
@@ -2,7 +2,8 @@
 //! It provides the capability to execute arithmetic and stack manipulation instructions
 //! with forward execution and backward undo functionality. The interpreter maintains
 //! a history of executed instructions, allowing for state reversal. This module is
-//! designed to support basic instructions like push, pop, and arithmetic operations
+//! designed to support basic instructions like push, pop, Forth-style stack shuffling
+//! (dup, swap, over, rot, nip, tuck, pick and roll), and arithmetic operations
 //! (addition, subtraction, multiplication, and division).
 //!
 //! # Examples
@@ -56,559 +57,4454 @@
 //! assert_eq!(interpreter.stack(), &vec![10, 0]); // Stack is restored to previous state.
 //! ```
 //! In this example, the division of 10 by 0 results in a `DivideByZero` error, and the stack is not changed.
+//! The error describes where it happened and on which values:
+//!
+//! ```rust
+//! # use reversible_interpreter::interpreter::{Instruction, Interpreter};
+//! # let mut interpreter = Interpreter::new();
+//! # interpreter.add_instructions(&[Instruction::Push(10), Instruction::Push(0), Instruction::Div]);
+//! let error = interpreter.run().unwrap_err();
+//! assert_eq!(error.to_string(), "DIV at instruction 2: divide by zero (10 / 0)");
+//! ```
+//!
+//! ## Serialization
+//!
+//! With the `serde` feature enabled, `Instruction`, `RuntimeError` and `Interpreter`
+//! implement `serde::Serialize` and `serde::Deserialize`. A serialized interpreter
+//! includes its program, stack, variables, memory and history, so a restored
+//! session can be stepped backwards exactly like the original. Deserializing an
+//! interpreter fails if its state is inconsistent, for example in a corrupt file.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 
-use std::collections::VecDeque;
+pub use crate::history::HistoryLimit;
+use crate::history::{apply, Effect, History, HistoryEntry, Values};
 
 /// Represents the possible instructions that can be executed by the interpreter.
 ///
 /// - `Push(i32)`: Pushes an integer value onto the stack.
 /// - `Pop`: Pops the top value off the stack.
+/// - `Dup`: Duplicates the top value (`a -- a a`).
+/// - `Swap`: Swaps the top two values (`a b -- b a`).
+/// - `Over`: Copies the second value to the top (`a b -- a b a`).
+/// - `Rot`: Rotates the third value to the top (`a b c -- b c a`).
+/// - `Nip`: Removes the second value (`a b -- b`).
+/// - `Tuck`: Copies the top value below the second (`a b -- b a b`).
+/// - `DropN(n)`: Pops the top `n` values off the stack.
+/// - `Pick(n)`: Copies the `n`th value (counting from 0 at the top) to the top.
+///   `Pick(0)` is equivalent to `Dup` and `Pick(1)` to `Over`.
+/// - `Roll(n)`: Moves the `n`th value (counting from 0 at the top) to the top.
+///   `Roll(1)` is equivalent to `Swap` and `Roll(2)` to `Rot`.
 /// - `Add`: Pops the top two values, adds them, and pushes the result.
 /// - `Sub`: Pops the top two values, subtracts the second from the first, and pushes the result.
 /// - `Mul`: Pops the top two values, multiplies them, and pushes the result.
 /// - `Div`: Pops the top two values, divides the first by the second, and pushes the result.
-///          If division by zero is attempted, it results in an error.
+///   If division by zero is attempted, it results in an error.
+/// - `Mod`: Pops the top two values and pushes the remainder of dividing the first by the
+///   second. The remainder has the sign of the dividend. Division by zero is an error.
+/// - `Neg`: Pops the top value and pushes its negation.
+/// - `Abs`: Pops the top value and pushes its absolute value.
+/// - `Min`, `Max`: Pops the top two values and pushes the smaller or larger of them.
+/// - `Pow`: Pops the top two values `a b` and pushes `a` raised to the power `b`.
+///   A negative exponent is an error.
+/// - `DivMod`: Pops the top two values `a b` and pushes the quotient `a / b` followed
+///   by the remainder `a % b`. Division by zero is an error.
+/// - `Eq`, `Ne`, `Lt`, `Gt`, `Le`, `Ge`: Pops the top two values `a b` and pushes `1`
+///   if the comparison `a ? b` holds (e.g. `Lt` pushes `1` if `a < b`), `0` otherwise.
+/// - `And`, `Or`: Pops the top two values and pushes their logical conjunction or
+///   disjunction as `1` or `0`. Any non-zero value counts as true.
+/// - `Not`: Pops the top value and pushes `1` if it is zero, `0` otherwise.
+/// - `BitAnd`, `BitOr`, `BitXor`: Pops the top two values and pushes their bitwise
+///   and, or, or exclusive or.
+/// - `BitNot`: Pops the top value and pushes its bitwise complement.
+/// - `Shl`, `Shr`, `Ushr`: Pops the top two values `a b` and pushes `a` shifted by `b`
+///   bits to the left, arithmetically to the right (sign-extending), or logically to
+///   the right (zero-filling). `b` must be in `0..32`.
+/// - `Rotl`, `Rotr`: Pops the top two values `a b` and pushes `a` rotated by `b` bits
+///   to the left or right. `b` must be in `0..32`.
+/// - `Load(name)`: Pushes the value of the variable `name`. Loading a variable that
+///   has never been stored is an error.
+/// - `Store(name)`: Pops the top value and binds it to the variable `name`, replacing
+///   any previous binding.
+/// - `FetchCell`: Pops an address and pushes the value of the memory cell at that
+///   address (`addr -- x`). Written `@` in program text.
+/// - `StoreCell`: Pops an address and a value and writes the value to the memory cell
+///   at that address (`x addr --`). Written `!` in program text.
+/// - `Label(name)`: Marks a jump target. Executing it has no effect.
+/// - `Jmp(name)`: Jumps unconditionally to the label `name`.
+/// - `Jz(name)`, `Jnz(name)`: Pops the top value and jumps to the label `name` if it
+///   is zero (`Jz`) or non-zero (`Jnz`).
+/// - `Call(name)`: Pushes the address of the following instruction onto the call stack
+///   and jumps to the label `name`.
+/// - `Ret`: Pops a return address off the call stack and jumps to it.
+/// - `Jeq(name)`, `Jne(name)`, `Jlt(name)`, `Jgt(name)`, `Jle(name)`, `Jge(name)`: Pops
+///   the top two values `a b` and jumps to the label `name` if the comparison `a ? b`
+///   holds (e.g. `Jlt` jumps if `a < b`).
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Instruction {
     Push(i32),
     Pop,
+    Dup,
+    Swap,
+    Over,
+    Rot,
+    Nip,
+    Tuck,
+    DropN(usize),
+    Pick(usize),
+    Roll(usize),
     Add,
     Sub,
     Mul,
     Div,
+    Mod,
+    Neg,
+    Abs,
+    Min,
+    Max,
+    Pow,
+    DivMod,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    And,
+    Or,
+    Not,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
+    Ushr,
+    Rotl,
+    Rotr,
+    Load(String),
+    Store(String),
+    FetchCell,
+    StoreCell,
+    Label(String),
+    Jmp(String),
+    Jz(String),
+    Jnz(String),
+    Jeq(String),
+    Jne(String),
+    Jlt(String),
+    Jgt(String),
+    Jle(String),
+    Jge(String),
+    Call(String),
+    Ret,
 }
 
-/// Represents an entry in the execution history of the interpreter. Each entry records:
-/// - The `instruction` that was executed.
-/// - The values that were `popped_values` off the stack during the execution of the instruction.
-/// - The values that were `pushed_values` onto the stack as a result of executing the instruction.
-///
-/// This structure is used to enable undo functionality in the interpreter by reversing
-/// the stack changes for each executed instruction.
-#[derive(Debug)]
-struct HistoryEntry {
-    instruction: Instruction,
-    popped_values: Vec<i32>,
-    pushed_values: Vec<i32>,
+impl Instruction {
+    /// Returns the name of the instruction as written in program text, e.g. `"DIV"`.
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Push(_) => "PUSH",
+            Instruction::Pop => "POP",
+            Instruction::Dup => "DUP",
+            Instruction::Swap => "SWAP",
+            Instruction::Over => "OVER",
+            Instruction::Rot => "ROT",
+            Instruction::Nip => "NIP",
+            Instruction::Tuck => "TUCK",
+            Instruction::DropN(_) => "DROPN",
+            Instruction::Pick(_) => "PICK",
+            Instruction::Roll(_) => "ROLL",
+            Instruction::Add => "ADD",
+            Instruction::Sub => "SUB",
+            Instruction::Mul => "MUL",
+            Instruction::Div => "DIV",
+            Instruction::Mod => "MOD",
+            Instruction::Neg => "NEG",
+            Instruction::Abs => "ABS",
+            Instruction::Min => "MIN",
+            Instruction::Max => "MAX",
+            Instruction::Pow => "POW",
+            Instruction::DivMod => "DIVMOD",
+            Instruction::Eq => "EQ",
+            Instruction::Ne => "NE",
+            Instruction::Lt => "LT",
+            Instruction::Gt => "GT",
+            Instruction::Le => "LE",
+            Instruction::Ge => "GE",
+            Instruction::And => "AND",
+            Instruction::Or => "OR",
+            Instruction::Not => "NOT",
+            Instruction::BitAnd => "BAND",
+            Instruction::BitOr => "BOR",
+            Instruction::BitXor => "BXOR",
+            Instruction::BitNot => "BNOT",
+            Instruction::Shl => "SHL",
+            Instruction::Shr => "SHR",
+            Instruction::Ushr => "USHR",
+            Instruction::Rotl => "ROTL",
+            Instruction::Rotr => "ROTR",
+            Instruction::Load(_) => "LOAD",
+            Instruction::Store(_) => "STORE",
+            Instruction::FetchCell => "@",
+            Instruction::StoreCell => "!",
+            Instruction::Label(_) => "LABEL",
+            Instruction::Jmp(_) => "JMP",
+            Instruction::Jz(_) => "JZ",
+            Instruction::Jnz(_) => "JNZ",
+            Instruction::Jeq(_) => "JEQ",
+            Instruction::Jne(_) => "JNE",
+            Instruction::Jlt(_) => "JLT",
+            Instruction::Jgt(_) => "JGT",
+            Instruction::Jle(_) => "JLE",
+            Instruction::Jge(_) => "JGE",
+            Instruction::Call(_) => "CALL",
+            Instruction::Ret => "RET",
+        }
+    }
+
+    /// Returns the infix operator of binary instructions that have a conventional
+    /// one, e.g. `"/"` for `Div`.
+    pub fn operator(&self) -> Option<&'static str> {
+        match self {
+            Instruction::Add => Some("+"),
+            Instruction::Sub => Some("-"),
+            Instruction::Mul => Some("*"),
+            Instruction::Div => Some("/"),
+            Instruction::Mod => Some("%"),
+            Instruction::Pow => Some("**"),
+            Instruction::Eq => Some("=="),
+            Instruction::Ne => Some("!="),
+            Instruction::Lt => Some("<"),
+            Instruction::Gt => Some(">"),
+            Instruction::Le => Some("<="),
+            Instruction::Ge => Some(">="),
+            Instruction::And => Some("&&"),
+            Instruction::Or => Some("||"),
+            Instruction::BitAnd => Some("&"),
+            Instruction::BitOr => Some("|"),
+            Instruction::BitXor => Some("^"),
+            Instruction::Shl => Some("<<"),
+            Instruction::Shr => Some(">>"),
+            Instruction::Ushr => Some(">>>"),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if executing the instruction leaves more values on the stack
+    /// than before. No instruction adds more than one.
+    fn grows_stack(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Push(_)
+                | Instruction::Dup
+                | Instruction::Over
+                | Instruction::Tuck
+                | Instruction::Pick(_)
+                | Instruction::Load(_)
+        )
+    }
+
+    /// Returns the number of stack values the instruction consumes or inspects.
+    pub fn operand_count(&self) -> usize {
+        match self {
+            Instruction::Push(_)
+            | Instruction::Load(_)
+            | Instruction::Label(_)
+            | Instruction::Jmp(_)
+            | Instruction::Call(_)
+            | Instruction::Ret => 0,
+            Instruction::Pop
+            | Instruction::Dup
+            | Instruction::Neg
+            | Instruction::Abs
+            | Instruction::Not
+            | Instruction::BitNot
+            | Instruction::Store(_)
+            | Instruction::FetchCell
+            | Instruction::Jz(_)
+            | Instruction::Jnz(_) => 1,
+            Instruction::Rot => 3,
+            Instruction::DropN(n) => *n,
+            Instruction::Pick(n) | Instruction::Roll(n) => n.saturating_add(1),
+            _ => 2,
+        }
+    }
+}
+
+/// Formats the instruction in canonical program text, e.g. `PUSH 5`, `JMP loop` or
+/// `loop:` for a label, so that it can be assembled back into the same instruction.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Push(value) => write!(f, "{} {}", self.mnemonic(), value),
+            Instruction::DropN(count) | Instruction::Pick(count) | Instruction::Roll(count) => {
+                write!(f, "{} {}", self.mnemonic(), count)
+            }
+            Instruction::Label(name) => write!(f, "{}:", name),
+            Instruction::Load(name)
+            | Instruction::Store(name)
+            | Instruction::Jmp(name)
+            | Instruction::Jz(name)
+            | Instruction::Jnz(name)
+            | Instruction::Jeq(name)
+            | Instruction::Jne(name)
+            | Instruction::Jlt(name)
+            | Instruction::Jgt(name)
+            | Instruction::Jle(name)
+            | Instruction::Jge(name)
+            | Instruction::Call(name) => write!(f, "{} {}", self.mnemonic(), name),
+            _ => f.write_str(self.mnemonic()),
+        }
+    }
 }
 
-/// Represents possible runtime errors that can occur during the interpretation process.
+/// Represents the kinds of runtime errors that can occur during the interpretation process.
 ///
 /// - `DivideByZero`: Attempted to divide by zero.
 /// - `StackUnderflow`: Tried to pop from an empty stack or use insufficient stack values.
 /// - `NoInstructions`: No instructions available for execution.
 /// - `ArithmeticOverflow`: An arithmetic operation caused an overflow.
 /// - `InvalidCommand`: Encountered an unrecognized or malformed command.
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub enum RuntimeError {
+/// - `UndefinedLabel`: A jump referred to a label that the program does not define.
+/// - `CallStackUnderflow`: Tried to return with an empty call stack.
+/// - `ShiftOutOfRange`: A shift or rotate amount was negative or not less than 32.
+/// - `NegativeExponent`: Tried to raise a value to a negative power.
+/// - `UndefinedVariable`: Tried to load a variable that has never been stored.
+/// - `OutOfBounds`: A memory address was negative or not less than the memory size.
+/// - `UndefinedCheckpoint`: Tried to restore a checkpoint that was never recorded.
+/// - `NothingToRedo`: Tried to redo with no reversed instruction to replay.
+/// - `UndefinedBranch`: Referred to a branch that does not exist.
+/// - `HistoryExhausted`: Tried to reverse past the oldest instruction kept within the
+///   history limit, or to reach a branch that diverged before it.
+/// - `StepLimitExceeded`: Executing would exceed `ExecutionLimits::max_steps`.
+/// - `StackLimitExceeded`: Executing would exceed `ExecutionLimits::max_stack`.
+/// - `HistoryLimitExceeded`: Executing would exceed `ExecutionLimits::max_history`.
+/// - `InconsistentHistory`: The instruction to reverse or replay did not leave or
+///   does not start from the current state, so doing so would corrupt it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ErrorKind {
     DivideByZero,
     StackUnderflow,
     NoInstructions,
     ArithmeticOverflow,
     InvalidCommand,
+    UndefinedLabel,
+    CallStackUnderflow,
+    ShiftOutOfRange,
+    NegativeExponent,
+    UndefinedVariable,
+    OutOfBounds,
+    UndefinedCheckpoint,
+    NothingToRedo,
+    UndefinedBranch,
+    HistoryExhausted,
+    StepLimitExceeded,
+    StackLimitExceeded,
+    HistoryLimitExceeded,
+    InconsistentHistory,
 }
 
-/// The `Interpreter` struct manages the state of the stack-based instruction execution.
-/// It holds:
-/// - `instructions`: A queue of instructions to be executed.
-/// - `stack`: A vector representing the current state of the stack.
-/// - `history`: A list of past executions to allow for reversing instructions.
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            ErrorKind::DivideByZero => "divide by zero",
+            ErrorKind::StackUnderflow => "stack underflow",
+            ErrorKind::NoInstructions => "no instructions",
+            ErrorKind::ArithmeticOverflow => "arithmetic overflow",
+            ErrorKind::InvalidCommand => "invalid command",
+            ErrorKind::UndefinedLabel => "undefined label",
+            ErrorKind::CallStackUnderflow => "return without a call",
+            ErrorKind::ShiftOutOfRange => "shift amount out of range",
+            ErrorKind::NegativeExponent => "negative exponent",
+            ErrorKind::UndefinedVariable => "undefined variable",
+            ErrorKind::OutOfBounds => "memory address out of bounds",
+            ErrorKind::UndefinedCheckpoint => "undefined checkpoint",
+            ErrorKind::NothingToRedo => "nothing to redo",
+            ErrorKind::UndefinedBranch => "undefined branch",
+            ErrorKind::HistoryExhausted => "history exhausted",
+            ErrorKind::StepLimitExceeded => "step limit exceeded",
+            ErrorKind::StackLimitExceeded => "stack limit exceeded",
+            ErrorKind::HistoryLimitExceeded => "history limit exceeded",
+            ErrorKind::InconsistentHistory => "history does not match the state",
+        };
+        f.write_str(message)
+    }
+}
+
+/// Describes the instruction that failed and the state it failed in:
+/// - `instruction`: The failing instruction.
+/// - `pc`: The program counter, i.e. the index of the failing instruction.
+/// - `line`: The source line the instruction came from, if known. The interpreter does
+///   not know about source text, so this is only filled in through
+///   `RuntimeError::with_line`.
+/// - `stack_depth`: The number of values on the stack when the instruction failed.
+/// - `operands`: The stack values the instruction would have consumed, bottom to top.
+///   There may be fewer than the instruction needs if the stack underflowed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ErrorContext {
+    pub instruction: Instruction,
+    pub pc: usize,
+    pub line: Option<usize>,
+    pub stack_depth: usize,
+    pub operands: Vec<i32>,
+}
+
+/// A runtime error: an `ErrorKind` together with an `ErrorContext` describing the
+/// failing instruction. Errors that are not caused by an instruction, such as
+/// reversing with an empty history, have no context.
 ///
-/// The interpreter supports forward execution of instructions and the ability to undo
-/// previous operations via a backtracking mechanism.
-#[derive(Debug, Default)]
-pub struct Interpreter {
-    instructions: VecDeque<Instruction>,
-    stack: Vec<i32>,
-    history: Vec<HistoryEntry>,
+/// The `Display` implementation renders messages like
+/// `DIV at instruction 2: divide by zero (10 / 0)`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RuntimeError {
+    kind: ErrorKind,
+    context: Option<Box<ErrorContext>>,
 }
 
-impl Interpreter {
-    /// Constructs a new interpreter with an empty list of instructions
-    /// and an empty stack.
-    pub fn new() -> Self {
+impl RuntimeError {
+    /// Returns the kind of the error.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Returns the context of the failing instruction, if the error was caused by one.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        self.context.as_deref()
+    }
+
+    /// Attaches the source line of the failing instruction. Has no effect on errors
+    /// without context.
+    pub fn with_line(mut self, line: usize) -> Self {
+        if let Some(context) = self.context.as_mut() {
+            context.line = Some(line);
+        }
+        self
+    }
+}
+
+impl From<ErrorKind> for RuntimeError {
+    fn from(kind: ErrorKind) -> Self {
         Self {
-            instructions: VecDeque::new(),
-            stack: Vec::new(),
-            history: Vec::new(),
+            kind,
+            context: None,
         }
     }
+}
 
-    /// Adds instructions to the interpreter. The instructions are not
-    // interpreted, just stored.
-    pub fn add_instructions(&mut self, instructions: &[Instruction]) {
-        for instr in instructions {
-            self.instructions.push_back(instr.clone());
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(context) = &self.context else {
+            return write!(f, "{}", self.kind);
+        };
+        write!(f, "{} at ", context.instruction.mnemonic())?;
+        match context.line {
+            Some(line) => write!(f, "line {}", line)?,
+            None => write!(f, "instruction {}", context.pc)?,
+        }
+        write!(f, ": {}", self.kind)?;
+
+        match (context.instruction.operator(), &context.operands[..]) {
+            (_, []) => Ok(()),
+            (Some(operator), [a, b]) => write!(f, " ({} {} {})", a, operator, b),
+            (_, operands) => {
+                let operands: Vec<String> = operands.iter().map(i32::to_string).collect();
+                write!(f, " (operands: {})", operands.join(", "))
+            }
         }
     }
+}
 
-    /// Returns a mutable reference to the next instruction that will be executed
-    /// on the next `.forward()` call.
-    pub fn current_instruction(&mut self) -> Option<&mut Instruction> {
-        self.instructions.get_mut(0)
+impl std::error::Error for RuntimeError {}
+
+/// Selects how arithmetic instructions behave when their result does not fit in an `i32`.
+///
+/// - `Checked`: The instruction fails with `ErrorKind::ArithmeticOverflow` and the
+///   stack is left unchanged. This is the default.
+/// - `Wrapping`: The result wraps around at the boundary of the type, like two's
+///   complement hardware arithmetic.
+/// - `Saturating`: The result is clamped to `i32::MIN` or `i32::MAX`.
+///
+/// Division by zero and negative exponents are errors in every mode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OverflowMode {
+    #[default]
+    Checked,
+    Wrapping,
+    Saturating,
+}
+
+impl OverflowMode {
+    /// Picks the result of an arithmetic operation computed in each of the modes.
+    /// Returns `None` only for an overflow in `OverflowMode::Checked`.
+    fn resolve(self, checked: Option<i32>, wrapping: i32, saturating: i32) -> Option<i32> {
+        match self {
+            OverflowMode::Checked => checked,
+            OverflowMode::Wrapping => Some(wrapping),
+            OverflowMode::Saturating => Some(saturating),
+        }
+    }
+}
+
+impl std::fmt::Display for OverflowMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            OverflowMode::Checked => "checked",
+            OverflowMode::Wrapping => "wrapping",
+            OverflowMode::Saturating => "saturating",
+        };
+        f.write_str(name)
     }
+}
 
-    /// Returns a reference to the instruction queue.
-    pub fn instructions(&self) -> &VecDeque<Instruction> {
-        &self.instructions
+impl std::str::FromStr for OverflowMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "checked" => Ok(OverflowMode::Checked),
+            "wrapping" => Ok(OverflowMode::Wrapping),
+            "saturating" => Ok(OverflowMode::Saturating),
+            _ => Err(format!(
+                "Invalid overflow mode '{}': expected checked, wrapping or saturating",
+                s
+            )),
+        }
     }
+}
 
-    /// Returns a reference to the stack.
-    pub fn stack(&self) -> &Vec<i32> {
-        &self.stack
+/// Limits on the resources a program may use, for running untrusted programs that
+/// could loop forever or grow the stack until memory runs out. `.forward()` and
+/// `.redo()` fail before executing an instruction that would exceed one of them,
+/// leaving the state unchanged:
+/// - `max_steps`: The number of instructions executed or replayed, i.e. `.steps()`.
+///   Reversing instructions does not give steps back, so stepping back and forth
+///   cannot run a program for longer. Exceeding it fails with
+///   `ErrorKind::StepLimitExceeded`.
+/// - `max_stack`: The number of values on the stack. Exceeding it fails with
+///   `ErrorKind::StackLimitExceeded`.
+/// - `max_history`: The memory used by the history kept for reversing, not counting
+///   the redo stack and other branches. Exceeding it fails with
+///   `ErrorKind::HistoryLimitExceeded`, whereas the history limit set with
+///   `.set_history_limit()` discards the oldest instructions instead.
+///
+/// All limits are unset by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExecutionLimits {
+    pub max_steps: Option<usize>,
+    pub max_stack: Option<usize>,
+    pub max_history: HistoryLimit,
+}
+
+/// Receives the events of an `Interpreter` it is registered on with
+/// `.add_observer()`, for building tracers, profilers or user interfaces on top of
+/// it. Every method does nothing by default, and each is passed the interpreter in
+/// its state at the time of the event:
+/// - `before_execute`, `after_execute`: Around executing an instruction with
+///   `.forward()` or any method built on it, and replaying one with `.redo()`.
+/// - `before_undo`, `after_undo`: Around reversing an instruction with `.back()` or
+///   any method built on it.
+/// - `on_error`: When executing, replaying or reversing an instruction fails, in
+///   place of `after_execute` or `after_undo`.
+///
+/// To read back what an observer collected, register it as an `Arc<Mutex<_>>` and
+/// keep a clone of the `Arc`.
+pub trait ExecutionObserver {
+    fn before_execute(&mut self, _interpreter: &Interpreter, _instruction: &Instruction) {}
+
+    fn after_execute(&mut self, _interpreter: &Interpreter, _instruction: &Instruction) {}
+
+    fn before_undo(&mut self, _interpreter: &Interpreter, _instruction: &Instruction) {}
+
+    fn after_undo(&mut self, _interpreter: &Interpreter, _instruction: &Instruction) {}
+
+    fn on_error(&mut self, _interpreter: &Interpreter, _error: &RuntimeError) {}
+}
+
+impl<T: ExecutionObserver> ExecutionObserver for Arc<Mutex<T>> {
+    fn before_execute(&mut self, interpreter: &Interpreter, instruction: &Instruction) {
+        lock(self).before_execute(interpreter, instruction);
     }
 
-    /// Interprets the first instruction in `Self.instructions`.
-    /// If there are no instructions, returns `RuntimeError::NoInstructions`.
-    /// Other errors should be handled as described in the `RuntimeError` struct.
-    pub fn forward(&mut self) -> Result<Instruction, RuntimeError> {
-        // Remove the instruction from the queue
-        let instruction = self
-            .instructions
-            .pop_front()
-            .ok_or(RuntimeError::NoInstructions)?;
+    fn after_execute(&mut self, interpreter: &Interpreter, instruction: &Instruction) {
+        lock(self).after_execute(interpreter, instruction);
+    }
 
-        match instruction {
-            Instruction::Push(value) => {
-                self.stack.push(value);
-                self.history.push(HistoryEntry {
-                    instruction: instruction.clone(),
-                    popped_values: Vec::new(),
-                    pushed_values: vec![value],
-                });
-                Ok(instruction)
-            }
-            Instruction::Pop => {
-                let value = self.stack.pop().ok_or(RuntimeError::StackUnderflow)?;
-                self.history.push(HistoryEntry {
-                    instruction: instruction.clone(),
-                    popped_values: vec![value],
-                    pushed_values: Vec::new(),
-                });
-                Ok(instruction)
-            }
-            Instruction::Add | Instruction::Sub | Instruction::Mul | Instruction::Div => {
-                if self.stack.len() < 2 {
-                    return Err(RuntimeError::StackUnderflow);
-                }
-                // The following pops should never fail since we already checked for underflow above.
-                let b = self.stack.pop().ok_or(RuntimeError::StackUnderflow)?;
-                let a = self.stack.pop().ok_or(RuntimeError::StackUnderflow)?;
+    fn before_undo(&mut self, interpreter: &Interpreter, instruction: &Instruction) {
+        lock(self).before_undo(interpreter, instruction);
+    }
 
-                let result = match instruction {
-                    Instruction::Add => a.checked_add(b),
-                    Instruction::Sub => a.checked_sub(b),
-                    Instruction::Mul => a.checked_mul(b),
-                    Instruction::Div => {
-                        if b == 0 {
-                            // Restore stack before returning error
-                            self.stack.push(a);
-                            self.stack.push(b);
-                            return Err(RuntimeError::DivideByZero);
-                        }
-                        a.checked_div(b)
-                    }
-                    _ => unreachable!(),
-                };
-                if let Some(res) = result {
-                    self.stack.push(res);
-                    self.history.push(HistoryEntry {
-                        instruction: instruction.clone(),
-                        popped_values: vec![b, a],
-                        pushed_values: vec![res],
-                    });
-                    Ok(instruction)
-                } else {
-                    // Restore stack before returning error
-                    self.stack.push(a);
-                    self.stack.push(b);
-                    Err(RuntimeError::ArithmeticOverflow)
-                }
-            }
-        }
+    fn after_undo(&mut self, interpreter: &Interpreter, instruction: &Instruction) {
+        lock(self).after_undo(interpreter, instruction);
     }
 
-    /// Calls `.forward()` until there are no more instructions or
-    /// if there is an error.
-    pub fn run(&mut self) -> Result<(), RuntimeError> {
-        while !self.instructions.is_empty() {
-            self.forward()?;
-        }
-        Ok(())
+    fn on_error(&mut self, interpreter: &Interpreter, error: &RuntimeError) {
+        lock(self).on_error(interpreter, error);
     }
+}
 
-    /// *Reverses* the last instruction executed with `.forward()`.
-    /// This should undo the last instruction and restore the state of
-    /// the stack. Repeated calls should be possible until the stack
-    /// is restored to its original state before the first forward call.
-    ///
-    /// If there is no instruction to reverse, return an error.
-    pub fn back(&mut self) -> Result<(), RuntimeError> {
-        let history_entry = self.history.pop().ok_or(RuntimeError::NoInstructions)?;
+/// Locks a shared observer, even if a previous callback panicked while holding it.
+fn lock<T>(observer: &Mutex<T>) -> MutexGuard<'_, T> {
+    observer.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The observers of an interpreter, taken out of it while they are notified. They
+/// are put back when this is dropped, even if an observer panicked.
+struct Notifying<'a> {
+    interpreter: &'a mut Interpreter,
+    observers: Vec<Box<dyn ExecutionObserver + Send>>,
+}
+
+impl Drop for Notifying<'_> {
+    fn drop(&mut self) {
+        self.interpreter.observers = mem::take(&mut self.observers);
+    }
+}
+
+impl fmt::Debug for dyn ExecutionObserver + Send {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ExecutionObserver")
+    }
+}
 
-        self.instructions
-            .push_front(history_entry.instruction.clone());
+/// A location at which `continue_forward` and `continue_backward` stop:
+/// - `Instruction(index)`: Stops when the instruction at `index` is the next to execute.
+/// - `Label(name)`: Stops when the definition of the label `name` is the next
+///   instruction to execute.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Breakpoint {
+    Instruction(usize),
+    Label(String),
+}
 
-        // Reverse the stack changes
-        // First, remove the values that were pushed
-        // We could also check that the values being popped match the values
-        // that were originally pushed, ensuring the state is consistent.
-        for _ in &history_entry.pushed_values {
-            self.stack.pop().ok_or(RuntimeError::StackUnderflow)?;
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Breakpoint::Instruction(index) => write!(f, "instruction {}", index),
+            Breakpoint::Label(name) => write!(f, "label {}", name),
         }
+    }
+}
+
+/// A change in state at which `continue_forward` and `continue_backward` stop:
+/// - `StackDepth(n)`: Stops when the stack depth becomes `n`.
+/// - `TopOfStack(value)`: Stops when the value on top of the stack becomes `value`.
+/// - `Variable(name)`: Stops when the variable `name` is bound, changed or unbound.
+///
+/// Watchpoints trigger on the instruction that makes the change, so a watchpoint whose
+/// condition already holds does not stop execution until it stops holding and then
+/// holds again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Watchpoint {
+    StackDepth(usize),
+    TopOfStack(i32),
+    Variable(String),
+}
 
-        // Then, push back the values that were popped in reverse order
-        for &value in history_entry.popped_values.iter().rev() {
-            self.stack.push(value);
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Watchpoint::StackDepth(depth) => write!(f, "stack depth {}", depth),
+            Watchpoint::TopOfStack(value) => write!(f, "top of stack {}", value),
+            Watchpoint::Variable(name) => write!(f, "variable {}", name),
         }
+    }
+}
 
-        Ok(())
+/// Why `continue_forward` or `continue_backward` stopped before running out of
+/// instructions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint(Breakpoint),
+    Watchpoint(Watchpoint),
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stop::Breakpoint(breakpoint) => write!(f, "breakpoint at {}", breakpoint),
+            Stop::Watchpoint(watchpoint) => write!(f, "watchpoint on {}", watchpoint),
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use proptest::prelude::*;
+/// A timeline of execution. Branches form a tree: a branch shares the first `fork`
+/// entries of its path with its `parent` and continues with its own. The path of the
+/// current branch is the interpreter's history followed by its redo stack; the
+/// others keep the history `position` they were left at, and only the `entries` of
+/// their path that the branch made current then does not share. Those follow the
+/// first entries of the path of another branch, given by `base`, or of the parent up
+/// to `fork` if there is none; they start at the start of the retained history if
+/// that is later. A branch is `discarded` once it diverges from the current one
+/// before the start of the retained history, as it can no longer be reached.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Branch {
+    parent: Option<usize>,
+    fork: usize,
+    position: usize,
+    entries: Vec<HistoryEntry>,
+    discarded: bool,
+    base: Option<(usize, usize)>,
+}
 
-    fn instruction_strategy() -> impl Strategy<Value = Instruction> {
-        prop_oneof![
-            any::<i32>().prop_map(Instruction::Push),
-            Just(Instruction::Pop),
-            Just(Instruction::Add),
-            Just(Instruction::Sub),
-            Just(Instruction::Mul),
-            Just(Instruction::Div),
-        ]
+impl Branch {
+    /// Returns the branch whose path `entries` follow, and the length of the part of
+    /// that path they follow, or `None` if they start the path.
+    fn base(&self) -> Option<(usize, usize)> {
+        self.base.or(self.parent.map(|parent| (parent, self.fork)))
     }
+}
 
-    fn instruction_sequence() -> impl Strategy<Value = Vec<Instruction>> {
-        prop::collection::vec(instruction_strategy(), 1..100)
+/// The branches of a new interpreter: just the initial branch `0`.
+fn initial_branches() -> Vec<Branch> {
+    vec![Branch {
+        parent: None,
+        fork: 0,
+        position: 0,
+        entries: Vec::new(),
+        discarded: false,
+        base: None,
+    }]
+}
+
+/// Returns whether `entry` left the state with program counter `pc`, `stack` and
+/// `call_stack` behind, so that reversing it restores the state before it.
+fn reversible(
+    entry: &HistoryEntry,
+    pc: usize,
+    stack: &[i32],
+    call_stack: &[usize],
+    memory_size: usize,
+) -> bool {
+    entry.next_pc == pc
+        && stack.ends_with(&entry.pushed_values)
+        && match &entry.effect {
+            Some(Effect::Call(address)) => call_stack.last() == Some(address),
+            _ => effect_applies(entry, memory_size),
+        }
+}
+
+/// Returns whether `entry` starts from the state with program counter `pc`, `stack`
+/// and `call_stack`, so that replaying it restores the state it left behind.
+fn replayable(
+    entry: &HistoryEntry,
+    pc: usize,
+    stack: &[i32],
+    call_stack: &[usize],
+    memory_size: usize,
+) -> bool {
+    // The popped values are recorded top first.
+    entry.pc == pc
+        && stack.len() >= entry.popped_values.len()
+        && stack
+            .iter()
+            .rev()
+            .zip(entry.popped_values.iter())
+            .all(|(a, b)| a == b)
+        && match &entry.effect {
+            Some(Effect::Return(address)) => call_stack.last() == Some(address),
+            _ => effect_applies(entry, memory_size),
+        }
+}
+
+/// Returns whether the variable or memory effect of `entry` can be applied: only
+/// STORE binds variables, and memory writes stay within the memory.
+fn effect_applies(entry: &HistoryEntry, memory_size: usize) -> bool {
+    match &entry.effect {
+        Some(Effect::Store { .. }) => matches!(*entry.instruction, Instruction::Store(_)),
+        Some(Effect::MemoryWrite { address, .. }) => *address < memory_size,
+        _ => true,
     }
+}
 
-    #[test]
-    fn test_property_execution_without_panic() {
-        proptest!(|(instructions in instruction_sequence())| {
-            let mut interpreter = Interpreter::new();
-            interpreter.add_instructions(&instructions);
+/// Reverses the changes of `entry`, which must be `reversible`, to the stack and the
+/// call stack.
+fn reverse_stacks(entry: &HistoryEntry, stack: &mut Vec<i32>, call_stack: &mut Vec<usize>) {
+    stack.truncate(stack.len() - entry.pushed_values.len());
+    stack.extend(entry.popped_values.iter().rev());
+    match &entry.effect {
+        Some(Effect::Call(_)) => {
+            call_stack.pop();
+        }
+        Some(Effect::Return(address)) => call_stack.push(*address),
+        _ => {}
+    }
+}
 
-            let _ = interpreter.run();
-            // Ensures no panic occurs during execution.
-        });
+/// Repeats the changes of `entry`, which must be `replayable`, to the stack and the
+/// call stack.
+fn replay_stacks(entry: &HistoryEntry, stack: &mut Vec<i32>, call_stack: &mut Vec<usize>) {
+    apply(stack, entry);
+    match &entry.effect {
+        Some(Effect::Call(address)) => call_stack.push(*address),
+        Some(Effect::Return(_)) => {
+            call_stack.pop();
+        }
+        _ => {}
     }
+}
 
-    #[test]
-    fn test_property_reverse_execution() {
-        proptest!(|(instructions in instruction_sequence())| {
-            let mut interpreter = Interpreter::new();
-            interpreter.add_instructions(&instructions);
+/// A named point of execution, as recorded by `Interpreter::checkpoint`:
+/// - `branch`: The branch of execution it was recorded on.
+/// - `position`: The number of instructions that had been executed along it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Checkpoint {
+    pub branch: usize,
+    pub position: usize,
+}
 
-            let run_result = interpreter.run();
+/// A description of a branch of execution, as returned by `Interpreter::branches`.
+/// Positions count instructions from the start of execution:
+/// - `id`: The number identifying the branch.
+/// - `parent`: The branch it was forked from, `None` for the initial branch.
+/// - `fork`: The number of executed instructions it shares with its parent.
+/// - `position`: The number of its instructions that are currently executed, or were
+///   when it was switched away from.
+/// - `len`: The number of instructions along the whole branch, including those that
+///   have been reversed.
+/// - `stack`: The stack at the end of the branch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BranchSummary {
+    pub id: usize,
+    pub parent: Option<usize>,
+    pub fork: usize,
+    pub position: usize,
+    pub len: usize,
+    pub stack: Vec<i32>,
+}
 
-            // Collect executed instructions
-            let executed_instructions: Vec<_> = interpreter.history.iter().map(|h| h.instruction.clone()).collect();
-            let executed_count = executed_instructions.len();
+/// The `Interpreter` struct manages the state of the stack-based instruction execution.
+/// It holds:
+/// - `instructions`: The program, i.e. the list of instructions added so far.
+/// - `shared`: The executed instructions, shared with the history entries that record
+///   them instead of being copied into each.
+/// - `labels`: The index of the first `Label` of each name, built when a jump first
+///   needs it and rebuilt after the program changes.
+/// - `pc`: The program counter, the index of the next instruction to execute.
+/// - `stack`: A vector representing the current state of the stack.
+/// - `call_stack`: The return addresses of the subroutine calls in progress.
+/// - `variables`: The named variables bound by `Store` instructions.
+/// - `memory`: A fixed-size linear memory of `i32` cells, initially all zero.
+/// - `history`: A list of past executions to allow for reversing instructions, kept
+///   within a `HistoryLimit`.
+/// - `redo_stack`: The instructions reversed by `back`, which `redo` replays.
+/// - `branches`, `branch`: The tree of timelines and the index of the current one.
+/// - `checkpoints`: Named points of execution, recorded by `checkpoint`.
+/// - `breakpoints`, `watchpoints`: Where `continue_forward` and `continue_backward` stop.
+/// - `overflow_mode`: How arithmetic overflow is handled, see `OverflowMode`.
+/// - `limits`: The `ExecutionLimits` enforced by `forward`.
+/// - `observers`: The registered `ExecutionObserver`s, which are not serialized.
+///
+/// The interpreter supports forward execution of instructions and the ability to undo
+/// previous operations via a backtracking mechanism. Undoing an instruction also
+/// restores the program counter, so jumps and branches are reversed as well.
+#[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "SavedInterpreter")
+)]
+pub struct Interpreter {
+    instructions: Vec<Instruction>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    shared: Vec<Option<Arc<Instruction>>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    labels: Option<BTreeMap<String, usize>>,
+    pc: usize,
+    stack: Vec<i32>,
+    call_stack: Vec<usize>,
+    variables: BTreeMap<String, i32>,
+    memory: Vec<i32>,
+    history: History,
+    redo_stack: Vec<HistoryEntry>,
+    branches: Vec<Branch>,
+    branch: usize,
+    checkpoints: BTreeMap<String, Checkpoint>,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    overflow_mode: OverflowMode,
+    limits: ExecutionLimits,
+    steps: usize,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    observers: Vec<Box<dyn ExecutionObserver + Send>>,
+}
 
-            if run_result.is_ok() {
-                prop_assert_eq!(&executed_instructions, &instructions, "When run is successful executed instructions should be the same as input instructions");
+/// The serialized form of an `Interpreter`. Saved sessions may be corrupt or edited
+/// by hand, so they are checked for consistency before they become an interpreter
+/// rather than panicking once it is used.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SavedInterpreter {
+    instructions: Vec<Instruction>,
+    pc: usize,
+    stack: Vec<i32>,
+    call_stack: Vec<usize>,
+    variables: BTreeMap<String, i32>,
+    memory: Vec<i32>,
+    history: History,
+    redo_stack: Vec<HistoryEntry>,
+    branches: Vec<Branch>,
+    branch: usize,
+    checkpoints: BTreeMap<String, Checkpoint>,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    overflow_mode: OverflowMode,
+    limits: ExecutionLimits,
+    steps: usize,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<SavedInterpreter> for Interpreter {
+    type Error = String;
+
+    fn try_from(saved: SavedInterpreter) -> Result<Self, Self::Error> {
+        if saved.pc > saved.instructions.len() {
+            return Err(format!(
+                "program counter {} is past the end of the program",
+                saved.pc
+            ));
+        }
+        if saved.branch >= saved.branches.len() {
+            return Err(format!("current branch {} does not exist", saved.branch));
+        }
+        for (id, branch) in saved.branches.iter().enumerate() {
+            if branch.parent.is_some_and(|parent| parent >= id) {
+                return Err(format!("branch {} has an invalid parent", id));
+            }
+            if branch
+                .base
+                .is_some_and(|(base, _)| base >= saved.branches.len())
+            {
+                return Err(format!(
+                    "branch {} follows a branch that does not exist",
+                    id
+                ));
+            }
+        }
+        for id in 0..saved.branches.len() {
+            // The branches a path follows lead to the current branch or to the start.
+            let mut next = Some(id);
+            for _ in 0..saved.branches.len() {
+                next = next
+                    .filter(|&next| next != saved.branch)
+                    .and_then(|next| saved.branches[next].base())
+                    .map(|(base, _)| base);
+            }
+            if next.is_some() {
+                return Err(format!("the path of branch {} follows itself", id));
+            }
+        }
+        let entries = saved
+            .history
+            .entries()
+            .into_iter()
+            .chain(saved.redo_stack.iter().cloned())
+            .chain(
+                saved
+                    .branches
+                    .iter()
+                    .flat_map(|b| b.entries.iter().cloned()),
+            );
+        for entry in entries {
+            match &entry.effect {
+                Some(Effect::Store { .. })
+                    if !matches!(*entry.instruction, Instruction::Store(_)) =>
+                {
+                    return Err(format!(
+                        "history records a variable store by {}",
+                        entry.instruction
+                    ));
+                }
+                Some(Effect::MemoryWrite { address, .. }) if *address >= saved.memory.len() => {
+                    return Err(format!(
+                        "history records a write to memory address {} outside memory",
+                        address
+                    ));
+                }
+                _ => {}
+            }
+        }
+        // Reversing the history from the current state leads back to its snapshot, and
+        // the redo stack replays from the current state.
+        let memory_size = saved.memory.len();
+        let (mut pc, mut stack, mut call_stack) =
+            (saved.pc, saved.stack.clone(), saved.call_stack.clone());
+        for entry in saved.history.entries().iter().rev() {
+            if !reversible(entry, pc, &stack, &call_stack, memory_size) {
+                return Err(format!(
+                    "history does not lead to the state at instruction {}",
+                    pc
+                ));
             }
+            reverse_stacks(entry, &mut stack, &mut call_stack);
+            pc = entry.pc;
+        }
+        if stack != saved.history.snapshot() {
+            return Err("history does not start from its snapshot".to_string());
+        }
+        if saved.history.start() == 0 && !call_stack.is_empty() {
+            return Err("history does not start from an empty call stack".to_string());
+        }
+        let (mut pc, mut stack, mut call_stack) =
+            (saved.pc, saved.stack.clone(), saved.call_stack.clone());
+        for entry in saved.redo_stack.iter().rev() {
+            if !replayable(entry, pc, &stack, &call_stack, memory_size) {
+                return Err(format!(
+                    "redo stack does not follow from the state at instruction {}",
+                    pc
+                ));
+            }
+            replay_stacks(entry, &mut stack, &mut call_stack);
+            pc = entry.next_pc;
+        }
 
-            // Attempt to reverse all executed instructions
-            while interpreter.back().is_ok() {}
+        Ok(Self {
+            instructions: saved.instructions,
+            shared: Vec::new(),
+            labels: None,
+            pc: saved.pc,
+            stack: saved.stack,
+            call_stack: saved.call_stack,
+            variables: saved.variables,
+            memory: saved.memory,
+            history: saved.history,
+            redo_stack: saved.redo_stack,
+            branches: saved.branches,
+            branch: saved.branch,
+            checkpoints: saved.checkpoints,
+            breakpoints: saved.breakpoints,
+            watchpoints: saved.watchpoints,
+            overflow_mode: saved.overflow_mode,
+            limits: saved.limits,
+            steps: saved.steps,
+            observers: Vec::new(),
+        })
+    }
+}
 
-            prop_assert_eq!(interpreter.stack, vec![], "After reversing the stack should be empty");
+/// The number of memory cells of an interpreter constructed with `Interpreter::new`.
+pub const DEFAULT_MEMORY_SIZE: usize = 1024;
 
-            // Compute unexecuted instructions, if any
-            let unexecuted_instructions = if run_result.is_err() {
-                // If an error occurred, skip the failed instruction
-                if executed_count < instructions.len() {
-                    &instructions[executed_count + 1..]
-                } else {
-                    &[]
-                }
-            } else {
-                &[]
-            };
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-            // Build expected instructions after reversal
-            let mut expected_instructions = executed_instructions.clone();
-            expected_instructions.extend_from_slice(unexecuted_instructions);
+impl Interpreter {
+    /// Constructs a new interpreter with an empty list of instructions,
+    /// an empty stack and `DEFAULT_MEMORY_SIZE` memory cells.
+    pub fn new() -> Self {
+        Self::with_memory_size(DEFAULT_MEMORY_SIZE)
+    }
 
-            let restored_instructions: Vec<Instruction> = interpreter.instructions.iter().cloned().collect();
-            prop_assert_eq!(restored_instructions, expected_instructions);
-        });
+    /// Constructs a new interpreter with an empty list of instructions,
+    /// an empty stack and `size` memory cells.
+    pub fn with_memory_size(size: usize) -> Self {
+        Self {
+            instructions: Vec::new(),
+            shared: Vec::new(),
+            labels: None,
+            pc: 0,
+            stack: Vec::new(),
+            call_stack: Vec::new(),
+            variables: BTreeMap::new(),
+            memory: vec![0; size],
+            history: History::default(),
+            redo_stack: Vec::new(),
+            branches: initial_branches(),
+            branch: 0,
+            checkpoints: BTreeMap::new(),
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            overflow_mode: OverflowMode::Checked,
+            limits: ExecutionLimits::default(),
+            steps: 0,
+            observers: Vec::new(),
+        }
     }
 
-    #[test]
-    fn test_stack_underflow_on_pop() {
-        let mut interpreter = Interpreter::new();
-        interpreter.add_instructions(&[Instruction::Pop]);
-        let result = interpreter.run();
-        assert_eq!(result, Err(RuntimeError::StackUnderflow));
-        assert!(
-            interpreter.instructions.is_empty(),
-            "Instruction should have been removed"
-        );
+    /// Adds instructions to the end of the program. The instructions are not
+    /// interpreted, just stored.
+    pub fn add_instructions(&mut self, instructions: &[Instruction]) {
+        self.instructions.extend_from_slice(instructions);
+        self.labels = None;
     }
 
-    #[test]
-    fn test_divide_by_zero() {
+    /// Returns a mutable reference to the next instruction that will be executed
+    /// on the next `.forward()` call.
+    pub fn current_instruction(&mut self) -> Option<&mut Instruction> {
+        self.unshare(self.pc);
+        self.instructions.get_mut(self.pc)
+    }
+
+    /// Returns a reference to the program.
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// Returns the program counter, the index of the instruction that will be
+    /// executed on the next `.forward()` call.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Returns a reference to the stack.
+    pub fn stack(&self) -> &Vec<i32> {
+        &self.stack
+    }
+
+    /// Returns the named variables bound by `Store` instructions, sorted by name.
+    pub fn variables(&self) -> &BTreeMap<String, i32> {
+        &self.variables
+    }
+
+    /// Returns the linear memory.
+    pub fn memory(&self) -> &[i32] {
+        &self.memory
+    }
+
+    /// Returns how arithmetic overflow is handled.
+    pub fn overflow_mode(&self) -> OverflowMode {
+        self.overflow_mode
+    }
+
+    /// Sets how arithmetic overflow is handled by instructions executed from now on.
+    /// Instructions already in the history are undone exactly regardless of the mode
+    /// they were executed in.
+    pub fn set_overflow_mode(&mut self, mode: OverflowMode) {
+        self.overflow_mode = mode;
+    }
+
+    /// Returns the limits enforced by `.forward()`.
+    pub fn limits(&self) -> ExecutionLimits {
+        self.limits
+    }
+
+    /// Sets the limits enforced by `.forward()` and `.redo()` from now on, see
+    /// `ExecutionLimits`. The current state may already exceed them, in which case the
+    /// next instruction that would go further fails.
+    pub fn set_limits(&mut self, limits: ExecutionLimits) {
+        self.limits = limits;
+    }
+
+    /// Returns the number of instructions executed with `.forward()` or replayed with
+    /// `.redo()` so far, which `ExecutionLimits::max_steps` limits. Unlike
+    /// `.history_len()`, reversing instructions does not decrease it.
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Registers an observer to be notified of every instruction executed, replayed
+    /// or reversed from now on, after the observers registered before it.
+    pub fn add_observer(&mut self, observer: impl ExecutionObserver + Send + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// Unregisters all observers.
+    pub fn clear_observers(&mut self) {
+        self.observers.clear();
+    }
+
+    /// Passes the interpreter to `event` for each of the observers.
+    fn notify(&mut self, mut event: impl FnMut(&mut dyn ExecutionObserver, &Interpreter)) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let observers = mem::take(&mut self.observers);
+        let mut notifying = Notifying {
+            interpreter: self,
+            observers,
+        };
+        let Notifying {
+            interpreter,
+            observers,
+        } = &mut notifying;
+        for observer in observers {
+            event(observer.as_mut(), interpreter);
+        }
+    }
+
+    /// Returns the return addresses of the subroutine calls in progress, the
+    /// innermost call last. Each address is the index of the instruction that
+    /// follows the corresponding `Call`.
+    pub fn call_stack(&self) -> &[usize] {
+        &self.call_stack
+    }
+
+    /// Returns the number of executed instructions in the history, i.e. the position
+    /// of the current state in it. `.back()` can be called until it goes down to
+    /// `.history_start()`.
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Returns the position of the oldest executed instruction kept in the history:
+    /// zero, unless older instructions were discarded to stay within the history limit.
+    pub fn history_start(&self) -> usize {
+        self.history.start()
+    }
+
+    /// Returns the approximate memory used by the history, in bytes, not counting the
+    /// redo stack and the entries kept for other branches.
+    pub fn history_bytes(&self) -> usize {
+        self.history.bytes()
+    }
+
+    /// Returns the limit on the memory used by the history.
+    pub fn history_limit(&self) -> HistoryLimit {
+        self.history.limit()
+    }
+
+    /// Sets the limit on the memory used by the history, see `HistoryLimit`. When the
+    /// limit is exceeded the oldest executed instructions are discarded and can no
+    /// longer be reversed, along with checkpoints and branches before them. The redo
+    /// stack and other branches are not limited, so a session that forks often keeps
+    /// growing with the entries of its branches.
+    pub fn set_history_limit(&mut self, limit: HistoryLimit) {
+        let start = self.history.start();
+        self.history.set_limit(limit);
+        self.discard_before(start);
+    }
+
+    /// Returns the recorded checkpoints, sorted by name.
+    pub fn checkpoints(&self) -> &BTreeMap<String, Checkpoint> {
+        &self.checkpoints
+    }
+
+    /// Records the current branch and position in the history as the checkpoint
+    /// `name`, replacing any previous checkpoint with the same name.
+    pub fn checkpoint(&mut self, name: &str) {
+        let checkpoint = Checkpoint {
+            branch: self.branch,
+            position: self.history.len(),
+        };
+        self.checkpoints.insert(name.to_string(), checkpoint);
+    }
+
+    /// Returns the interpreter to the state it was in when the checkpoint `name` was
+    /// recorded. If it was recorded on another branch, that branch is switched to
+    /// first, as by `.switch_branch()`. Then, if the interpreter has executed past
+    /// the checkpoint, the extra instructions are reversed; if it has been reversed
+    /// past the checkpoint, the instructions up to it are replayed as by `.redo()`.
+    ///
+    /// Returns `ErrorKind::UndefinedCheckpoint` if there is no such checkpoint, and
+    /// `ErrorKind::HistoryExhausted` if its branch can no longer be reached.
+    pub fn restore(&mut self, name: &str) -> Result<(), RuntimeError> {
+        let checkpoint = *self
+            .checkpoints
+            .get(name)
+            .ok_or(ErrorKind::UndefinedCheckpoint)?;
+        self.switch_branch(checkpoint.branch)?;
+        let current = self.history.len();
+        if checkpoint.position <= current {
+            return self.back_n(current - checkpoint.position);
+        }
+        while self.history.len() < checkpoint.position {
+            self.redo()?;
+        }
+        Ok(())
+    }
+
+    /// Interprets the instruction at the program counter and advances the program
+    /// counter, either to the following instruction or to the target of a jump.
+    /// If there are no instructions left, returns an `ErrorKind::NoInstructions` error.
+    /// On error the stack and program counter are left unchanged, so the failing
+    /// instruction is the current instruction.
+    /// Other errors are described by `ErrorKind`; they carry an `ErrorContext` describing
+    /// the failing instruction.
+    pub fn forward(&mut self) -> Result<Instruction, RuntimeError> {
+        let instruction = self.execute()?;
+        Ok(Instruction::clone(&instruction))
+    }
+
+    /// Executes the instruction at the program counter like `.forward()`, without
+    /// returning a copy of it.
+    fn advance(&mut self) -> Result<(), RuntimeError> {
+        self.execute()?;
+        Ok(())
+    }
+
+    /// Executes the instruction at the program counter, notifying the observers.
+    fn execute(&mut self) -> Result<Arc<Instruction>, RuntimeError> {
+        if !self.observers.is_empty() {
+            if let Some(instruction) = self.shared_instruction(self.pc) {
+                self.notify(|observer, interpreter| {
+                    observer.before_execute(interpreter, &instruction)
+                });
+            }
+        }
+        match self.step() {
+            Ok(instruction) => {
+                self.notify(|observer, interpreter| {
+                    observer.after_execute(interpreter, &instruction)
+                });
+                Ok(instruction)
+            }
+            Err(kind) => Err(self.fail(self.error_at_pc(kind))),
+        }
+    }
+
+    /// Notifies the observers of `error` and returns it.
+    fn fail(&mut self, error: RuntimeError) -> RuntimeError {
+        self.notify(|observer, interpreter| observer.on_error(interpreter, &error));
+        error
+    }
+
+    /// Returns the instruction at `pc`, shared with the history entries recording it.
+    fn shared_instruction(&mut self, pc: usize) -> Option<Arc<Instruction>> {
+        let instruction = self.instructions.get(pc)?;
+        if self.shared.len() < self.instructions.len() {
+            self.shared.resize(self.instructions.len(), None);
+        }
+        let shared = self.shared[pc].get_or_insert_with(|| Arc::new(instruction.clone()));
+        Some(Arc::clone(shared))
+    }
+
+    /// Stops sharing the instruction at `pc`, before it is changed, and forgets the
+    /// labels in case it is or becomes one.
+    fn unshare(&mut self, pc: usize) {
+        if let Some(shared) = self.shared.get_mut(pc) {
+            *shared = None;
+        }
+        self.labels = None;
+    }
+
+    /// Executes the instruction at the program counter; the implementation of `.forward()`.
+    fn step(&mut self) -> Result<Arc<Instruction>, ErrorKind> {
+        let pc = self.pc;
+        let shared = self
+            .shared_instruction(pc)
+            .ok_or(ErrorKind::NoInstructions)?;
+        let instruction: &Instruction = &shared;
+        self.check_limits(instruction)?;
+        let mut next_pc = pc + 1;
+        let mut effect = None;
+
+        let (popped_values, pushed_values) = match instruction {
+            Instruction::Push(value) => {
+                self.stack.push(*value);
+                (Values::new(), Values::from([*value]))
+            }
+            Instruction::Pop => {
+                let value = self.stack.pop().ok_or(ErrorKind::StackUnderflow)?;
+                (Values::from([value]), Values::new())
+            }
+            Instruction::Dup
+            | Instruction::Swap
+            | Instruction::Over
+            | Instruction::Rot
+            | Instruction::Nip
+            | Instruction::Tuck
+            | Instruction::DropN(_)
+            | Instruction::Roll(_) => {
+                let depth = match *instruction {
+                    Instruction::Dup => 1,
+                    Instruction::Swap
+                    | Instruction::Over
+                    | Instruction::Nip
+                    | Instruction::Tuck => 2,
+                    Instruction::Rot => 3,
+                    Instruction::DropN(n) => n,
+                    Instruction::Roll(n) => n.checked_add(1).ok_or(ErrorKind::StackUnderflow)?,
+                    _ => unreachable!(),
+                };
+                let start = self
+                    .stack
+                    .len()
+                    .checked_sub(depth)
+                    .ok_or(ErrorKind::StackUnderflow)?;
+                let popped_values = self.stack[start..].iter().rev().copied().collect();
+
+                // Rearrange the affected values in place, in stack order (bottom to top).
+                match *instruction {
+                    Instruction::Dup | Instruction::Over => self.stack.push(self.stack[start]),
+                    Instruction::Swap => self.stack.swap(start, start + 1),
+                    Instruction::Rot | Instruction::Roll(_) => self.stack[start..].rotate_left(1),
+                    Instruction::Nip => {
+                        self.stack.remove(start);
+                    }
+                    Instruction::Tuck => self.stack.insert(start, self.stack[start + 1]),
+                    Instruction::DropN(_) => self.stack.truncate(start),
+                    _ => unreachable!(),
+                }
+
+                let pushed_values = self.stack[start..].iter().copied().collect();
+                (popped_values, pushed_values)
+            }
+            Instruction::Pick(n) => {
+                if *n >= self.stack.len() {
+                    return Err(ErrorKind::StackUnderflow);
+                }
+                let value = self.stack[self.stack.len() - 1 - n];
+                self.stack.push(value);
+                (Values::new(), Values::from([value]))
+            }
+            Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Div
+            | Instruction::Mod
+            | Instruction::Min
+            | Instruction::Max
+            | Instruction::Pow => {
+                if self.stack.len() < 2 {
+                    return Err(ErrorKind::StackUnderflow);
+                }
+                // The following pops should never fail since we already checked for underflow above.
+                let b = self.stack.pop().ok_or(ErrorKind::StackUnderflow)?;
+                let a = self.stack.pop().ok_or(ErrorKind::StackUnderflow)?;
+
+                let mode = self.overflow_mode;
+                let result = match instruction {
+                    Instruction::Add => {
+                        mode.resolve(a.checked_add(b), a.wrapping_add(b), a.saturating_add(b))
+                    }
+                    Instruction::Sub => {
+                        mode.resolve(a.checked_sub(b), a.wrapping_sub(b), a.saturating_sub(b))
+                    }
+                    Instruction::Mul => {
+                        mode.resolve(a.checked_mul(b), a.wrapping_mul(b), a.saturating_mul(b))
+                    }
+                    Instruction::Div | Instruction::Mod => {
+                        if b == 0 {
+                            // Restore stack before returning error
+                            self.stack.push(a);
+                            self.stack.push(b);
+                            return Err(ErrorKind::DivideByZero);
+                        }
+                        if let Instruction::Div = instruction {
+                            mode.resolve(a.checked_div(b), a.wrapping_div(b), a.saturating_div(b))
+                        } else {
+                            // The only overflowing remainder is `i32::MIN % -1`, which is 0.
+                            mode.resolve(a.checked_rem(b), a.wrapping_rem(b), a.wrapping_rem(b))
+                        }
+                    }
+                    Instruction::Min => Some(a.min(b)),
+                    Instruction::Max => Some(a.max(b)),
+                    Instruction::Pow => {
+                        let Ok(exponent) = u32::try_from(b) else {
+                            // Restore stack before returning error
+                            self.stack.push(a);
+                            self.stack.push(b);
+                            return Err(ErrorKind::NegativeExponent);
+                        };
+                        mode.resolve(
+                            a.checked_pow(exponent),
+                            a.wrapping_pow(exponent),
+                            a.saturating_pow(exponent),
+                        )
+                    }
+                    _ => unreachable!(),
+                };
+                if let Some(res) = result {
+                    self.stack.push(res);
+                    (Values::from([b, a]), Values::from([res]))
+                } else {
+                    // Restore stack before returning error
+                    self.stack.push(a);
+                    self.stack.push(b);
+                    return Err(ErrorKind::ArithmeticOverflow);
+                }
+            }
+            Instruction::Neg | Instruction::Abs => {
+                let value = self.stack.pop().ok_or(ErrorKind::StackUnderflow)?;
+                let mode = self.overflow_mode;
+                let result = match instruction {
+                    Instruction::Neg => mode.resolve(
+                        value.checked_neg(),
+                        value.wrapping_neg(),
+                        value.saturating_neg(),
+                    ),
+                    Instruction::Abs => mode.resolve(
+                        value.checked_abs(),
+                        value.wrapping_abs(),
+                        value.saturating_abs(),
+                    ),
+                    _ => unreachable!(),
+                };
+                if let Some(res) = result {
+                    self.stack.push(res);
+                    (Values::from([value]), Values::from([res]))
+                } else {
+                    // Restore stack before returning error
+                    self.stack.push(value);
+                    return Err(ErrorKind::ArithmeticOverflow);
+                }
+            }
+            Instruction::DivMod => {
+                if self.stack.len() < 2 {
+                    return Err(ErrorKind::StackUnderflow);
+                }
+                let (a, b) = (
+                    self.stack[self.stack.len() - 2],
+                    self.stack[self.stack.len() - 1],
+                );
+                if b == 0 {
+                    return Err(ErrorKind::DivideByZero);
+                }
+                let mode = self.overflow_mode;
+                let quotient =
+                    mode.resolve(a.checked_div(b), a.wrapping_div(b), a.saturating_div(b));
+                let remainder =
+                    mode.resolve(a.checked_rem(b), a.wrapping_rem(b), a.wrapping_rem(b));
+                let (Some(quotient), Some(remainder)) = (quotient, remainder) else {
+                    return Err(ErrorKind::ArithmeticOverflow);
+                };
+                let popped_values = self.pop_values(2)?;
+                self.stack.push(quotient);
+                self.stack.push(remainder);
+                (popped_values, Values::from([quotient, remainder]))
+            }
+            Instruction::Eq
+            | Instruction::Ne
+            | Instruction::Lt
+            | Instruction::Gt
+            | Instruction::Le
+            | Instruction::Ge
+            | Instruction::And
+            | Instruction::Or => {
+                let popped_values = self.pop_values(2)?;
+                let (b, a) = (popped_values[0], popped_values[1]);
+                let result = match instruction {
+                    Instruction::Eq => a == b,
+                    Instruction::Ne => a != b,
+                    Instruction::Lt => a < b,
+                    Instruction::Gt => a > b,
+                    Instruction::Le => a <= b,
+                    Instruction::Ge => a >= b,
+                    Instruction::And => a != 0 && b != 0,
+                    Instruction::Or => a != 0 || b != 0,
+                    _ => unreachable!(),
+                };
+                let result = i32::from(result);
+                self.stack.push(result);
+                (popped_values, Values::from([result]))
+            }
+            Instruction::Not => {
+                let value = self.stack.pop().ok_or(ErrorKind::StackUnderflow)?;
+                let result = i32::from(value == 0);
+                self.stack.push(result);
+                (Values::from([value]), Values::from([result]))
+            }
+            Instruction::BitAnd | Instruction::BitOr | Instruction::BitXor => {
+                let popped_values = self.pop_values(2)?;
+                let (b, a) = (popped_values[0], popped_values[1]);
+                let result = match instruction {
+                    Instruction::BitAnd => a & b,
+                    Instruction::BitOr => a | b,
+                    Instruction::BitXor => a ^ b,
+                    _ => unreachable!(),
+                };
+                self.stack.push(result);
+                (popped_values, Values::from([result]))
+            }
+            Instruction::BitNot => {
+                let value = self.stack.pop().ok_or(ErrorKind::StackUnderflow)?;
+                self.stack.push(!value);
+                (Values::from([value]), Values::from([!value]))
+            }
+            Instruction::Shl
+            | Instruction::Shr
+            | Instruction::Ushr
+            | Instruction::Rotl
+            | Instruction::Rotr => {
+                if self.stack.len() < 2 {
+                    return Err(ErrorKind::StackUnderflow);
+                }
+                // Validate the amount before popping so the stack is intact on error.
+                let amount = u32::try_from(self.stack[self.stack.len() - 1])
+                    .ok()
+                    .filter(|amount| *amount < i32::BITS)
+                    .ok_or(ErrorKind::ShiftOutOfRange)?;
+                let popped_values = self.pop_values(2)?;
+                let a = popped_values[1];
+                let result = match instruction {
+                    Instruction::Shl => a << amount,
+                    Instruction::Shr => a >> amount,
+                    Instruction::Ushr => ((a as u32) >> amount) as i32,
+                    Instruction::Rotl => a.rotate_left(amount),
+                    Instruction::Rotr => a.rotate_right(amount),
+                    _ => unreachable!(),
+                };
+                self.stack.push(result);
+                (popped_values, Values::from([result]))
+            }
+            Instruction::Load(name) => {
+                let value = *self
+                    .variables
+                    .get(name)
+                    .ok_or(ErrorKind::UndefinedVariable)?;
+                self.stack.push(value);
+                (Values::new(), Values::from([value]))
+            }
+            Instruction::Store(name) => {
+                let value = self.stack.pop().ok_or(ErrorKind::StackUnderflow)?;
+                let previous = self.set_variable(name, value);
+                effect = Some(Effect::Store { previous, value });
+                (Values::from([value]), Values::new())
+            }
+            Instruction::FetchCell => {
+                let address = *self.stack.last().ok_or(ErrorKind::StackUnderflow)?;
+                let address = self.memory_address(address)?;
+                let popped_values = self.pop_values(1)?;
+                let value = self.memory[address];
+                self.stack.push(value);
+                (popped_values, Values::from([value]))
+            }
+            Instruction::StoreCell => {
+                if self.stack.len() < 2 {
+                    return Err(ErrorKind::StackUnderflow);
+                }
+                // Validate the address before popping so the stack is intact on error.
+                let address = self.memory_address(self.stack[self.stack.len() - 1])?;
+                let popped_values = self.pop_values(2)?;
+                let value = popped_values[1];
+                let previous = mem::replace(&mut self.memory[address], value);
+                effect = Some(Effect::MemoryWrite {
+                    address,
+                    previous,
+                    value,
+                });
+                (popped_values, Values::new())
+            }
+            Instruction::Label(_) => (Values::new(), Values::new()),
+            Instruction::Jmp(label) => {
+                next_pc = self.label_position(label)?;
+                (Values::new(), Values::new())
+            }
+            Instruction::Jz(label) | Instruction::Jnz(label) => {
+                let target = self.label_position(label)?;
+                let condition = self.stack.pop().ok_or(ErrorKind::StackUnderflow)?;
+                let taken = match instruction {
+                    Instruction::Jz(_) => condition == 0,
+                    _ => condition != 0,
+                };
+                if taken {
+                    next_pc = target;
+                }
+                (Values::from([condition]), Values::new())
+            }
+            Instruction::Jeq(label)
+            | Instruction::Jne(label)
+            | Instruction::Jlt(label)
+            | Instruction::Jgt(label)
+            | Instruction::Jle(label)
+            | Instruction::Jge(label) => {
+                let target = self.label_position(label)?;
+                let popped_values = self.pop_values(2)?;
+                let (b, a) = (popped_values[0], popped_values[1]);
+                let taken = match instruction {
+                    Instruction::Jeq(_) => a == b,
+                    Instruction::Jne(_) => a != b,
+                    Instruction::Jlt(_) => a < b,
+                    Instruction::Jgt(_) => a > b,
+                    Instruction::Jle(_) => a <= b,
+                    Instruction::Jge(_) => a >= b,
+                    _ => unreachable!(),
+                };
+                if taken {
+                    next_pc = target;
+                }
+                (popped_values, Values::new())
+            }
+            Instruction::Call(label) => {
+                next_pc = self.label_position(label)?;
+                self.call_stack.push(pc + 1);
+                effect = Some(Effect::Call(pc + 1));
+                (Values::new(), Values::new())
+            }
+            Instruction::Ret => {
+                let address = self.call_stack.pop().ok_or(ErrorKind::CallStackUnderflow)?;
+                next_pc = address;
+                effect = Some(Effect::Return(address));
+                (Values::new(), Values::new())
+            }
+        };
+
+        let entry = HistoryEntry {
+            instruction: Arc::clone(&shared),
+            pc,
+            next_pc,
+            popped_values,
+            pushed_values,
+            effect,
+        };
+        // Executing the next undone instruction again keeps the rest of the undone
+        // future; executing anything else starts a new branch.
+        if self.redo_stack.last() == Some(&entry) {
+            self.redo_stack.pop();
+        } else if !self.redo_stack.is_empty() {
+            self.fork();
+        }
+        self.record(entry);
+        self.pc = next_pc;
+        self.steps += 1;
+        Ok(shared)
+    }
+
+    /// Returns the error for the first of the `ExecutionLimits` that executing
+    /// `instruction` would exceed, if any.
+    fn check_limits(&self, instruction: &Instruction) -> Result<(), ErrorKind> {
+        let limits = &self.limits;
+        if limits.max_steps.is_some_and(|max| self.steps >= max) {
+            return Err(ErrorKind::StepLimitExceeded);
+        }
+        let history_full = match limits.max_history {
+            HistoryLimit::Unbounded => false,
+            HistoryLimit::Entries(max) => self.history.len() - self.history.start() >= max,
+            HistoryLimit::Bytes(max) => self.history.bytes() >= max,
+        };
+        if history_full {
+            return Err(ErrorKind::HistoryLimitExceeded);
+        }
+        if instruction.grows_stack() && limits.max_stack.is_some_and(|max| self.stack.len() >= max)
+        {
+            return Err(ErrorKind::StackLimitExceeded);
+        }
+        Ok(())
+    }
+
+    /// Binds the variable `name` to `value` and returns the value it replaced, or
+    /// `None` if the variable was unset.
+    fn set_variable(&mut self, name: &str, value: i32) -> Option<i32> {
+        match self.variables.get_mut(name) {
+            Some(slot) => Some(mem::replace(slot, value)),
+            None => {
+                self.variables.insert(name.to_string(), value);
+                None
+            }
+        }
+    }
+
+    /// Builds the error for a failure of the instruction at the program counter,
+    /// capturing the values the instruction would have consumed.
+    fn error_at_pc(&self, kind: ErrorKind) -> RuntimeError {
+        let Some(instruction) = self.instructions.get(self.pc) else {
+            return kind.into();
+        };
+        let operand_count = instruction.operand_count().min(self.stack.len());
+        RuntimeError {
+            kind,
+            context: Some(Box::new(ErrorContext {
+                instruction: instruction.clone(),
+                pc: self.pc,
+                line: None,
+                stack_depth: self.stack.len(),
+                operands: self.stack[self.stack.len() - operand_count..].to_vec(),
+            })),
+        }
+    }
+
+    /// Pops `count` values off the stack and returns them in the order they were
+    /// popped (top of the stack first). If the stack holds fewer than `count`
+    /// values, returns `ErrorKind::StackUnderflow` and leaves the stack unchanged.
+    fn pop_values(&mut self, count: usize) -> Result<Values, ErrorKind> {
+        if self.stack.len() < count {
+            return Err(ErrorKind::StackUnderflow);
+        }
+        let start = self.stack.len() - count;
+        Ok(self.stack.drain(start..).rev().collect())
+    }
+
+    /// Converts a value into an index into the memory, or returns
+    /// `ErrorKind::OutOfBounds` if it does not address a memory cell.
+    fn memory_address(&self, address: i32) -> Result<usize, ErrorKind> {
+        usize::try_from(address)
+            .ok()
+            .filter(|address| *address < self.memory.len())
+            .ok_or(ErrorKind::OutOfBounds)
+    }
+
+    /// Returns the index of the first `Label` instruction with the given name,
+    /// or `ErrorKind::UndefinedLabel` if the program does not define it.
+    fn label_position(&mut self, label: &str) -> Result<usize, ErrorKind> {
+        let instructions = &self.instructions;
+        let labels = self.labels.get_or_insert_with(|| {
+            let mut labels = BTreeMap::new();
+            for (index, instruction) in instructions.iter().enumerate() {
+                if let Instruction::Label(name) = instruction {
+                    labels.entry(name.clone()).or_insert(index);
+                }
+            }
+            labels
+        });
+        labels.get(label).copied().ok_or(ErrorKind::UndefinedLabel)
+    }
+
+    /// Returns the breakpoints, in the order they were added.
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Adds a breakpoint, unless an identical one is already set.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    /// Removes a breakpoint. Returns `false` if it was not set.
+    pub fn remove_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|b| b != breakpoint);
+        self.breakpoints.len() != len
+    }
+
+    /// Returns the watchpoints, in the order they were added.
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Adds a watchpoint, unless an identical one is already set.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    /// Removes a watchpoint. Returns `false` if it was not set.
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| w != watchpoint);
+        self.watchpoints.len() != len
+    }
+
+    /// Calls `.forward()` until a breakpoint or watchpoint triggers, and returns the
+    /// one that did. The first instruction is always executed, so continuing from a
+    /// breakpoint moves past it. Returns `None` if the program counter moves past the
+    /// last instruction without stopping, and the error if an instruction fails.
+    pub fn continue_forward(&mut self) -> Result<Option<Stop>, RuntimeError> {
+        while self.pc < self.instructions.len() {
+            let before = self.watched_values();
+            self.advance()?;
+            if let Some(stop) = self.stop(&before) {
+                return Ok(Some(stop));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Calls `.back()` until a breakpoint or watchpoint triggers, and returns the one
+    /// that did. Breakpoints and watchpoints trigger as they would have when executing
+    /// forward: a breakpoint when the reversed instruction is at its location, and a
+    /// watchpoint when reversing an instruction changes what it watches. Returns `None`
+    /// if the whole history is reversed without stopping.
+    pub fn continue_backward(&mut self) -> Result<Option<Stop>, RuntimeError> {
+        while !self.history.is_empty() {
+            let before = self.watched_values();
+            self.back()?;
+            if let Some(stop) = self.stop(&before) {
+                return Ok(Some(stop));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the values observed by each of the watchpoints.
+    fn watched_values(&self) -> Vec<Option<i64>> {
+        self.watchpoints
+            .iter()
+            .map(|watchpoint| match watchpoint {
+                Watchpoint::StackDepth(_) => i64::try_from(self.stack.len()).ok(),
+                Watchpoint::TopOfStack(_) => self.stack.last().map(|&value| value.into()),
+                Watchpoint::Variable(name) => self.variables.get(name).map(|&value| value.into()),
+            })
+            .collect()
+    }
+
+    /// Returns the breakpoint at the program counter or, failing that, the first
+    /// watchpoint whose value changed from `before` into the watched condition.
+    fn stop(&self, before: &[Option<i64>]) -> Option<Stop> {
+        let breakpoint = self.breakpoints.iter().find(|breakpoint| match breakpoint {
+            Breakpoint::Instruction(index) => *index == self.pc,
+            Breakpoint::Label(name) => {
+                matches!(self.instructions.get(self.pc), Some(Instruction::Label(label)) if label == name)
+            }
+        });
+        if let Some(breakpoint) = breakpoint {
+            return Some(Stop::Breakpoint(breakpoint.clone()));
+        }
+
+        self.watchpoints
+            .iter()
+            .zip(before)
+            .zip(self.watched_values())
+            .find(|((watchpoint, before), after)| {
+                let target = match watchpoint {
+                    Watchpoint::StackDepth(depth) => i64::try_from(*depth).ok(),
+                    Watchpoint::TopOfStack(value) => Some((*value).into()),
+                    Watchpoint::Variable(_) => *after,
+                };
+                *before != after && *after == target
+            })
+            .map(|((watchpoint, _), _)| Stop::Watchpoint(watchpoint.clone()))
+    }
+
+    /// Calls `.forward()` `n` times. Stops at the first error and returns it, keeping
+    /// the instructions executed before the failing one.
+    pub fn forward_n(&mut self, n: usize) -> Result<(), RuntimeError> {
+        for _ in 0..n {
+            self.advance()?;
+        }
+        Ok(())
+    }
+
+    /// Calls `.forward()` until the program counter moves past the last
+    /// instruction or if there is an error.
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        while self.pc < self.instructions.len() {
+            self.advance()?;
+        }
+        Ok(())
+    }
+
+    /// *Reverses* the last instruction executed with `.forward()`.
+    /// This should undo the last instruction and restore the state of
+    /// the stack, the call stack, the variables, the memory and the program counter. Reversing a `Ret`
+    /// re-enters the callee and reversing a `Call` returns to the caller. Repeated calls should be possible
+    /// until the stack is restored to its original state before the first
+    /// forward call.
+    ///
+    /// Returns the reversed instruction. If there is no instruction to reverse,
+    /// returns an `ErrorKind::NoInstructions` error, or `ErrorKind::HistoryExhausted`
+    /// if older instructions were discarded to stay within the history limit.
+    pub fn back(&mut self) -> Result<Instruction, RuntimeError> {
+        if !self.observers.is_empty() {
+            if let Some(entry) = self.history.last() {
+                let instruction = Arc::clone(&entry.instruction);
+                self.notify(|observer, interpreter| {
+                    observer.before_undo(interpreter, &instruction)
+                });
+            }
+        }
+        match self.undo() {
+            Ok(instruction) => {
+                self.notify(|observer, interpreter| observer.after_undo(interpreter, &instruction));
+                Ok(instruction)
+            }
+            Err(error) => Err(self.fail(error)),
+        }
+    }
+
+    /// Reverses the last executed instruction; the implementation of `.back()`.
+    fn undo(&mut self) -> Result<Instruction, RuntimeError> {
+        let Some(history_entry) = self.history.last() else {
+            return Err(self.history_end().into());
+        };
+        // Check before changing anything, so that the state stays intact on error.
+        if !reversible(
+            history_entry,
+            self.pc,
+            &self.stack,
+            &self.call_stack,
+            self.memory.len(),
+        ) {
+            return Err(ErrorKind::InconsistentHistory.into());
+        }
+        let history_entry = self.history.pop().ok_or_else(|| self.history_end())?;
+
+        self.pc = history_entry.pc;
+        reverse_stacks(&history_entry, &mut self.stack, &mut self.call_stack);
+        match (&history_entry.effect, &*history_entry.instruction) {
+            (Some(Effect::Store { previous, .. }), Instruction::Store(name)) => match previous {
+                Some(value) => {
+                    self.set_variable(name, *value);
+                }
+                None => {
+                    self.variables.remove(name);
+                }
+            },
+            (
+                Some(Effect::MemoryWrite {
+                    address, previous, ..
+                }),
+                _,
+            ) => self.memory[*address] = *previous,
+            _ => {}
+        }
+
+        let instruction = Instruction::clone(&history_entry.instruction);
+        self.redo_stack.push(history_entry);
+        Ok(instruction)
+    }
+
+    /// *Replays* the last instruction reversed with `.back()`, restoring exactly the
+    /// state it produced when it was first executed, and returns it.
+    ///
+    /// The reversed instructions form a redo stack that is kept while execution
+    /// follows the same path: `.forward()` consumes the next undone instruction when
+    /// re-executing it has the same result. Executing anything else, for example
+    /// after changing the current instruction or the overflow mode, moves the redo
+    /// stack to a branch of its own (see `.branches()`). Instructions added with
+    /// `.add_instructions()` go to the end of the program and leave it intact.
+    ///
+    /// Returns an `ErrorKind::NothingToRedo` error if there is nothing to redo, or if
+    /// the current instruction was changed since it was reversed, in which case the
+    /// redo stack is moved to a branch of its own.
+    pub fn redo(&mut self) -> Result<Instruction, RuntimeError> {
+        if let Some(entry) = self.redo_stack.last() {
+            let instruction = Arc::clone(&entry.instruction);
+            self.notify(|observer, interpreter| observer.before_execute(interpreter, &instruction));
+        }
+        match self.replay() {
+            Ok(instruction) => {
+                self.notify(|observer, interpreter| {
+                    observer.after_execute(interpreter, &instruction)
+                });
+                Ok(instruction)
+            }
+            Err(error) => Err(self.fail(error)),
+        }
+    }
+
+    /// Replays the last reversed instruction; the implementation of `.redo()`.
+    fn replay(&mut self) -> Result<Instruction, RuntimeError> {
+        let history_entry = self.redo_stack.last().ok_or(ErrorKind::NothingToRedo)?;
+        // Check before changing anything, so that the state stays intact on error.
+        if !replayable(
+            history_entry,
+            self.pc,
+            &self.stack,
+            &self.call_stack,
+            self.memory.len(),
+        ) {
+            return Err(ErrorKind::InconsistentHistory.into());
+        }
+        if self.instructions.get(self.pc) != Some(&*history_entry.instruction) {
+            // The current instruction was changed after it was reversed, so the
+            // recorded future no longer follows from the program.
+            self.fork();
+            return Err(ErrorKind::NothingToRedo.into());
+        }
+        self.check_limits(&history_entry.instruction)
+            .map_err(|kind| self.error_at_pc(kind))?;
+        let history_entry = self.redo_stack.pop().ok_or(ErrorKind::NothingToRedo)?;
+
+        replay_stacks(&history_entry, &mut self.stack, &mut self.call_stack);
+        match (&history_entry.effect, &*history_entry.instruction) {
+            (Some(Effect::Store { value, .. }), Instruction::Store(name)) => {
+                self.set_variable(name, *value);
+            }
+            (Some(Effect::MemoryWrite { address, value, .. }), _) => self.memory[*address] = *value,
+            _ => {}
+        }
+
+        self.pc = history_entry.next_pc;
+        self.steps += 1;
+        let instruction = Instruction::clone(&history_entry.instruction);
+        self.record(history_entry);
+        Ok(instruction)
+    }
+
+    /// Appends an executed instruction to the history, then discards what can no
+    /// longer be reached if older instructions were discarded to stay within the
+    /// history limit.
+    fn record(&mut self, entry: HistoryEntry) {
+        let start = self.history.start();
+        self.history.push(entry);
+        self.discard_before(start);
+    }
+
+    /// Discards the checkpoints before the start of the history, and the branches
+    /// that diverge from the current one before it, after the history was trimmed
+    /// from `previous_start`.
+    fn discard_before(&mut self, previous_start: usize) {
+        let start = self.history.start();
+        if start == previous_start {
+            return;
+        }
+        self.checkpoints
+            .retain(|_, checkpoint| checkpoint.position >= start);
+        for id in 0..self.branches.len() {
+            if id == self.branch || self.branches[id].discarded {
+                continue;
+            }
+            if self.shared_path_len(id) < start {
+                let branch = &mut self.branches[id];
+                branch.discarded = true;
+                branch.entries = Vec::new();
+            } else {
+                // Keep the entries from the start of the retained history on.
+                let branch = &mut self.branches[id];
+                let base_len = branch.base().map_or(0, |(_, len)| len);
+                let stored = base_len.max(previous_start);
+                branch.entries.drain(..base_len.max(start) - stored);
+            }
+        }
+    }
+
+    /// Returns the number of instructions the path of branch `id` has in common with
+    /// the path of the current branch, from the shape of the tree of branches.
+    fn shared_path_len(&self, id: usize) -> usize {
+        // How far the current branch follows each of its ancestors.
+        let mut followed = BTreeMap::new();
+        let (mut branch, mut shared) = (self.branch, usize::MAX);
+        loop {
+            followed.insert(branch, shared);
+            let Some(parent) = self.branches[branch].parent else {
+                break;
+            };
+            shared = shared.min(self.branches[branch].fork);
+            branch = parent;
+        }
+
+        let (mut branch, mut shared) = (id, usize::MAX);
+        loop {
+            if let Some(followed) = followed.get(&branch) {
+                return shared.min(*followed);
+            }
+            shared = shared.min(self.branches[branch].fork);
+            branch = self.branches[branch]
+                .parent
+                .expect("every branch descends from the initial branch");
+        }
+    }
+
+    /// Returns the number of reversed instructions that `.redo()` can replay.
+    pub fn redo_len(&self) -> usize {
+        self.redo_stack.len()
+    }
+
+    /// Returns the index of the current branch of execution.
+    pub fn current_branch(&self) -> usize {
+        self.branch
+    }
+
+    /// Describes every branch of execution, in the order they were created.
+    ///
+    /// Executing an instruction after `.back()` that differs from the reversed one
+    /// does not discard the reversed future: the current branch keeps it, and
+    /// execution continues on a new branch forked at that point. Branches can be
+    /// switched between with `.switch_branch()`. Branches that diverge from the
+    /// current one before `.history_start()` can no longer be reached, and are left
+    /// out.
+    pub fn branches(&self) -> Vec<BranchSummary> {
+        (0..self.branches.len())
+            .filter(|&id| !self.branches[id].discarded)
+            .map(|id| {
+                let len = self.branch_len(id);
+                BranchSummary {
+                    id,
+                    parent: self.branches[id].parent,
+                    fork: self.branches[id].fork,
+                    position: if id == self.branch {
+                        self.history.len()
+                    } else {
+                        self.branches[id].position
+                    },
+                    len,
+                    stack: self.branch_stack(id, len),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the number of executed instructions branches `a` and `b` have in
+    /// common, or `None` if either does not exist or can no longer be reached.
+    pub fn shared_history_len(&self, a: usize, b: usize) -> Option<usize> {
+        let reachable = |id: usize| self.branches.get(id).is_some_and(|b| !b.discarded);
+        if !reachable(a) || !reachable(b) {
+            return None;
+        }
+        // Only the entries after the part both paths follow of the same branch are
+        // compared.
+        let followed_by_b = self.followed_paths(b);
+        let followed = self
+            .followed_paths(a)
+            .into_iter()
+            .find_map(|(id, len)| {
+                followed_by_b
+                    .iter()
+                    .find(|(other, _)| *other == id)
+                    .map(|(_, other_len)| len.min(*other_len))
+            })
+            .unwrap_or(0)
+            .min(self.branch_len(a).min(self.branch_len(b)))
+            .max(self.history.start());
+        let (a, b) = (self.path_from(a, followed), self.path_from(b, followed));
+        let shared = a.iter().zip(&b).take_while(|(a, b)| a == b).count();
+        Some(followed + shared)
+    }
+
+    /// Returns the branches whose paths the path of branch `id` is stored as
+    /// following, starting with itself, each with the number of instructions the
+    /// path of `id` is known to have in common with it.
+    fn followed_paths(&self, id: usize) -> Vec<(usize, usize)> {
+        let (mut branch, mut len) = (id, usize::MAX);
+        let mut followed = vec![(branch, len)];
+        while branch != self.branch {
+            let Some((base, base_len)) = self.branches[branch].base() else {
+                break;
+            };
+            (branch, len) = (base, len.min(base_len));
+            followed.push((branch, len));
+        }
+        followed
+    }
+
+    /// Makes `id` the current branch: reverses the current branch back to where the
+    /// two diverge, then replays the other up to the position it was left at. Any
+    /// instruction along the branch that was changed with `.current_instruction()`
+    /// since is restored to the one it executed. The rest of its instructions can
+    /// be replayed with `.redo()`.
+    ///
+    /// Returns `ErrorKind::UndefinedBranch` if there is no such branch, and
+    /// `ErrorKind::HistoryExhausted` if it diverges before `.history_start()`.
+    pub fn switch_branch(&mut self, id: usize) -> Result<(), RuntimeError> {
+        let branch = self.branches.get(id).ok_or(ErrorKind::UndefinedBranch)?;
+        if branch.discarded {
+            return Err(ErrorKind::HistoryExhausted.into());
+        }
+        if id == self.branch {
+            return Ok(());
+        }
+        let target = self.path_from(id, self.history.start());
+        let shared = self
+            .history
+            .entries()
+            .iter()
+            .zip(&target)
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        self.leave_branch(id, self.history.start() + shared);
+        self.back_n(self.history.len() - self.history.start() - shared)?;
+        // Instructions changed since the branch was recorded are changed back, so
+        // that its future can be replayed.
+        for entry in &target {
+            if let Some(instruction) = self.instructions.get_mut(entry.pc) {
+                if *instruction != *entry.instruction {
+                    *instruction = Instruction::clone(&entry.instruction);
+                    self.unshare(entry.pc);
+                }
+            }
+        }
+        self.redo_stack = target.into_iter().skip(shared).rev().collect();
+        self.branch = id;
+
+        // The path of the current branch lives in the history and redo stack.
+        let position = self.branches[id].position;
+        self.branches[id].entries.clear();
+        self.branches[id].base = None;
+        while self.history.len() < position {
+            self.redo()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of instructions along branch `id`, including those that
+    /// have been reversed.
+    fn branch_len(&self, id: usize) -> usize {
+        if id == self.branch {
+            return self.history.len() + self.redo_stack.len();
+        }
+        self.entries_start(id) + self.branches[id].entries.len()
+    }
+
+    /// Returns the position of the first of the stored `entries` of branch `id`,
+    /// which is not the current one.
+    fn entries_start(&self, id: usize) -> usize {
+        let base_len = self.branches[id].base().map_or(0, |(_, len)| len);
+        base_len.max(self.history.start())
+    }
+
+    /// Returns the entries along the path of branch `id` from `position` on,
+    /// including those that have been reversed. Positions before the start of the
+    /// retained history are taken to be its start.
+    fn path_from(&self, id: usize, position: usize) -> Vec<HistoryEntry> {
+        let position = position.max(self.history.start());
+        if id == self.branch {
+            let reversed = position.saturating_sub(self.history.len());
+            let mut path: Vec<_> = self.history.iter_from(position).collect();
+            path.extend(self.redo_stack.iter().rev().skip(reversed).cloned());
+            return path;
+        }
+        let branch = &self.branches[id];
+        let mut path = match branch.base() {
+            // A base that diverges before the retained history can no longer be
+            // followed, and none of its path is needed.
+            Some((base, len)) if len > self.history.start() && position < len => {
+                let mut path = self.path_from(base, position);
+                path.truncate(len - position);
+                path
+            }
+            _ => Vec::new(),
+        };
+        let skipped = position.saturating_sub(self.entries_start(id));
+        path.extend(branch.entries.iter().skip(skipped).cloned());
+        path
+    }
+
+    /// Returns the stack along the path of branch `id` after the instructions before
+    /// `position`, which is no earlier than the start of the retained history.
+    fn branch_stack(&self, id: usize, position: usize) -> Vec<i32> {
+        if id == self.branch {
+            if position < self.history.len() {
+                return self.history.stack_at(position);
+            }
+            let mut stack = self.stack.clone();
+            let replayed = position - self.history.len();
+            for entry in self.redo_stack.iter().rev().take(replayed) {
+                apply(&mut stack, entry);
+            }
+            return stack;
+        }
+        let branch = &self.branches[id];
+        let mut stack = match branch.base() {
+            Some((base, len)) if len > self.history.start() => {
+                self.branch_stack(base, position.min(len))
+            }
+            _ => self.history.snapshot().to_vec(),
+        };
+        let replayed = position.saturating_sub(self.entries_start(id));
+        for entry in branch.entries.iter().take(replayed) {
+            apply(&mut stack, entry);
+        }
+        stack
+    }
+
+    /// Stores the entries of the current branch after the first `shared` of its path,
+    /// which it has in common with branch `next` that is about to be made current, so
+    /// that it can be switched back to once it is no longer current.
+    fn leave_branch(&mut self, next: usize, shared: usize) {
+        let mut entries = Vec::new();
+        if shared < self.history.len() {
+            entries = self.history.iter_from(shared).collect();
+        }
+        entries.extend(self.redo_stack.iter().rev().cloned());
+        let branch = &mut self.branches[self.branch];
+        branch.entries = entries;
+        branch.base = Some((next, shared));
+        branch.position = self.history.len();
+    }
+
+    /// Leaves the current branch, keeping its reversed future, and continues on a
+    /// new branch forked from it at the current position.
+    fn fork(&mut self) {
+        self.leave_branch(self.branches.len(), self.history.len());
+        self.redo_stack.clear();
+        self.branches.push(Branch {
+            parent: Some(self.branch),
+            fork: self.history.len(),
+            position: 0,
+            entries: Vec::new(),
+            discarded: false,
+            base: None,
+        });
+        self.branch = self.branches.len() - 1;
+    }
+
+    /// Reverses the last `n` executed instructions, as if by calling `.back()` `n`
+    /// times. If fewer than `n` instructions can be reversed, returns the error
+    /// `.back()` would without reversing any.
+    pub fn back_n(&mut self, n: usize) -> Result<(), RuntimeError> {
+        if n > self.history.len() - self.history.start() {
+            return Err(self.history_end().into());
+        }
+        for _ in 0..n {
+            self.back()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the error for reversing past the start of the history: there are no
+    /// instructions to reverse, or they were discarded to stay within the history
+    /// limit.
+    fn history_end(&self) -> ErrorKind {
+        if self.history.start() > 0 {
+            ErrorKind::HistoryExhausted
+        } else {
+            ErrorKind::NoInstructions
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn instruction_strategy() -> impl Strategy<Value = Instruction> {
+        prop_oneof![
+            any::<i32>().prop_map(Instruction::Push),
+            Just(Instruction::Pop),
+            Just(Instruction::Dup),
+            Just(Instruction::Swap),
+            Just(Instruction::Over),
+            Just(Instruction::Rot),
+            Just(Instruction::Nip),
+            Just(Instruction::Tuck),
+            (0..4usize).prop_map(Instruction::DropN),
+            (0..4usize).prop_map(Instruction::Pick),
+            (0..4usize).prop_map(Instruction::Roll),
+            Just(Instruction::Add),
+            Just(Instruction::Sub),
+            Just(Instruction::Mul),
+            Just(Instruction::Div),
+            Just(Instruction::Mod),
+            Just(Instruction::Neg),
+            Just(Instruction::Abs),
+            Just(Instruction::Min),
+            Just(Instruction::Max),
+            Just(Instruction::Pow),
+            Just(Instruction::DivMod),
+            Just(Instruction::Eq),
+            Just(Instruction::Ne),
+            Just(Instruction::Lt),
+            Just(Instruction::Gt),
+            Just(Instruction::Le),
+            Just(Instruction::Ge),
+            Just(Instruction::And),
+            Just(Instruction::Or),
+            Just(Instruction::Not),
+            Just(Instruction::BitAnd),
+            Just(Instruction::BitOr),
+            Just(Instruction::BitXor),
+            Just(Instruction::BitNot),
+            Just(Instruction::Shl),
+            Just(Instruction::Shr),
+            Just(Instruction::Ushr),
+            Just(Instruction::Rotl),
+            Just(Instruction::Rotr),
+            prop_oneof![Just("x"), Just("y")].prop_map(|name| Instruction::Load(name.to_string())),
+            prop_oneof![Just("x"), Just("y")].prop_map(|name| Instruction::Store(name.to_string())),
+            Just(Instruction::FetchCell),
+            Just(Instruction::StoreCell),
+        ]
+    }
+
+    fn instruction_sequence() -> impl Strategy<Value = Vec<Instruction>> {
+        prop::collection::vec(instruction_strategy(), 1..100)
+    }
+
+    #[test]
+    fn test_property_execution_without_panic() {
+        proptest!(|(instructions in instruction_sequence())| {
+            let mut interpreter = Interpreter::new();
+            interpreter.add_instructions(&instructions);
+
+            let _ = interpreter.run();
+            // Ensures no panic occurs during execution.
+        });
+    }
+
+    #[test]
+    fn test_property_reverse_execution() {
+        proptest!(|(instructions in instruction_sequence())| {
+            let mut interpreter = Interpreter::new();
+            interpreter.add_instructions(&instructions);
+
+            let run_result = interpreter.run();
+
+            // Collect executed instructions
+            let executed_instructions: Vec<_> = interpreter.history.entries().into_iter().map(|h| Instruction::clone(&h.instruction)).collect();
+
+            if run_result.is_ok() {
+                prop_assert_eq!(&executed_instructions, &instructions, "When run is successful executed instructions should be the same as input instructions");
+            }
+
+            // Attempt to reverse all executed instructions
+            while interpreter.back().is_ok() {}
+
+            prop_assert_eq!(interpreter.stack, Vec::<i32>::new(), "After reversing the stack should be empty");
+            prop_assert_eq!(interpreter.pc, 0, "After reversing the program counter should be reset");
+            prop_assert!(interpreter.variables.is_empty(), "After reversing no variable should be bound");
+            prop_assert!(interpreter.memory.iter().all(|cell| *cell == 0), "After reversing the memory should be cleared");
+
+            // The program itself is never modified by execution or reversal
+            prop_assert_eq!(interpreter.instructions, instructions);
+        });
+    }
+
+    #[test]
+    fn test_property_redo() {
+        proptest!(|(instructions in instruction_sequence(), undone in 0..20usize)| {
+            let mut interpreter = Interpreter::new();
+            interpreter.add_instructions(&instructions);
+            let _ = interpreter.run();
+            let (stack, pc, variables, memory) = (
+                interpreter.stack.clone(),
+                interpreter.pc,
+                interpreter.variables.clone(),
+                interpreter.memory.clone(),
+            );
+
+            let undone = undone.min(interpreter.history.len());
+            interpreter.back_n(undone).unwrap();
+            prop_assert_eq!(interpreter.redo_len(), undone);
+            while interpreter.redo().is_ok() {}
+
+            prop_assert_eq!(interpreter.redo_len(), 0);
+            prop_assert_eq!(interpreter.stack, stack);
+            prop_assert_eq!(interpreter.pc, pc);
+            prop_assert_eq!(interpreter.variables, variables);
+            prop_assert_eq!(interpreter.memory, memory);
+        });
+    }
+
+    #[cfg(feature = "serde")]
+    fn assert_same_state(a: &Interpreter, b: &Interpreter) {
+        assert_eq!(a.instructions(), b.instructions());
+        assert_eq!(a.pc(), b.pc());
+        assert_eq!(a.stack(), b.stack());
+        assert_eq!(a.call_stack(), b.call_stack());
+        assert_eq!(a.variables(), b.variables());
+        assert_eq!(a.memory(), b.memory());
+        assert_eq!(a.overflow_mode(), b.overflow_mode());
+        assert_eq!(a.history.len(), b.history.len());
+        assert_eq!(a.redo_len(), b.redo_len());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_property_serde_round_trip() {
+        proptest!(|(instructions in instruction_sequence(), steps in 0..20usize)| {
+            let mut interpreter = Interpreter::new();
+            interpreter.add_instructions(&instructions);
+            for _ in 0..steps {
+                if interpreter.forward().is_err() {
+                    break;
+                }
+            }
+
+            let json = serde_json::to_string(&interpreter).unwrap();
+            let mut restored: Interpreter = serde_json::from_str(&json).unwrap();
+            assert_same_state(&interpreter, &restored);
+
+            // The restored session reverses exactly like the original one
+            loop {
+                let expected = interpreter.back();
+                prop_assert_eq!(restored.back(), expected.clone());
+                assert_same_state(&interpreter, &restored);
+                if expected.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_session_with_effects() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_overflow_mode(OverflowMode::Wrapping);
+        let mut program = square_program();
+        program.splice(
+            0..0,
+            [
+                Instruction::Push(9),
+                Instruction::Store("x".to_string()),
+                Instruction::Push(7),
+                Instruction::Push(3),
+                Instruction::StoreCell,
+            ],
+        );
+        interpreter.add_instructions(&program);
+        for _ in 0..9 {
+            interpreter.forward().unwrap();
+        }
+        assert_eq!(interpreter.call_stack(), &[7]);
+
+        let json = serde_json::to_string(&interpreter).unwrap();
+        let mut restored: Interpreter = serde_json::from_str(&json).unwrap();
+        assert_same_state(&interpreter, &restored);
+
+        restored.run().unwrap();
+        assert_eq!(restored.stack(), &vec![25]);
+        while restored.back().is_ok() {}
+        assert_eq!(restored.pc(), 0);
+        assert!(restored.variables().is_empty());
+        assert_eq!(restored.memory()[3], 0);
+        assert_eq!(restored.overflow_mode(), OverflowMode::Wrapping);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_limited_history() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_history_limit(HistoryLimit::Entries(10));
+        interpreter.add_instructions(&countdown_from(100));
+        interpreter.run().unwrap();
+
+        let json = serde_json::to_string(&interpreter).unwrap();
+        let mut restored: Interpreter = serde_json::from_str(&json).unwrap();
+        assert_same_state(&interpreter, &restored);
+        assert_eq!(restored.history_start(), interpreter.history_start());
+        assert_eq!(restored.history_limit(), HistoryLimit::Entries(10));
+        while restored.back().is_ok() {}
+        assert_eq!(restored.stack(), interpreter.history.snapshot());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_rejects_inconsistent_session() {
+        let mut interpreter = Interpreter::with_memory_size(4);
+        interpreter.add_instructions(&[
+            Instruction::Push(5),
+            Instruction::Push(3),
+            Instruction::StoreCell,
+            Instruction::Push(1),
+        ]);
+        interpreter.run().unwrap();
+        interpreter.back().unwrap();
+        *interpreter.current_instruction().unwrap() = Instruction::Push(2);
+        interpreter.forward().unwrap();
+        interpreter.switch_branch(0).unwrap();
+        let value = serde_json::to_value(&interpreter).unwrap();
+        assert!(serde_json::from_value::<Interpreter>(value.clone()).is_ok());
+
+        let corrupt = |edit: fn(&mut serde_json::Value)| {
+            let mut value = value.clone();
+            edit(&mut value);
+            serde_json::from_value::<Interpreter>(value)
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(corrupt(|v| v["branch"] = 7.into()).contains("current branch 7"));
+        assert!(corrupt(|v| v["pc"] = 9.into()).contains("program counter 9"));
+        assert!(
+            corrupt(|v| v["branches"][1]["base"] = serde_json::json!([5, 2]))
+                .contains("branch 1 follows a branch that does not exist")
+        );
+        assert!(
+            corrupt(|v| v["branches"][1]["base"] = serde_json::json!([1, 2]))
+                .contains("the path of branch 1 follows itself")
+        );
+        assert!(corrupt(
+            |v| v["history"]["entries"][2]["effect"]["MemoryWrite"]["address"] = 4.into()
+        )
+        .contains("memory address 4 outside memory"));
+        assert!(corrupt(|v| v["pc"] = 2.into())
+            .contains("history does not lead to the state at instruction 2"));
+        assert!(corrupt(|v| v["stack"] = serde_json::json!([1]))
+            .contains("history does not start from its snapshot"));
+        assert!(corrupt(|v| v["call_stack"] = serde_json::json!([3]))
+            .contains("history does not start from an empty call stack"));
+        assert!(corrupt(|v| v["redo_stack"][0]["pc"] = 0.into())
+            .contains("redo stack does not follow from the state at instruction 3"));
+    }
+
+    #[test]
+    fn test_inconsistent_history_leaves_state_intact() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&square_program());
+        interpreter.forward_n(4).unwrap();
+        let stack = interpreter.stack.clone();
+
+        // The values DUP pushed are gone
+        interpreter.stack.clear();
+        let error = interpreter.back().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InconsistentHistory);
+        assert_eq!(interpreter.history_len(), 4);
+        assert_eq!(interpreter.pc, 8);
+
+        // RET would return to another caller
+        interpreter.stack = stack;
+        interpreter.forward_n(2).unwrap();
+        interpreter.back().unwrap();
+        interpreter.call_stack = vec![7];
+        let error = interpreter.redo().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InconsistentHistory);
+        assert_eq!(interpreter.history_len(), 5);
+        assert_eq!(interpreter.call_stack(), &vec![7]);
+        assert_eq!(interpreter.pc, 9);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_runtime_error() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[
+            Instruction::Push(1),
+            Instruction::Push(0),
+            Instruction::Div,
+        ]);
+        let error = interpreter.run().unwrap_err().with_line(3);
+        let json = serde_json::to_string(&error).unwrap();
+        let restored: RuntimeError = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, error);
+        assert_eq!(
+            restored.to_string(),
+            "DIV at line 3: divide by zero (1 / 0)"
+        );
+
+        let json = serde_json::to_string(&Instruction::Jmp("loop".to_string())).unwrap();
+        assert_eq!(json, r#"{"Jmp":"loop"}"#);
+    }
+
+    #[test]
+    fn test_stack_underflow_on_pop() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[Instruction::Pop]);
+        let result = interpreter.run();
+        assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::StackUnderflow));
+        assert_eq!(
+            interpreter.pc, 0,
+            "Program counter should stay on the failing instruction"
+        );
+    }
+
+    #[test]
+    fn test_divide_by_zero() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[
+            Instruction::Push(10),
+            Instruction::Push(0),
+            Instruction::Div,
+        ]);
+        let result = interpreter.run();
+        assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::DivideByZero));
+        assert_eq!(interpreter.stack, vec![10, 0], "Stack should be restored");
+        assert_eq!(
+            interpreter.current_instruction(),
+            Some(&mut Instruction::Div),
+            "Program counter should stay on the failing instruction"
+        );
+    }
+
+    #[test]
+    fn test_addition_property() {
+        proptest!(|(a in any::<i32>(), b in any::<i32>())| {
+            let mut interpreter = Interpreter::new();
+            interpreter.add_instructions(&[
+                Instruction::Push(a),
+                Instruction::Push(b),
+                Instruction::Add,
+            ]);
+            let expected_result = match a.checked_add(b) {
+                Some(sum) => Ok(sum),
+                None => Err(ErrorKind::ArithmeticOverflow),
+            };
+            let result = interpreter.run();
+            match expected_result {
+                Ok(sum) => {
+                    prop_assert_eq!(result, Ok(()));
+                    prop_assert_eq!(interpreter.stack, vec![sum]);
+                }
+                Err(err) => {
+                    prop_assert_eq!(result.map_err(|e| e.kind()), Err(err));
+                    // Stack should be restored to [a, b]
+                    prop_assert_eq!(interpreter.stack, vec![a, b]);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_multiplication_property() {
+        proptest!(|(a in any::<i32>(), b in any::<i32>())| {
+            let mut interpreter = Interpreter::new();
+            interpreter.add_instructions(&[
+                Instruction::Push(a),
+                Instruction::Push(b),
+                Instruction::Mul,
+            ]);
+            let expected_result = match a.checked_mul(b) {
+                Some(product) => Ok(product),
+                None => Err(ErrorKind::ArithmeticOverflow),
+            };
+            let result = interpreter.run();
+            match expected_result {
+                Ok(product) => {
+                    prop_assert_eq!(result, Ok(()));
+                    prop_assert_eq!(interpreter.stack, vec![product]);
+                }
+                Err(err) => {
+                    prop_assert_eq!(result.map_err(|e| e.kind()), Err(err));
+                    // Stack should be restored to [a, b]
+                    prop_assert_eq!(interpreter.stack, vec![a, b]);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_subtraction_property() {
+        proptest!(|(a in any::<i32>(), b in any::<i32>())| {
+            let mut interpreter = Interpreter::new();
+            interpreter.add_instructions(&[
+                Instruction::Push(a),
+                Instruction::Push(b),
+                Instruction::Sub,
+            ]);
+            let expected_result = match a.checked_sub(b) {
+                Some(diff) => Ok(diff),
+                None => Err(ErrorKind::ArithmeticOverflow),
+            };
+            let result = interpreter.run();
+            match expected_result {
+                Ok(diff) => {
+                    prop_assert_eq!(result, Ok(()));
+                    prop_assert_eq!(interpreter.stack, vec![diff]);
+                }
+                Err(err) => {
+                    prop_assert_eq!(result.map_err(|e| e.kind()), Err(err));
+                    // Stack should be restored to [a, b]
+                    prop_assert_eq!(interpreter.stack, vec![a, b]);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_division_property() {
+        proptest!(|(a in any::<i32>(), b in any::<i32>())| {
+            let mut interpreter = Interpreter::new();
+            interpreter.add_instructions(&[
+                Instruction::Push(a),
+                Instruction::Push(b),
+                Instruction::Div,
+            ]);
+            let expected_result = a.checked_div(b);
+            let result = interpreter.run();
+            match expected_result {
+                Some(quotient) => {
+                    prop_assert_eq!(result, Ok(()));
+                    prop_assert_eq!(interpreter.stack, vec![quotient]);
+                }
+                None => {
+                    if b == 0 {
+                        prop_assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::DivideByZero));
+                    } else {
+                        prop_assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::ArithmeticOverflow));
+                    }
+                    // Stack should be restored to [a, b]
+                    prop_assert_eq!(interpreter.stack, vec![a, b]);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_modulo_property() {
+        proptest!(|(a in any::<i32>(), b in any::<i32>())| {
+            let mut interpreter = Interpreter::new();
+            interpreter.add_instructions(&[
+                Instruction::Push(a),
+                Instruction::Push(b),
+                Instruction::Mod,
+            ]);
+            let result = interpreter.run();
+            match a.checked_rem(b) {
+                Some(remainder) => {
+                    prop_assert_eq!(result, Ok(()));
+                    prop_assert_eq!(interpreter.stack, vec![remainder]);
+                }
+                None => {
+                    if b == 0 {
+                        prop_assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::DivideByZero));
+                    } else {
+                        prop_assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::ArithmeticOverflow));
+                    }
+                    // Stack should be restored to [a, b]
+                    prop_assert_eq!(interpreter.stack, vec![a, b]);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_divmod_property() {
+        proptest!(|(a in any::<i32>(), b in any::<i32>())| {
+            let mut interpreter = Interpreter::new();
+            interpreter.add_instructions(&[
+                Instruction::Push(a),
+                Instruction::Push(b),
+                Instruction::DivMod,
+            ]);
+            let result = interpreter.run();
+            match (a.checked_div(b), a.checked_rem(b)) {
+                (Some(quotient), Some(remainder)) => {
+                    prop_assert_eq!(result, Ok(()));
+                    prop_assert_eq!(&interpreter.stack, &vec![quotient, remainder]);
+                    interpreter.back().unwrap();
+                    prop_assert_eq!(interpreter.stack, vec![a, b]);
+                }
+                _ => {
+                    if b == 0 {
+                        prop_assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::DivideByZero));
+                    } else {
+                        prop_assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::ArithmeticOverflow));
+                    }
+                    // Stack should be restored to [a, b]
+                    prop_assert_eq!(interpreter.stack, vec![a, b]);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_power_property() {
+        proptest!(|(a in -20..20i32, b in -2..12i32)| {
+            let mut interpreter = Interpreter::new();
+            interpreter.add_instructions(&[
+                Instruction::Push(a),
+                Instruction::Push(b),
+                Instruction::Pow,
+            ]);
+            let result = interpreter.run();
+            if b < 0 {
+                prop_assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::NegativeExponent));
+                prop_assert_eq!(interpreter.stack, vec![a, b]);
+            } else if let Some(power) = a.checked_pow(b as u32) {
+                prop_assert_eq!(result, Ok(()));
+                prop_assert_eq!(interpreter.stack, vec![power]);
+            } else {
+                prop_assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::ArithmeticOverflow));
+                prop_assert_eq!(interpreter.stack, vec![a, b]);
+            }
+        });
+    }
+
+    #[test]
+    fn test_unary_and_min_max_arithmetic() {
+        let cases = [
+            (vec![Instruction::Push(-4), Instruction::Neg], vec![4]),
+            (vec![Instruction::Push(-4), Instruction::Abs], vec![4]),
+            (
+                vec![
+                    Instruction::Push(3),
+                    Instruction::Push(-2),
+                    Instruction::Min,
+                ],
+                vec![-2],
+            ),
+            (
+                vec![
+                    Instruction::Push(3),
+                    Instruction::Push(-2),
+                    Instruction::Max,
+                ],
+                vec![3],
+            ),
+        ];
+        for (instructions, expected) in cases {
+            let mut interpreter = Interpreter::new();
+            interpreter.add_instructions(&instructions);
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.stack, expected);
+        }
+
+        for instruction in [Instruction::Neg, Instruction::Abs] {
+            let mut interpreter = Interpreter::new();
+            interpreter.add_instructions(&[Instruction::Push(i32::MIN), instruction]);
+            assert_eq!(
+                interpreter.run().map_err(|e| e.kind()),
+                Err(ErrorKind::ArithmeticOverflow)
+            );
+            assert_eq!(
+                interpreter.stack,
+                vec![i32::MIN],
+                "Stack should be restored"
+            );
+        }
+    }
+
+    #[test]
+    fn test_overflow_modes_property() {
+        proptest!(|(a in any::<i32>(), b in any::<i32>())| {
+            let cases = [
+                (Instruction::Add, a.wrapping_add(b), a.saturating_add(b)),
+                (Instruction::Sub, a.wrapping_sub(b), a.saturating_sub(b)),
+                (Instruction::Mul, a.wrapping_mul(b), a.saturating_mul(b)),
+            ];
+            for (instruction, wrapped, saturated) in cases {
+                for (mode, expected) in [
+                    (OverflowMode::Wrapping, wrapped),
+                    (OverflowMode::Saturating, saturated),
+                ] {
+                    let mut interpreter = Interpreter::new();
+                    interpreter.set_overflow_mode(mode);
+                    interpreter.add_instructions(&[
+                        Instruction::Push(a),
+                        Instruction::Push(b),
+                        instruction.clone(),
+                    ]);
+                    prop_assert_eq!(interpreter.run(), Ok(()));
+                    prop_assert_eq!(&interpreter.stack, &vec![expected]);
+                    // Undo restores the exact operands even if the result wrapped
+                    interpreter.back().unwrap();
+                    prop_assert_eq!(&interpreter.stack, &vec![a, b]);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_overflow_modes_edge_cases() {
+        let cases = [
+            (Instruction::Div, OverflowMode::Wrapping, vec![i32::MIN]),
+            (Instruction::Div, OverflowMode::Saturating, vec![i32::MAX]),
+            (Instruction::Mod, OverflowMode::Saturating, vec![0]),
+            (
+                Instruction::DivMod,
+                OverflowMode::Wrapping,
+                vec![i32::MIN, 0],
+            ),
+            (
+                Instruction::DivMod,
+                OverflowMode::Saturating,
+                vec![i32::MAX, 0],
+            ),
+        ];
+        for (instruction, mode, expected) in cases {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_overflow_mode(mode);
+            interpreter.add_instructions(&[
+                Instruction::Push(i32::MIN),
+                Instruction::Push(-1),
+                instruction.clone(),
+            ]);
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.stack, expected, "{:?} {:?}", instruction, mode);
+        }
+
+        let mut interpreter = Interpreter::new();
+        interpreter.set_overflow_mode(OverflowMode::Saturating);
+        interpreter.add_instructions(&[Instruction::Push(i32::MIN), Instruction::Neg]);
+        interpreter.run().unwrap();
+        assert_eq!(interpreter.stack, vec![i32::MAX]);
+        interpreter.back().unwrap();
+        assert_eq!(interpreter.stack, vec![i32::MIN]);
+
+        // Division by zero is an error in every mode
+        let mut interpreter = Interpreter::new();
+        interpreter.set_overflow_mode(OverflowMode::Wrapping);
+        interpreter.add_instructions(&[
+            Instruction::Push(1),
+            Instruction::Push(0),
+            Instruction::Div,
+        ]);
+        assert_eq!(
+            interpreter.run().map_err(|e| e.kind()),
+            Err(ErrorKind::DivideByZero)
+        );
+    }
+
+    #[test]
+    fn test_parse_overflow_mode() {
+        assert_eq!("wrapping".parse(), Ok(OverflowMode::Wrapping));
+        assert_eq!("Saturating".parse(), Ok(OverflowMode::Saturating));
+        assert_eq!(
+            OverflowMode::Checked.to_string().parse(),
+            Ok(OverflowMode::Checked)
+        );
+        assert!("unbounded".parse::<OverflowMode>().is_err());
+    }
+
+    #[test]
+    fn test_comparison_property() {
+        proptest!(|(a in -3..3i32, b in -3..3i32)| {
+            let cases = [
+                (Instruction::Eq, a == b),
+                (Instruction::Ne, a != b),
+                (Instruction::Lt, a < b),
+                (Instruction::Gt, a > b),
+                (Instruction::Le, a <= b),
+                (Instruction::Ge, a >= b),
+                (Instruction::And, a != 0 && b != 0),
+                (Instruction::Or, a != 0 || b != 0),
+            ];
+            for (instruction, expected) in cases {
+                let mut interpreter = Interpreter::new();
+                interpreter.add_instructions(&[Instruction::Push(a), Instruction::Push(b), instruction]);
+                prop_assert_eq!(interpreter.run(), Ok(()));
+                prop_assert_eq!(&interpreter.stack, &vec![i32::from(expected)]);
+                interpreter.back().unwrap();
+                prop_assert_eq!(&interpreter.stack, &vec![a, b]);
+            }
+        });
+    }
+
+    #[test]
+    fn test_not() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[Instruction::Push(7), Instruction::Not, Instruction::Not]);
+        interpreter.forward().unwrap();
+        interpreter.forward().unwrap();
+        assert_eq!(interpreter.stack, vec![0]);
+        interpreter.forward().unwrap();
+        assert_eq!(interpreter.stack, vec![1]);
+        interpreter.back().unwrap();
+        interpreter.back().unwrap();
+        assert_eq!(interpreter.stack, vec![7]);
+    }
+
+    #[test]
+    fn test_bitwise_property() {
+        proptest!(|(a in any::<i32>(), b in any::<i32>(), amount in 0..32u32)| {
+            let cases = [
+                (Instruction::BitAnd, b, a & b),
+                (Instruction::BitOr, b, a | b),
+                (Instruction::BitXor, b, a ^ b),
+                (Instruction::Shl, amount as i32, a << amount),
+                (Instruction::Shr, amount as i32, a >> amount),
+                (Instruction::Ushr, amount as i32, ((a as u32) >> amount) as i32),
+                (Instruction::Rotl, amount as i32, a.rotate_left(amount)),
+                (Instruction::Rotr, amount as i32, a.rotate_right(amount)),
+            ];
+            for (instruction, operand, expected) in cases {
+                let mut interpreter = Interpreter::new();
+                interpreter.add_instructions(&[Instruction::Push(a), Instruction::Push(operand), instruction]);
+                prop_assert_eq!(interpreter.run(), Ok(()));
+                prop_assert_eq!(&interpreter.stack, &vec![expected]);
+                interpreter.back().unwrap();
+                prop_assert_eq!(&interpreter.stack, &vec![a, operand]);
+            }
+        });
+    }
+
+    #[test]
+    fn test_shift_out_of_range() {
+        for amount in [-1, 32, i32::MAX] {
+            let mut interpreter = Interpreter::new();
+            interpreter.add_instructions(&[
+                Instruction::Push(1),
+                Instruction::Push(amount),
+                Instruction::Shl,
+            ]);
+            let result = interpreter.run();
+            assert_eq!(
+                result.map_err(|e| e.kind()),
+                Err(ErrorKind::ShiftOutOfRange)
+            );
+            assert_eq!(
+                interpreter.stack,
+                vec![1, amount],
+                "Stack should be unchanged"
+            );
+        }
+    }
+
+    #[test]
+    fn test_variables() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[
+            Instruction::Push(1),
+            Instruction::Store("x".to_string()),
+            Instruction::Push(2),
+            Instruction::Store("x".to_string()),
+            Instruction::Load("x".to_string()),
+            Instruction::Load("x".to_string()),
+            Instruction::Add,
+        ]);
+        interpreter.run().unwrap();
+        assert_eq!(interpreter.stack, vec![4]);
+        assert_eq!(interpreter.variables().get("x"), Some(&2));
+
+        for _ in 0..3 {
+            interpreter.back().unwrap(); // Undo Add and both Loads
+        }
+        assert!(interpreter.stack.is_empty());
+        interpreter.back().unwrap(); // Undo the overwriting Store
+        assert_eq!(interpreter.variables().get("x"), Some(&1));
+        assert_eq!(interpreter.stack, vec![2]);
+        interpreter.back().unwrap(); // Undo Push(2)
+        interpreter.back().unwrap(); // Undo the first Store: x becomes unset
+        assert!(interpreter.variables().is_empty());
+        assert_eq!(interpreter.stack, vec![1]);
+    }
+
+    #[test]
+    fn test_load_undefined_variable() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[Instruction::Load("x".to_string())]);
+        assert_eq!(
+            interpreter.run().map_err(|e| e.kind()),
+            Err(ErrorKind::UndefinedVariable)
+        );
+        assert!(interpreter.stack.is_empty());
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut interpreter = Interpreter::with_memory_size(4);
+        interpreter.add_instructions(&[
+            Instruction::Push(10),
+            Instruction::Push(3),
+            Instruction::StoreCell,
+            Instruction::Push(20),
+            Instruction::Push(3),
+            Instruction::StoreCell,
+            Instruction::Push(3),
+            Instruction::FetchCell,
+        ]);
+        interpreter.run().unwrap();
+        assert_eq!(interpreter.stack, vec![20]);
+        assert_eq!(interpreter.memory(), &[0, 0, 0, 20]);
+
+        interpreter.back().unwrap(); // Undo Fetch
+        assert_eq!(interpreter.stack, vec![3]);
+        interpreter.back().unwrap(); // Undo Push(3)
+        interpreter.back().unwrap(); // Undo the overwriting Store
+        assert_eq!(interpreter.memory(), &[0, 0, 0, 10]);
+        assert_eq!(interpreter.stack, vec![20, 3]);
+        while interpreter.back().is_ok() {}
+        assert_eq!(interpreter.memory(), &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_memory_out_of_bounds() {
+        for (instructions, expected_stack) in [
+            (vec![Instruction::Push(4), Instruction::FetchCell], vec![4]),
+            (
+                vec![Instruction::Push(-1), Instruction::FetchCell],
+                vec![-1],
+            ),
+            (
+                vec![
+                    Instruction::Push(7),
+                    Instruction::Push(4),
+                    Instruction::StoreCell,
+                ],
+                vec![7, 4],
+            ),
+        ] {
+            let mut interpreter = Interpreter::with_memory_size(4);
+            interpreter.add_instructions(&instructions);
+            assert_eq!(
+                interpreter.run().map_err(|e| e.kind()),
+                Err(ErrorKind::OutOfBounds)
+            );
+            assert_eq!(
+                interpreter.stack, expected_stack,
+                "Stack should be unchanged"
+            );
+            assert_eq!(interpreter.memory(), &[0, 0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn test_error_context() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[
+            Instruction::Push(7),
+            Instruction::Push(10),
+            Instruction::Push(0),
+            Instruction::Div,
+        ]);
+        let error = interpreter.run().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::DivideByZero);
+        let context = error.context().unwrap();
+        assert_eq!(context.instruction, Instruction::Div);
+        assert_eq!(context.pc, 3);
+        assert_eq!(context.line, None);
+        assert_eq!(context.stack_depth, 3);
+        assert_eq!(context.operands, vec![10, 0]);
+        assert_eq!(
+            error.to_string(),
+            "DIV at instruction 3: divide by zero (10 / 0)"
+        );
+        assert_eq!(
+            error.with_line(12).to_string(),
+            "DIV at line 12: divide by zero (10 / 0)"
+        );
+    }
+
+    #[test]
+    fn test_error_display_without_operator() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[Instruction::Push(1), Instruction::Rot]);
+        let error = interpreter.run().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "ROT at instruction 1: stack underflow (operands: 1)"
+        );
+
+        // Errors that are not caused by an instruction have no context
+        let error = interpreter
+            .back()
+            .and_then(|_| interpreter.back())
+            .unwrap_err();
+        assert_eq!(error.context(), None);
+        assert_eq!(error.to_string(), "no instructions");
+        let error: Box<dyn std::error::Error> = Box::new(error);
+        assert_eq!(error.to_string(), "no instructions");
+    }
+
+    #[test]
+    fn test_instruction_display() {
+        assert_eq!(Instruction::Push(-5).to_string(), "PUSH -5");
+        assert_eq!(Instruction::Roll(2).to_string(), "ROLL 2");
+        assert_eq!(Instruction::BitXor.to_string(), "BXOR");
+        assert_eq!(Instruction::StoreCell.to_string(), "!");
+        assert_eq!(Instruction::Store("n".to_string()).to_string(), "STORE n");
+        assert_eq!(Instruction::Label("loop".to_string()).to_string(), "loop:");
+        assert_eq!(Instruction::Jge("end".to_string()).to_string(), "JGE end");
+    }
+
+    #[test]
+    fn test_back_with_empty_history() {
+        let mut interpreter = Interpreter::new();
+        let result = interpreter.back();
+        assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::NoInstructions));
+    }
+
+    #[test]
+    fn test_forward_with_empty_instructions() {
+        let mut interpreter = Interpreter::new();
+        let result = interpreter.forward();
+        assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::NoInstructions));
+    }
+
+    #[test]
+    fn test_run_with_empty_instructions() {
+        let mut interpreter = Interpreter::new();
+        let result = interpreter.run();
+        assert_eq!(result, Ok(()));
+        assert!(interpreter.stack.is_empty());
+        assert!(interpreter.history.is_empty());
+    }
+
+    #[test]
+    fn test_history_entry_for_add() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[
+            Instruction::Push(2),
+            Instruction::Push(3),
+            Instruction::Add,
+        ]);
+        interpreter.run().unwrap();
+        // Stack should be [5]
+        assert_eq!(interpreter.stack, vec![5]);
+        // History should have three entries
+        assert_eq!(interpreter.history.len(), 3);
+        // Check the last history entry
+        let last_entry = &interpreter.history.entries()[2];
+        assert_eq!(*last_entry.instruction, Instruction::Add);
+        assert_eq!(last_entry.popped_values, vec![3, 2]);
+        assert_eq!(last_entry.pushed_values, vec![5]);
+    }
+
+    #[test]
+    fn test_back_after_error() {
         let mut interpreter = Interpreter::new();
         interpreter.add_instructions(&[
+            Instruction::Push(5),
+            Instruction::Pop,
+            Instruction::Pop, // Will cause StackUnderflow
+        ]);
+        let result = interpreter.run();
+        assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::StackUnderflow));
+        // Perform back operations
+        interpreter.back().unwrap(); // Undo Pop
+        assert_eq!(interpreter.stack, vec![5]);
+        interpreter.back().unwrap(); // Undo Push(5)
+        assert_eq!(interpreter.stack, Vec::<i32>::new());
+        // No more history
+        let result = interpreter.back();
+        assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::NoInstructions));
+    }
+
+    #[test]
+    fn test_multiple_back_operations() {
+        let mut interpreter = Interpreter::new();
+        let instructions = [
             Instruction::Push(10),
-            Instruction::Push(0),
+            Instruction::Push(20),
+            Instruction::Add,
+            Instruction::Push(5),
+            Instruction::Mul,
+            Instruction::Push(1),
+            Instruction::Pop,
+            Instruction::Push(2),
+            Instruction::Push(3),
+            Instruction::Sub,
             Instruction::Div,
+        ];
+        interpreter.add_instructions(&instructions);
+        interpreter.run().unwrap();
+        // Stack should be [150]
+        assert_eq!(interpreter.stack, vec![-150]);
+        // Perform back operations
+        interpreter.back().unwrap(); // Undo Div
+        assert_eq!(interpreter.stack, vec![150, -1]);
+        interpreter.back().unwrap(); // Undo Sub
+        assert_eq!(interpreter.stack, vec![150, 2, 3]);
+        interpreter.back().unwrap(); // Undo Push 3
+        assert_eq!(interpreter.stack, vec![150, 2]);
+        interpreter.back().unwrap(); // Undo Push 2
+        assert_eq!(interpreter.stack, vec![150]);
+        interpreter.back().unwrap(); // Undo Pop
+        assert_eq!(interpreter.stack, vec![150, 1]);
+        interpreter.back().unwrap(); // Undo Push 1
+        assert_eq!(interpreter.stack, vec![150]);
+        interpreter.back().unwrap(); // Undo Mul
+        assert_eq!(interpreter.stack, vec![30, 5]);
+        interpreter.back().unwrap(); // Undo Push 5
+        assert_eq!(interpreter.stack, vec![30]);
+        interpreter.back().unwrap(); // Undo Add
+        assert_eq!(interpreter.stack, vec![10, 20]);
+        interpreter.back().unwrap(); // Undo Push 20
+        assert_eq!(interpreter.stack, vec![10]);
+        interpreter.back().unwrap(); // Undo Push 10
+        assert_eq!(interpreter.stack, Vec::<i32>::new());
+        // Attempt to back with empty history
+        let result = interpreter.back();
+        assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::NoInstructions));
+        // Program counter should be restored
+        assert_eq!(interpreter.pc, 0);
+        assert_eq!(interpreter.instructions, instructions.to_vec());
+    }
+
+    #[test]
+    fn test_history_after_error() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[
+            Instruction::Push(5),
+            Instruction::Pop,
+            Instruction::Pop, // This will cause StackUnderflow
         ]);
         let result = interpreter.run();
-        assert_eq!(result, Err(RuntimeError::DivideByZero));
-        assert_eq!(interpreter.stack, vec![10, 0], "Stack should be restored");
-        assert!(
-            interpreter.instructions.is_empty(),
-            "Instruction should have been removed"
+        assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::StackUnderflow));
+        // History should contain two entries
+        assert_eq!(interpreter.history.len(), 2);
+        assert_eq!(
+            *interpreter.history.entries()[0].instruction,
+            Instruction::Push(5)
         );
+        assert_eq!(
+            *interpreter.history.entries()[1].instruction,
+            Instruction::Pop
+        );
+        // Program counter should point at the failing instruction
+        assert_eq!(interpreter.pc, 2);
     }
 
     #[test]
-    fn test_addition_property() {
-        proptest!(|(a in any::<i32>(), b in any::<i32>())| {
+    fn test_stack_shuffling() {
+        let cases = [
+            (Instruction::Dup, vec![1, 2, 3, 3]),
+            (Instruction::Swap, vec![1, 3, 2]),
+            (Instruction::Over, vec![1, 2, 3, 2]),
+            (Instruction::Rot, vec![2, 3, 1]),
+            (Instruction::Nip, vec![1, 3]),
+            (Instruction::Tuck, vec![1, 3, 2, 3]),
+            (Instruction::DropN(2), vec![1]),
+            (Instruction::Pick(2), vec![1, 2, 3, 1]),
+            (Instruction::Roll(0), vec![1, 2, 3]),
+            (Instruction::Roll(2), vec![2, 3, 1]),
+        ];
+        for (instruction, expected) in cases {
             let mut interpreter = Interpreter::new();
             interpreter.add_instructions(&[
-                Instruction::Push(a),
-                Instruction::Push(b),
-                Instruction::Add,
+                Instruction::Push(1),
+                Instruction::Push(2),
+                Instruction::Push(3),
+                instruction.clone(),
             ]);
-            let expected_result = match a.checked_add(b) {
-                Some(sum) => Ok(sum),
-                None => Err(RuntimeError::ArithmeticOverflow),
-            };
-            let result = interpreter.run();
-            match expected_result {
-                Ok(sum) => {
-                    prop_assert_eq!(result, Ok(()));
-                    prop_assert_eq!(interpreter.stack, vec![sum]);
-                }
-                Err(err) => {
-                    prop_assert_eq!(result, Err(err));
-                    // Stack should be restored to [a, b]
-                    prop_assert_eq!(interpreter.stack, vec![a, b]);
-                }
-            }
-        });
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.stack, expected, "{:?}", instruction);
+            interpreter.back().unwrap();
+            assert_eq!(interpreter.stack, vec![1, 2, 3], "undo {:?}", instruction);
+            assert_eq!(interpreter.pc, 3);
+        }
     }
 
     #[test]
-    fn test_multiplication_property() {
-        proptest!(|(a in any::<i32>(), b in any::<i32>())| {
+    fn test_stack_shuffling_underflow() {
+        for instruction in [
+            Instruction::Rot,
+            Instruction::DropN(3),
+            Instruction::Pick(2),
+            Instruction::Roll(2),
+        ] {
             let mut interpreter = Interpreter::new();
             interpreter.add_instructions(&[
-                Instruction::Push(a),
-                Instruction::Push(b),
-                Instruction::Mul,
+                Instruction::Push(1),
+                Instruction::Push(2),
+                instruction.clone(),
             ]);
-            let expected_result = match a.checked_mul(b) {
-                Some(product) => Ok(product),
-                None => Err(RuntimeError::ArithmeticOverflow),
-            };
             let result = interpreter.run();
-            match expected_result {
-                Ok(product) => {
-                    prop_assert_eq!(result, Ok(()));
-                    prop_assert_eq!(interpreter.stack, vec![product]);
-                }
-                Err(err) => {
-                    prop_assert_eq!(result, Err(err));
-                    // Stack should be restored to [a, b]
-                    prop_assert_eq!(interpreter.stack, vec![a, b]);
-                }
-            }
+            assert_eq!(
+                result.map_err(|e| e.kind()),
+                Err(ErrorKind::StackUnderflow),
+                "{:?}",
+                instruction
+            );
+            assert_eq!(interpreter.stack, vec![1, 2], "Stack should be unchanged");
+        }
+    }
+
+    /// Sums the numbers 1 to 5 with a countdown loop:
+    /// `acc n -- acc+n n-1` until `n` reaches zero.
+    fn countdown_loop() -> Vec<Instruction> {
+        vec![
+            Instruction::Push(0),
+            Instruction::Push(5),
+            Instruction::Label("loop".to_string()),
+            Instruction::Dup,
+            Instruction::Jz("end".to_string()),
+            Instruction::Tuck,
+            Instruction::Add,
+            Instruction::Swap,
+            Instruction::Push(1),
+            Instruction::Sub,
+            Instruction::Jmp("loop".to_string()),
+            Instruction::Label("end".to_string()),
+            Instruction::Pop,
+        ]
+    }
+
+    /// A loop that counts down from `n` to zero, executing five instructions per
+    /// iteration.
+    fn countdown_from(n: i32) -> Vec<Instruction> {
+        vec![
+            Instruction::Push(n),
+            Instruction::Label("loop".to_string()),
+            Instruction::Push(1),
+            Instruction::Sub,
+            Instruction::Dup,
+            Instruction::Jnz("loop".to_string()),
+        ]
+    }
+
+    #[test]
+    fn test_history_limit() {
+        let mut unbounded = Interpreter::new();
+        unbounded.add_instructions(&countdown_from(10000));
+        unbounded.run().unwrap();
+        assert_eq!(unbounded.history_len(), 50001);
+        assert_eq!(unbounded.history_start(), 0);
+
+        let mut interpreter = Interpreter::new();
+        interpreter.set_history_limit(HistoryLimit::Entries(100));
+        interpreter.add_instructions(&countdown_from(10000));
+        interpreter.forward().unwrap();
+        interpreter.checkpoint("start");
+        interpreter.run().unwrap();
+        assert_eq!(interpreter.stack(), &vec![0]);
+        assert_eq!(interpreter.history_len(), 50001);
+        let retained = interpreter.history_len() - interpreter.history_start();
+        assert!((100..300).contains(&retained));
+        assert!(interpreter.history_bytes() * 10 < unbounded.history_bytes());
+        assert!(interpreter.checkpoints().is_empty());
+
+        // Reversing stops at the oldest retained instruction
+        interpreter.back_n(100).unwrap();
+        let result = interpreter.back_n(retained);
+        assert_eq!(
+            result.map_err(|e| e.kind()),
+            Err(ErrorKind::HistoryExhausted)
+        );
+        while interpreter.back().is_ok() {}
+        let result = interpreter.back();
+        assert_eq!(
+            result.map_err(|e| e.kind()),
+            Err(ErrorKind::HistoryExhausted)
+        );
+        assert_eq!(interpreter.history_len(), interpreter.history_start());
+        assert_eq!(interpreter.stack(), interpreter.history.snapshot());
+        assert_eq!(interpreter.continue_backward(), Ok(None));
+
+        while interpreter.redo().is_ok() {}
+        assert_eq!(interpreter.stack(), &vec![0]);
+        assert_eq!(interpreter.pc, 6);
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[
+            Instruction::Label("loop".to_string()),
+            Instruction::Jmp("loop".to_string()),
+        ]);
+        interpreter.set_limits(ExecutionLimits {
+            max_steps: Some(100),
+            ..ExecutionLimits::default()
+        });
+        let result = interpreter.run();
+        assert_eq!(
+            result.map_err(|e| e.kind()),
+            Err(ErrorKind::StepLimitExceeded)
+        );
+        assert_eq!(interpreter.history_len(), 100);
+        assert_eq!(interpreter.steps(), 100);
+        assert_eq!(interpreter.pc, 0);
+
+        // Reversing instructions does not give steps back
+        interpreter.back_n(10).unwrap();
+        assert_eq!(interpreter.steps(), 100);
+        let result = interpreter.forward();
+        assert_eq!(
+            result.map_err(|e| e.kind()),
+            Err(ErrorKind::StepLimitExceeded)
+        );
+        assert_eq!(interpreter.history_len(), 90);
+    }
+
+    #[test]
+    fn test_step_limit_back_and_forth() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[Instruction::Push(1)]);
+        interpreter.set_limits(ExecutionLimits {
+            max_steps: Some(10),
+            ..ExecutionLimits::default()
+        });
+        for _ in 0..10 {
+            interpreter.forward().unwrap();
+            interpreter.back().unwrap();
+        }
+        assert_eq!(interpreter.history_len(), 0);
+        assert_eq!(interpreter.steps(), 10);
+        let result = interpreter.forward();
+        assert_eq!(
+            result.map_err(|e| e.kind()),
+            Err(ErrorKind::StepLimitExceeded)
+        );
+    }
+
+    #[test]
+    fn test_redo_enforces_limits() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&countdown_from(10));
+        interpreter.run().unwrap();
+        let steps = interpreter.steps();
+        interpreter.set_limits(ExecutionLimits {
+            max_steps: Some(steps + 5),
+            ..ExecutionLimits::default()
+        });
+
+        // Replaying counts as executing again
+        interpreter.back_n(10).unwrap();
+        for _ in 0..5 {
+            interpreter.redo().unwrap();
+        }
+        let error = interpreter.redo().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::StepLimitExceeded);
+        assert_eq!(interpreter.steps(), steps + 5);
+        assert_eq!(interpreter.history_len(), steps - 5);
+
+        // The stack limit applies to replayed instructions too
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[Instruction::Push(1), Instruction::Push(2)]);
+        interpreter.run().unwrap();
+        interpreter.back_n(2).unwrap();
+        interpreter.set_limits(ExecutionLimits {
+            max_stack: Some(1),
+            ..ExecutionLimits::default()
+        });
+        interpreter.redo().unwrap();
+        let error = interpreter.redo().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::StackLimitExceeded);
+        assert_eq!(interpreter.stack(), &vec![1]);
+        assert_eq!(interpreter.pc, 1);
+    }
+
+    #[test]
+    fn test_stack_limit() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[
+            Instruction::Push(1),
+            Instruction::Label("loop".to_string()),
+            Instruction::Dup,
+            Instruction::Jmp("loop".to_string()),
+        ]);
+        interpreter.set_limits(ExecutionLimits {
+            max_stack: Some(10),
+            ..ExecutionLimits::default()
         });
+        let error = interpreter.run().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::StackLimitExceeded);
+        assert_eq!(
+            error.to_string(),
+            "DUP at instruction 2: stack limit exceeded (operands: 1)"
+        );
+        assert_eq!(interpreter.stack(), &vec![1; 10]);
+        assert_eq!(interpreter.pc, 2);
+
+        // Instructions that do not grow the stack still execute
+        *interpreter.current_instruction().unwrap() = Instruction::Add;
+        interpreter.forward().unwrap();
+        assert_eq!(interpreter.stack().len(), 9);
+    }
+
+    #[test]
+    fn test_history_size_limit() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&countdown_from(100));
+        interpreter.set_limits(ExecutionLimits {
+            max_history: HistoryLimit::Entries(50),
+            ..ExecutionLimits::default()
+        });
+        let result = interpreter.run();
+        assert_eq!(
+            result.map_err(|e| e.kind()),
+            Err(ErrorKind::HistoryLimitExceeded)
+        );
+        assert_eq!(interpreter.history_len(), 50);
+
+        // Discarding history makes room for more
+        interpreter.set_history_limit(HistoryLimit::Entries(10));
+        interpreter.set_limits(ExecutionLimits {
+            max_history: HistoryLimit::Entries(200),
+            ..ExecutionLimits::default()
+        });
+        interpreter.run().unwrap();
+        assert_eq!(interpreter.stack(), &vec![0]);
+        assert_eq!(interpreter.history_len(), 501);
+    }
+
+    /// Records every event as a line of text.
+    #[derive(Default)]
+    struct EventLog(Vec<String>);
+
+    impl ExecutionObserver for EventLog {
+        fn before_execute(&mut self, interpreter: &Interpreter, instruction: &Instruction) {
+            let line = format!("before {} {:?}", instruction, interpreter.stack());
+            self.0.push(line);
+        }
+
+        fn after_execute(&mut self, interpreter: &Interpreter, instruction: &Instruction) {
+            let line = format!("after {} {:?}", instruction, interpreter.stack());
+            self.0.push(line);
+        }
+
+        fn before_undo(&mut self, interpreter: &Interpreter, instruction: &Instruction) {
+            let line = format!("before undo {} {:?}", instruction, interpreter.stack());
+            self.0.push(line);
+        }
+
+        fn after_undo(&mut self, interpreter: &Interpreter, instruction: &Instruction) {
+            let line = format!("after undo {} {:?}", instruction, interpreter.stack());
+            self.0.push(line);
+        }
+
+        fn on_error(&mut self, interpreter: &Interpreter, error: &RuntimeError) {
+            let line = format!("error {} {:?}", error.kind(), interpreter.stack());
+            self.0.push(line);
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let log = Arc::new(Mutex::new(EventLog::default()));
+        let mut interpreter = Interpreter::new();
+        interpreter.add_observer(Arc::clone(&log));
+        interpreter.add_instructions(&[Instruction::Push(2), Instruction::Dup, Instruction::Div]);
+        interpreter.forward_n(2).unwrap();
+        interpreter.back().unwrap();
+        interpreter.redo().unwrap();
+        interpreter.add_instructions(&[Instruction::Div]);
+        assert!(interpreter.run().is_err());
+        assert!(interpreter.redo().is_err());
+        assert_eq!(
+            log.lock().unwrap().0,
+            vec![
+                "before PUSH 2 []",
+                "after PUSH 2 [2]",
+                "before DUP [2]",
+                "after DUP [2, 2]",
+                "before undo DUP [2, 2]",
+                "after undo DUP [2]",
+                "before DUP [2]",
+                "after DUP [2, 2]",
+                "before DIV [2, 2]",
+                "after DIV [1]",
+                "before DIV [1]",
+                "error stack underflow [1]",
+                "error nothing to redo [1]",
+            ]
+        );
+
+        // Observers are notified in the order they were added
+        let first = Arc::new(Mutex::new(EventLog::default()));
+        let mut interpreter = Interpreter::new();
+        interpreter.add_observer(Arc::clone(&first));
+        interpreter.add_observer(Arc::clone(&log));
+        interpreter.add_instructions(&[Instruction::Push(1)]);
+        interpreter.run().unwrap();
+        assert_eq!(first.lock().unwrap().0.len(), 2);
+        assert_eq!(log.lock().unwrap().0.len(), 15);
+
+        interpreter.clear_observers();
+        interpreter.back().unwrap();
+        assert_eq!(log.lock().unwrap().0.len(), 15);
+    }
+
+    /// Panics on every executed instruction.
+    struct PanickingObserver;
+
+    impl ExecutionObserver for PanickingObserver {
+        fn after_execute(&mut self, _interpreter: &Interpreter, _instruction: &Instruction) {
+            panic!("observer failed");
+        }
+    }
+
+    #[test]
+    fn test_observers_survive_panic() {
+        let log = Arc::new(Mutex::new(EventLog::default()));
+        let mut interpreter = Interpreter::new();
+        interpreter.add_observer(Arc::clone(&log));
+        interpreter.add_observer(PanickingObserver);
+        interpreter.add_instructions(&[Instruction::Push(1)]);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            interpreter.forward().unwrap();
+        }));
+        assert!(result.is_err());
+
+        // Both observers are still registered
+        assert_eq!(interpreter.observers.len(), 2);
+        interpreter.back().unwrap();
+        assert_eq!(lock(&log).0.len(), 4);
+    }
+
+    #[test]
+    fn test_history_limit_discards_branches() {
+        let mut program = vec![
+            Instruction::Push(i32::MAX),
+            Instruction::Push(1),
+            Instruction::Add,
+            Instruction::Pop,
+        ];
+        program.extend(countdown_from(200));
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&program);
+        interpreter.set_overflow_mode(OverflowMode::Wrapping);
+        interpreter.run().unwrap();
+        interpreter.back_n(interpreter.history_len() - 2).unwrap();
+
+        interpreter.set_overflow_mode(OverflowMode::Saturating);
+        interpreter.forward_n(10).unwrap();
+        assert_eq!(interpreter.branches().len(), 2);
+        assert_eq!(interpreter.shared_history_len(0, 1), Some(2));
+
+        // Once the fork is discarded the other branch can no longer be reached
+        interpreter.set_history_limit(HistoryLimit::Entries(10));
+        interpreter.run().unwrap();
+        assert!(interpreter.history_start() > 2);
+        let branches = interpreter.branches();
+        assert_eq!(branches.len(), 1);
+        assert_eq!(branches[0].id, 1);
+        assert_eq!(branches[0].len, interpreter.history_len());
+        assert_eq!(branches[0].stack, vec![0]);
+        assert_eq!(interpreter.shared_history_len(0, 1), None);
+        let result = interpreter.switch_branch(0);
+        assert_eq!(
+            result.map_err(|e| e.kind()),
+            Err(ErrorKind::HistoryExhausted)
+        );
+    }
+
+    #[test]
+    fn test_history_limit_keeps_reachable_branches() {
+        let mut program = countdown_from(200);
+        program.extend([
+            Instruction::Push(i32::MAX),
+            Instruction::Push(1),
+            Instruction::Add,
+        ]);
+        let mut interpreter = Interpreter::new();
+        interpreter.set_history_limit(HistoryLimit::Entries(10));
+        interpreter.add_instructions(&program);
+        interpreter.set_overflow_mode(OverflowMode::Wrapping);
+        interpreter.run().unwrap();
+        interpreter.back().unwrap();
+        interpreter.set_overflow_mode(OverflowMode::Saturating);
+        interpreter.forward().unwrap();
+
+        // Branches forked within the retained history can be switched between
+        let len = interpreter.history_len();
+        assert!(interpreter.history_start() > 0);
+        assert_eq!(interpreter.branches().len(), 2);
+        assert_eq!(interpreter.shared_history_len(0, 1), Some(len - 1));
+        interpreter.switch_branch(0).unwrap();
+        assert_eq!(interpreter.stack(), &vec![0, i32::MAX, 1]);
+        interpreter.redo().unwrap();
+        assert_eq!(interpreter.stack(), &vec![0, i32::MIN]);
+        interpreter.switch_branch(1).unwrap();
+        assert_eq!(interpreter.stack(), &vec![0, i32::MAX]);
     }
 
     #[test]
-    fn test_subtraction_property() {
-        proptest!(|(a in any::<i32>(), b in any::<i32>())| {
-            let mut interpreter = Interpreter::new();
-            interpreter.add_instructions(&[
-                Instruction::Push(a),
-                Instruction::Push(b),
-                Instruction::Sub,
-            ]);
-            let expected_result = match a.checked_sub(b) {
-                Some(diff) => Ok(diff),
-                None => Err(RuntimeError::ArithmeticOverflow),
-            };
-            let result = interpreter.run();
-            match expected_result {
-                Ok(diff) => {
-                    prop_assert_eq!(result, Ok(()));
-                    prop_assert_eq!(interpreter.stack, vec![diff]);
-                }
-                Err(err) => {
-                    prop_assert_eq!(result, Err(err));
-                    // Stack should be restored to [a, b]
-                    prop_assert_eq!(interpreter.stack, vec![a, b]);
-                }
-            }
-        });
+    fn test_loop_execution() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&countdown_loop());
+        interpreter.run().unwrap();
+        assert_eq!(interpreter.stack, vec![15]);
+        assert_eq!(interpreter.pc, interpreter.instructions.len());
     }
 
     #[test]
-    fn test_division_property() {
-        proptest!(|(a in any::<i32>(), b in any::<i32>())| {
+    fn test_back_reverses_control_flow() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&countdown_loop());
+        interpreter.run().unwrap();
+
+        interpreter.back().unwrap(); // Undo Pop
+        assert_eq!(interpreter.stack, vec![15, 0]);
+        interpreter.back().unwrap(); // Undo Label("end")
+        interpreter.back().unwrap(); // Undo the taken Jz
+        assert_eq!(interpreter.pc, 4);
+        assert_eq!(interpreter.stack, vec![15, 0, 0]);
+        interpreter.back().unwrap(); // Undo Dup
+        interpreter.back().unwrap(); // Undo Label("loop")
+        interpreter.back().unwrap(); // Undo Jmp("loop")
+        assert_eq!(interpreter.pc, 10);
+        assert_eq!(interpreter.stack, vec![15, 0]);
+
+        while interpreter.back().is_ok() {}
+        assert_eq!(interpreter.pc, 0);
+        assert!(interpreter.stack.is_empty());
+        assert!(interpreter.history.is_empty());
+
+        // Replaying the whole program gives the same result
+        interpreter.run().unwrap();
+        assert_eq!(interpreter.stack, vec![15]);
+    }
+
+    #[test]
+    fn test_comparison_branches() {
+        let cases = [
+            (Instruction::Jeq("t".to_string()), 2, 2, true),
+            (Instruction::Jne("t".to_string()), 2, 2, false),
+            (Instruction::Jlt("t".to_string()), 1, 2, true),
+            (Instruction::Jgt("t".to_string()), 1, 2, false),
+            (Instruction::Jle("t".to_string()), 2, 2, true),
+            (Instruction::Jge("t".to_string()), 1, 2, false),
+        ];
+        for (branch, a, b, taken) in cases {
             let mut interpreter = Interpreter::new();
             interpreter.add_instructions(&[
                 Instruction::Push(a),
                 Instruction::Push(b),
-                Instruction::Div,
+                branch.clone(),
+                Instruction::Push(100),
+                Instruction::Label("t".to_string()),
             ]);
-            let expected_result = a.checked_div(b);
-            let result = interpreter.run();
-            match expected_result {
-                Some(quotient) => {
-                    prop_assert_eq!(result, Ok(()));
-                    prop_assert_eq!(interpreter.stack, vec![quotient]);
-                }
-                None => {
-                    if b == 0 {
-                        prop_assert_eq!(result, Err(RuntimeError::DivideByZero));
-                    } else {
-                        prop_assert_eq!(result, Err(RuntimeError::ArithmeticOverflow));
-                    }
-                    // Stack should be restored to [a, b]
-                    prop_assert_eq!(interpreter.stack, vec![a, b]);
-                }
-            }
-        });
+            interpreter.run().unwrap();
+            let expected = if taken { vec![] } else { vec![100] };
+            assert_eq!(interpreter.stack, expected, "{:?}", branch);
+        }
     }
 
     #[test]
-    fn test_back_with_empty_history() {
+    fn test_undefined_label() {
         let mut interpreter = Interpreter::new();
-        let result = interpreter.back();
-        assert_eq!(result, Err(RuntimeError::NoInstructions));
+        interpreter
+            .add_instructions(&[Instruction::Push(0), Instruction::Jz("nowhere".to_string())]);
+        let result = interpreter.run();
+        assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::UndefinedLabel));
+        assert_eq!(interpreter.stack, vec![0], "Stack should be unchanged");
+        assert_eq!(interpreter.pc, 1);
     }
 
     #[test]
-    fn test_forward_with_empty_instructions() {
+    fn test_labels_follow_program_changes() {
         let mut interpreter = Interpreter::new();
-        let result = interpreter.forward();
-        assert_eq!(result, Err(RuntimeError::NoInstructions));
+        interpreter.add_instructions(&[
+            Instruction::Jmp("end".to_string()),
+            Instruction::Push(1),
+            Instruction::Label("end".to_string()),
+        ]);
+        interpreter.forward().unwrap();
+        assert_eq!(interpreter.pc, 2);
+
+        // A label added later is found
+        interpreter.add_instructions(&[
+            Instruction::Jmp("later".to_string()),
+            Instruction::Label("later".to_string()),
+            Instruction::Label("end".to_string()),
+            Instruction::Jmp("end".to_string()),
+        ]);
+        interpreter.forward_n(2).unwrap();
+        assert_eq!(interpreter.pc, 4);
+
+        // Once the first `end` label is replaced, jumps go to the second one
+        interpreter.back_n(2).unwrap();
+        *interpreter.current_instruction().unwrap() = Instruction::Push(2);
+        interpreter.forward_n(5).unwrap();
+        assert_eq!(interpreter.pc, 5);
+    }
+
+    /// Computes `square(3) + square(4)` with a subroutine.
+    fn square_program() -> Vec<Instruction> {
+        vec![
+            Instruction::Push(3),
+            Instruction::Call("square".to_string()),
+            Instruction::Push(4),
+            Instruction::Call("square".to_string()),
+            Instruction::Add,
+            Instruction::Jmp("end".to_string()),
+            Instruction::Label("square".to_string()),
+            Instruction::Dup,
+            Instruction::Mul,
+            Instruction::Ret,
+            Instruction::Label("end".to_string()),
+        ]
     }
 
     #[test]
-    fn test_run_with_empty_instructions() {
+    fn test_back_n_and_forward_n() {
         let mut interpreter = Interpreter::new();
-        let result = interpreter.run();
-        assert_eq!(result, Ok(()));
-        assert!(interpreter.stack.is_empty());
-        assert!(interpreter.history.is_empty());
+        interpreter.add_instructions(&countdown_loop());
+        interpreter.forward_n(8).unwrap();
+        assert_eq!(interpreter.history_len(), 8);
+        assert_eq!(interpreter.stack, vec![5, 5]);
+
+        interpreter.back_n(3).unwrap();
+        assert_eq!(interpreter.history_len(), 5);
+        assert_eq!(interpreter.pc, 5);
+        assert_eq!(interpreter.stack, vec![0, 5]);
+
+        // Reversing more than was executed fails without reversing anything
+        let result = interpreter.back_n(6);
+        assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::NoInstructions));
+        assert_eq!(interpreter.history_len(), 5);
+
+        // Executing past the end keeps the instructions executed before it
+        let result = interpreter.forward_n(1000);
+        assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::NoInstructions));
+        assert_eq!(interpreter.stack, vec![15]);
+        interpreter.back_n(interpreter.history_len()).unwrap();
+        assert_eq!(interpreter.stack, Vec::<i32>::new());
+        assert_eq!(interpreter.pc, 0);
     }
 
     #[test]
-    fn test_history_entry_for_add() {
+    fn test_checkpoints() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&square_program());
+        interpreter.forward_n(3).unwrap();
+        interpreter.checkpoint("in_call");
+        assert_eq!(
+            interpreter.checkpoints().get("in_call"),
+            Some(&Checkpoint {
+                branch: 0,
+                position: 3
+            })
+        );
+        let (stack, pc) = (interpreter.stack.clone(), interpreter.pc);
+        assert_eq!(interpreter.call_stack(), &[2]);
+
+        // Rewind to a checkpoint after running to the end
+        interpreter.run().unwrap();
+        interpreter.checkpoint("end");
+        interpreter.restore("in_call").unwrap();
+        assert_eq!(interpreter.stack, stack);
+        assert_eq!(interpreter.pc, pc);
+        assert_eq!(interpreter.call_stack(), &[2]);
+
+        // Re-advance to a checkpoint after reversing past it
+        interpreter.back_n(3).unwrap();
+        interpreter.restore("end").unwrap();
+        assert_eq!(interpreter.stack, vec![25]);
+        assert_eq!(interpreter.pc, interpreter.instructions.len());
+
+        // Recording a checkpoint again moves it
+        interpreter.back_n(2).unwrap();
+        interpreter.checkpoint("end");
+        interpreter.restore("in_call").unwrap();
+        interpreter.restore("end").unwrap();
+        assert_eq!(interpreter.pc, 5);
+
+        let result = interpreter.restore("missing");
+        assert_eq!(
+            result.map_err(|e| e.kind()),
+            Err(ErrorKind::UndefinedCheckpoint)
+        );
+    }
+
+    #[test]
+    fn test_restore_checkpoint_on_other_branch() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&square_program());
+        interpreter.run().unwrap();
+        interpreter.back_n(4).unwrap();
+        interpreter.checkpoint("returning");
+        let (stack, pc) = (interpreter.stack.clone(), interpreter.pc);
+        interpreter.redo().unwrap();
+        interpreter.checkpoint("adding");
+
+        // Replacing the current instruction forks a new branch
+        *interpreter.current_instruction().unwrap() = Instruction::Sub;
+        interpreter.run().unwrap();
+        assert_eq!(interpreter.current_branch(), 1);
+        assert_eq!(interpreter.stack, vec![-7]);
+
+        interpreter.restore("adding").unwrap();
+        assert_eq!(interpreter.current_branch(), 0);
+        assert_eq!(interpreter.instructions[4], Instruction::Add);
+        interpreter.restore("returning").unwrap();
+        assert_eq!(interpreter.stack, stack);
+        assert_eq!(interpreter.pc, pc);
+        assert_eq!(interpreter.call_stack(), &[4]);
+        interpreter.run().unwrap();
+        assert_eq!(interpreter.stack, vec![25]);
+        assert_eq!(interpreter.branches().len(), 2);
+    }
+
+    #[test]
+    fn test_redo() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&square_program());
+        interpreter.run().unwrap();
+        interpreter.back_n(4).unwrap();
+        assert_eq!(interpreter.call_stack(), &[4]);
+        assert_eq!(interpreter.redo_len(), 4);
+
+        // Redo replays the undone instructions, including their effects
+        assert_eq!(interpreter.redo(), Ok(Instruction::Ret));
+        assert_eq!(interpreter.call_stack(), &[] as &[usize]);
+        assert_eq!(interpreter.pc, 4);
+        assert_eq!(interpreter.stack, vec![9, 16]);
+
+        // Re-executing the next undone instruction keeps the rest of the future
+        assert_eq!(interpreter.forward(), Ok(Instruction::Add));
+        assert_eq!(interpreter.redo_len(), 2);
+
+        // Adding instructions does not affect the undone future
+        interpreter.add_instructions(&[Instruction::Push(1)]);
+        interpreter.redo().unwrap();
+        interpreter.redo().unwrap();
+        assert_eq!(interpreter.stack, vec![25]);
+        assert_eq!(interpreter.pc, 11);
+        let result = interpreter.redo();
+        assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::NothingToRedo));
+    }
+
+    #[test]
+    fn test_redo_after_divergence() {
         let mut interpreter = Interpreter::new();
         interpreter.add_instructions(&[
-            Instruction::Push(2),
-            Instruction::Push(3),
+            Instruction::Push(i32::MAX),
+            Instruction::Push(1),
             Instruction::Add,
+            Instruction::Store("x".to_string()),
         ]);
+        interpreter.set_overflow_mode(OverflowMode::Wrapping);
         interpreter.run().unwrap();
-        // Stack should be [5]
-        assert_eq!(interpreter.stack, vec![5]);
-        // History should have three entries
-        assert_eq!(interpreter.history.len(), 3);
-        // Check the last history entry
-        let last_entry = &interpreter.history[2];
-        assert_eq!(last_entry.instruction, Instruction::Add);
-        assert_eq!(last_entry.popped_values, vec![3, 2]);
-        assert_eq!(last_entry.pushed_values, vec![5]);
+        interpreter.back_n(2).unwrap();
+
+        // Executing the ADD with a different result moves the redo stack to a branch
+        interpreter.set_overflow_mode(OverflowMode::Saturating);
+        interpreter.forward().unwrap();
+        assert_eq!(interpreter.stack, vec![i32::MAX]);
+        assert_eq!(interpreter.redo_len(), 0);
+        let result = interpreter.redo();
+        assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::NothingToRedo));
+
+        // Changing the current instruction discards the undone future
+        interpreter.forward().unwrap();
+        interpreter.back_n(2).unwrap();
+        *interpreter.current_instruction().unwrap() = Instruction::Sub;
+        let result = interpreter.redo();
+        assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::NothingToRedo));
+        assert_eq!(interpreter.redo_len(), 0);
+        assert_eq!(interpreter.stack, vec![i32::MAX, 1]);
     }
 
     #[test]
-    fn test_back_after_error() {
+    fn test_branches() {
         let mut interpreter = Interpreter::new();
         interpreter.add_instructions(&[
-            Instruction::Push(5),
-            Instruction::Pop,
-            Instruction::Pop, // Will cause StackUnderflow
+            Instruction::Push(i32::MAX),
+            Instruction::Push(1),
+            Instruction::Add,
+            Instruction::Push(2),
+            Instruction::Sub,
         ]);
-        let result = interpreter.run();
-        assert_eq!(result, Err(RuntimeError::StackUnderflow));
-        // Perform back operations
-        interpreter.back().unwrap(); // Undo Pop
-        assert_eq!(interpreter.stack, vec![5]);
-        interpreter.back().unwrap(); // Undo Push(5)
-        assert_eq!(interpreter.stack, vec![]);
-        // No more history
-        let result = interpreter.back();
-        assert_eq!(result, Err(RuntimeError::NoInstructions));
+        interpreter.set_overflow_mode(OverflowMode::Wrapping);
+        interpreter.run().unwrap();
+        interpreter.back_n(3).unwrap();
+
+        // Diverging keeps the undone future on the original branch
+        interpreter.set_overflow_mode(OverflowMode::Saturating);
+        interpreter.run().unwrap();
+        assert_eq!(interpreter.current_branch(), 1);
+        assert_eq!(
+            interpreter.branches(),
+            vec![
+                BranchSummary {
+                    id: 0,
+                    parent: None,
+                    fork: 0,
+                    position: 2,
+                    len: 5,
+                    stack: vec![i32::MAX - 1],
+                },
+                BranchSummary {
+                    id: 1,
+                    parent: Some(0),
+                    fork: 2,
+                    position: 5,
+                    len: 5,
+                    stack: vec![i32::MAX - 2],
+                },
+            ]
+        );
+        assert_eq!(interpreter.shared_history_len(0, 1), Some(2));
+        assert_eq!(interpreter.shared_history_len(0, 2), None);
+
+        // Switching returns to the position the branch was left at
+        interpreter.switch_branch(0).unwrap();
+        assert_eq!(interpreter.stack, vec![i32::MAX, 1]);
+        assert_eq!(interpreter.redo_len(), 3);
+        interpreter.redo().unwrap();
+        interpreter.redo().unwrap();
+        interpreter.redo().unwrap();
+        assert_eq!(interpreter.stack, vec![i32::MAX - 1]);
+
+        interpreter.switch_branch(1).unwrap();
+        assert_eq!(interpreter.stack, vec![i32::MAX - 2]);
+        assert_eq!(interpreter.pc, 5);
+        assert_eq!(interpreter.redo_len(), 0);
+        assert_eq!(interpreter.branches()[0].position, 5);
+
+        let result = interpreter.switch_branch(2);
+        assert_eq!(
+            result.map_err(|e| e.kind()),
+            Err(ErrorKind::UndefinedBranch)
+        );
     }
 
     #[test]
-    fn test_multiple_back_operations() {
+    fn test_branches_of_long_history() {
         let mut interpreter = Interpreter::new();
-        let instructions = [
-            Instruction::Push(10),
-            Instruction::Push(20),
-            Instruction::Add,
-            Instruction::Push(5),
-            Instruction::Mul,
-            Instruction::Push(1),
-            Instruction::Pop,
+        interpreter.set_history_limit(HistoryLimit::Entries(3000));
+        interpreter.add_instructions(&countdown_from(1000));
+        interpreter.run().unwrap();
+        let len = interpreter.history_len();
+        interpreter.back_n(1502).unwrap();
+        let stack = interpreter.stack.clone();
+        *interpreter.current_instruction().unwrap() = Instruction::Push(7);
+        interpreter.forward().unwrap();
+
+        let branches = interpreter.branches();
+        assert_eq!(branches[0].len, len);
+        assert_eq!(branches[0].stack, vec![0]);
+        assert_eq!(branches[1].len, len - 1501);
+        assert_eq!(branches[1].stack, [stack, vec![7]].concat());
+        assert_eq!(interpreter.shared_history_len(0, 1), Some(len - 1502));
+
+        let mut expected = Interpreter::new();
+        expected.add_instructions(&countdown_from(1000));
+        expected.forward_n(len - 1000).unwrap();
+        assert_eq!(interpreter.branch_stack(0, len - 1000), expected.stack);
+    }
+
+    #[test]
+    fn test_branches_keep_only_abandoned_entries() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&countdown_loop());
+        interpreter.run().unwrap();
+        let len = interpreter.history_len();
+        let stack = interpreter.stack.clone();
+        interpreter.back_n(2).unwrap();
+        *interpreter.current_instruction().unwrap() = Instruction::Push(1);
+        interpreter.forward().unwrap();
+        interpreter.back_n(3).unwrap();
+        *interpreter.current_instruction().unwrap() = Instruction::Push(2);
+        interpreter.forward().unwrap();
+
+        // Forking stores the reversed future, not the shared history
+        assert_eq!(interpreter.current_branch(), 2);
+        assert_eq!(interpreter.branches[0].entries.len(), 2);
+        assert_eq!(interpreter.branches[1].entries.len(), 3);
+
+        let branches = interpreter.branches();
+        assert_eq!(branches[0].len, len);
+        assert_eq!(branches[0].stack, stack);
+        assert_eq!(branches[1].len, len - 1);
+        assert_eq!(interpreter.shared_history_len(0, 1), Some(len - 2));
+        assert_eq!(interpreter.shared_history_len(1, 2), Some(len - 4));
+
+        interpreter.switch_branch(0).unwrap();
+        interpreter.switch_branch(1).unwrap();
+        interpreter.switch_branch(0).unwrap();
+        while interpreter.redo().is_ok() {}
+        assert_eq!(interpreter.stack, stack);
+        let mut expected = branches;
+        expected[0].position = len;
+        expected[1].position = len - 2;
+        assert_eq!(interpreter.branches(), expected);
+    }
+
+    #[test]
+    fn test_branch_on_changed_instruction() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[
             Instruction::Push(2),
             Instruction::Push(3),
-            Instruction::Sub,
-            Instruction::Div,
-        ];
-        interpreter.add_instructions(&instructions);
+            Instruction::Add,
+        ]);
         interpreter.run().unwrap();
-        // Stack should be [150]
-        assert_eq!(interpreter.stack, vec![-150]);
-        // Perform back operations
-        interpreter.back().unwrap(); // Undo Div
-        assert_eq!(interpreter.stack, vec![150, -1]);
-        interpreter.back().unwrap(); // Undo Sub
-        assert_eq!(interpreter.stack, vec![150, 2, 3]);
-        interpreter.back().unwrap(); // Undo Push 3
-        assert_eq!(interpreter.stack, vec![150, 2]);
-        interpreter.back().unwrap(); // Undo Push 2
-        assert_eq!(interpreter.stack, vec![150]);
-        interpreter.back().unwrap(); // Undo Pop
-        assert_eq!(interpreter.stack, vec![150, 1]);
-        interpreter.back().unwrap(); // Undo Push 1
-        assert_eq!(interpreter.stack, vec![150]);
-        interpreter.back().unwrap(); // Undo Mul
-        assert_eq!(interpreter.stack, vec![30, 5]);
-        interpreter.back().unwrap(); // Undo Push 5
-        assert_eq!(interpreter.stack, vec![30]);
-        interpreter.back().unwrap(); // Undo Add
-        assert_eq!(interpreter.stack, vec![10, 20]);
-        interpreter.back().unwrap(); // Undo Push 20
-        assert_eq!(interpreter.stack, vec![10]);
-        interpreter.back().unwrap(); // Undo Push 10
-        assert_eq!(interpreter.stack, vec![]);
-        // Attempt to back with empty history
-        let result = interpreter.back();
-        assert_eq!(result, Err(RuntimeError::NoInstructions));
-        // Instructions should be restored
-        assert_eq!(interpreter.instructions, VecDeque::from(instructions));
+        interpreter.back_n(2).unwrap();
+        *interpreter.current_instruction().unwrap() = Instruction::Push(4);
+
+        // A redo that no longer matches the program forks instead of discarding
+        let result = interpreter.redo();
+        assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::NothingToRedo));
+        assert_eq!(interpreter.current_branch(), 1);
+        assert_eq!(interpreter.redo_len(), 0);
+        interpreter.run().unwrap();
+
+        let branches = interpreter.branches();
+        assert_eq!(branches[0].stack, vec![5]);
+        assert_eq!(branches[0].len, 3);
+        assert_eq!(branches[1].stack, vec![6]);
+        assert_eq!(branches[1].fork, 1);
+        assert_eq!(interpreter.shared_history_len(1, 0), Some(1));
     }
 
     #[test]
-    fn test_history_after_error() {
+    fn test_breakpoints() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&countdown_loop());
+        interpreter.add_breakpoint(Breakpoint::Label("loop".to_string()));
+        interpreter.add_breakpoint(Breakpoint::Instruction(12));
+        interpreter.add_breakpoint(Breakpoint::Instruction(12));
+        assert_eq!(interpreter.breakpoints().len(), 2);
+
+        // Stops before the label on entry and after every jump back to it
+        let loop_stop = Some(Stop::Breakpoint(Breakpoint::Label("loop".to_string())));
+        assert_eq!(interpreter.continue_forward(), Ok(loop_stop.clone()));
+        assert_eq!((interpreter.pc, interpreter.stack.clone()), (2, vec![0, 5]));
+        assert_eq!(interpreter.continue_forward(), Ok(loop_stop.clone()));
+        assert_eq!((interpreter.pc, interpreter.stack.clone()), (2, vec![5, 4]));
+
+        // Reverse-continue goes back to the previous iteration
+        assert_eq!(interpreter.continue_backward(), Ok(loop_stop.clone()));
+        assert_eq!((interpreter.pc, interpreter.stack.clone()), (2, vec![0, 5]));
+
+        assert!(interpreter.remove_breakpoint(&Breakpoint::Label("loop".to_string())));
+        assert!(!interpreter.remove_breakpoint(&Breakpoint::Label("loop".to_string())));
+        assert_eq!(
+            interpreter.continue_forward(),
+            Ok(Some(Stop::Breakpoint(Breakpoint::Instruction(12))))
+        );
+        assert_eq!(interpreter.stack, vec![15, 0]);
+        assert_eq!(interpreter.continue_forward(), Ok(None));
+        assert_eq!(interpreter.stack, vec![15]);
+
+        // Reversing everything without another breakpoint returns to the start
+        interpreter.remove_breakpoint(&Breakpoint::Instruction(12));
+        assert_eq!(interpreter.continue_backward(), Ok(None));
+        assert_eq!(interpreter.pc, 0);
+        assert_eq!(interpreter.continue_backward(), Ok(None));
+    }
+
+    #[test]
+    fn test_watchpoints() {
         let mut interpreter = Interpreter::new();
         interpreter.add_instructions(&[
-            Instruction::Push(5),
-            Instruction::Pop,
-            Instruction::Pop, // This will cause StackUnderflow
+            Instruction::Push(1),
+            Instruction::Store("x".to_string()),
+            Instruction::Push(2),
+            Instruction::Push(3),
+            Instruction::Add,
+            Instruction::Dup,
+            Instruction::Store("x".to_string()),
         ]);
-        let result = interpreter.run();
-        assert_eq!(result, Err(RuntimeError::StackUnderflow));
-        // History should contain two entries
-        assert_eq!(interpreter.history.len(), 2);
-        assert_eq!(interpreter.history[0].instruction, Instruction::Push(5));
-        assert_eq!(interpreter.history[1].instruction, Instruction::Pop);
-        // Instructions queue should be empty
-        assert!(interpreter.instructions.is_empty());
+        interpreter.add_watchpoint(Watchpoint::StackDepth(2));
+        interpreter.add_watchpoint(Watchpoint::TopOfStack(5));
+        interpreter.add_watchpoint(Watchpoint::Variable("x".to_string()));
+
+        let depth = Some(Stop::Watchpoint(Watchpoint::StackDepth(2)));
+        let top = Some(Stop::Watchpoint(Watchpoint::TopOfStack(5)));
+        let variable = Some(Stop::Watchpoint(Watchpoint::Variable("x".to_string())));
+        assert_eq!(interpreter.continue_forward(), Ok(variable.clone()));
+        assert_eq!(interpreter.pc, 2);
+        assert_eq!(interpreter.continue_forward(), Ok(depth.clone()));
+        assert_eq!(interpreter.pc, 4);
+        assert_eq!(interpreter.continue_forward(), Ok(top.clone()));
+        assert_eq!(interpreter.stack, vec![5]);
+        // DUP makes the depth 2 again; the top stays 5 so only the depth triggers
+        assert_eq!(interpreter.continue_forward(), Ok(depth.clone()));
+        assert_eq!(interpreter.continue_forward(), Ok(variable.clone()));
+        assert_eq!(interpreter.variables.get("x"), Some(&5));
+        assert_eq!(interpreter.continue_forward(), Ok(None));
+
+        // Backwards, watchpoints trigger on the reversed instruction that changes them.
+        // Reversing the STORE triggers both the depth and the variable watchpoints, and
+        // the one added first is reported.
+        assert_eq!(interpreter.continue_backward(), Ok(depth.clone()));
+        assert_eq!(interpreter.pc, 6);
+        assert_eq!(interpreter.variables.get("x"), Some(&1));
+        assert_eq!(interpreter.continue_backward(), Ok(depth.clone()));
+        assert_eq!(interpreter.pc, 4);
+        assert_eq!(interpreter.stack, vec![2, 3]);
+
+        assert!(interpreter.remove_watchpoint(&Watchpoint::StackDepth(2)));
+        assert_eq!(interpreter.watchpoints().len(), 2);
+        assert_eq!(interpreter.continue_backward(), Ok(variable));
+        assert_eq!(interpreter.pc, 1);
+    }
+
+    #[test]
+    fn test_continue_stops_on_error() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[Instruction::Push(1), Instruction::Add]);
+        interpreter.add_breakpoint(Breakpoint::Instruction(5));
+        let result = interpreter.continue_forward();
+        assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::StackUnderflow));
+        assert_eq!(interpreter.pc, 1);
+        assert_eq!(
+            Stop::Breakpoint(Breakpoint::Label("a".to_string())).to_string(),
+            "breakpoint at label a"
+        );
+        assert_eq!(
+            Stop::Watchpoint(Watchpoint::TopOfStack(-1)).to_string(),
+            "watchpoint on top of stack -1"
+        );
+    }
+
+    #[test]
+    fn test_call_and_return() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&square_program());
+        interpreter.run().unwrap();
+        assert_eq!(interpreter.stack, vec![25]);
+        assert!(interpreter.call_stack().is_empty());
+    }
+
+    #[test]
+    fn test_back_across_call_and_return() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&square_program());
+        for _ in 0..6 {
+            interpreter.forward().unwrap(); // Up to and including the first Ret
+        }
+        assert_eq!(interpreter.pc, 2);
+        assert!(interpreter.call_stack().is_empty());
+
+        interpreter.back().unwrap(); // Undo Ret: re-enter the callee
+        assert_eq!(interpreter.pc, 9);
+        assert_eq!(interpreter.call_stack(), &[2]);
+        assert_eq!(interpreter.stack, vec![9]);
+
+        for _ in 0..3 {
+            interpreter.back().unwrap(); // Undo Mul, Dup and Label
+        }
+        interpreter.back().unwrap(); // Undo Call: return to the caller
+        assert_eq!(interpreter.pc, 1);
+        assert!(interpreter.call_stack().is_empty());
+        assert_eq!(interpreter.stack, vec![3]);
+    }
+
+    #[test]
+    fn test_return_without_call() {
+        let mut interpreter = Interpreter::new();
+        interpreter.add_instructions(&[Instruction::Ret]);
+        assert_eq!(
+            interpreter.run().map_err(|e| e.kind()),
+            Err(ErrorKind::CallStackUnderflow)
+        );
+        assert_eq!(interpreter.pc, 0);
     }
 }
@@ -1,2 +1,6 @@
+pub mod assembler;
+pub mod bytecode;
 pub mod cli;
+mod history;
 pub mod interpreter;
+pub mod trace;
@@ -0,0 +1,357 @@
+//! This module implements execution traces: a `Tracer` is an `ExecutionObserver`
+//! that records every instruction an interpreter executes, with the stack before
+//! and after it, so that what a program did can be audited afterwards.
+//!
+//! A trace renders as aligned text for reading, or as JSON Lines or CSV for other
+//! tools. Every format has one line per step and nothing that varies between runs,
+//! so two runs of the same program can be compared with `diff`.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use reversible_interpreter::interpreter::{Instruction, Interpreter};
+//! use reversible_interpreter::trace::{TraceFormat, Tracer};
+//! use std::sync::{Arc, Mutex};
+//!
+//! let tracer = Arc::new(Mutex::new(Tracer::new()));
+//! let mut interpreter = Interpreter::new();
+//! interpreter.add_observer(Arc::clone(&tracer));
+//! interpreter.add_instructions(&[Instruction::Push(2), Instruction::Dup, Instruction::Mul]);
+//! interpreter.run().unwrap();
+//!
+//! let tracer = tracer.lock().unwrap();
+//! assert_eq!(tracer.steps().len(), 3);
+//! assert_eq!(
+//!     tracer.render(TraceFormat::Text),
+//!     "STEP  PC  INSTRUCTION  BEFORE  AFTER\n\
+//!      0     0   PUSH 2       []      [2]\n\
+//!      1     1   DUP          [2]     [2, 2]\n\
+//!      2     2   MUL          [2, 2]  [4]\n"
+//! );
+//! ```
+
+use std::fmt;
+
+use crate::interpreter::{ErrorKind, ExecutionObserver, Instruction, Interpreter, RuntimeError};
+
+/// One executed instruction in a trace:
+/// - `step`: The position of the step in the trace, counting from zero.
+/// - `pc`: The program counter, i.e. the index of the instruction in the program.
+/// - `instruction`: The executed instruction.
+/// - `before`: The stack before the instruction, bottom to top.
+/// - `after`: The stack after the instruction, or the error it failed with, in
+///   which case the stack is unchanged.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceStep {
+    pub step: usize,
+    pub pc: usize,
+    pub instruction: Instruction,
+    pub before: Vec<i32>,
+    pub after: Result<Vec<i32>, ErrorKind>,
+}
+
+/// How a trace is rendered:
+/// - `Text`: Columns aligned for reading, under a header line. This is the default.
+/// - `JsonLines`: One JSON object per step, with the error in place of the stack
+///   after a failing instruction.
+/// - `Csv`: Comma-separated values under a header line, with an empty `after` and
+///   the error in the last column for a failing instruction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TraceFormat {
+    #[default]
+    Text,
+    JsonLines,
+    Csv,
+}
+
+impl fmt::Display for TraceFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TraceFormat::Text => "text",
+            TraceFormat::JsonLines => "jsonl",
+            TraceFormat::Csv => "csv",
+        };
+        f.write_str(name)
+    }
+}
+
+impl std::str::FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(TraceFormat::Text),
+            "jsonl" => Ok(TraceFormat::JsonLines),
+            "csv" => Ok(TraceFormat::Csv),
+            _ => Err(format!(
+                "Invalid trace format '{}': expected text, jsonl or csv",
+                s
+            )),
+        }
+    }
+}
+
+/// Records the instructions executed by the interpreter it is registered on, see
+/// `Interpreter::add_observer`. Instructions replayed with `.redo()` are recorded
+/// as they are executed again; reversing instructions does not remove them.
+#[derive(Debug, Default)]
+pub struct Tracer {
+    steps: Vec<TraceStep>,
+    pending: Option<(usize, Instruction, Vec<i32>)>,
+}
+
+impl Tracer {
+    /// Constructs a tracer with an empty trace.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the recorded steps, in the order they were executed.
+    pub fn steps(&self) -> &[TraceStep] {
+        &self.steps
+    }
+
+    /// Renders the recorded steps in `format`, one line per step.
+    pub fn render(&self, format: TraceFormat) -> String {
+        match format {
+            TraceFormat::Text => self.render_text(),
+            TraceFormat::JsonLines => self.render_json_lines(),
+            TraceFormat::Csv => self.render_csv(),
+        }
+    }
+
+    fn render_text(&self) -> String {
+        let mut rows = vec![["STEP", "PC", "INSTRUCTION", "BEFORE", "AFTER"].map(String::from)];
+        rows.extend(self.steps.iter().map(|step| {
+            let after = match &step.after {
+                Ok(stack) => format!("{:?}", stack),
+                Err(kind) => format!("error: {}", kind),
+            };
+            [
+                step.step.to_string(),
+                step.pc.to_string(),
+                step.instruction.to_string(),
+                format!("{:?}", step.before),
+                after,
+            ]
+        }));
+
+        let mut widths = [0; 5];
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+        let mut text = String::new();
+        for row in &rows {
+            let line = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:<width$}", cell))
+                .collect::<Vec<_>>()
+                .join("  ");
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+
+    fn render_json_lines(&self) -> String {
+        let mut text = String::new();
+        for step in &self.steps {
+            let outcome = match &step.after {
+                Ok(stack) => format!("\"after\":{}", json_array(stack)),
+                Err(kind) => format!("\"error\":{}", json_string(&kind.to_string())),
+            };
+            text.push_str(&format!(
+                "{{\"step\":{},\"pc\":{},\"instruction\":{},\"before\":{},{}}}\n",
+                step.step,
+                step.pc,
+                json_string(&step.instruction.to_string()),
+                json_array(&step.before),
+                outcome
+            ));
+        }
+        text
+    }
+
+    fn render_csv(&self) -> String {
+        let mut text = String::from("step,pc,instruction,before,after,error\n");
+        for step in &self.steps {
+            let (after, error) = match &step.after {
+                Ok(stack) => (format!("{:?}", stack), String::new()),
+                Err(kind) => (String::new(), kind.to_string()),
+            };
+            let fields = [
+                step.step.to_string(),
+                step.pc.to_string(),
+                step.instruction.to_string(),
+                format!("{:?}", step.before),
+                after,
+                error,
+            ];
+            let line = fields.map(|field| csv_field(&field)).join(",");
+            text.push_str(&line);
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Appends the step of the instruction being executed, if any.
+    fn record(&mut self, after: Result<Vec<i32>, ErrorKind>) {
+        if let Some((pc, instruction, before)) = self.pending.take() {
+            self.steps.push(TraceStep {
+                step: self.steps.len(),
+                pc,
+                instruction,
+                before,
+                after,
+            });
+        }
+    }
+}
+
+impl ExecutionObserver for Tracer {
+    fn before_execute(&mut self, interpreter: &Interpreter, instruction: &Instruction) {
+        let before = interpreter.stack().clone();
+        self.pending = Some((interpreter.pc(), instruction.clone(), before));
+    }
+
+    fn after_execute(&mut self, interpreter: &Interpreter, _instruction: &Instruction) {
+        self.record(Ok(interpreter.stack().clone()));
+    }
+
+    /// Records the failing instruction, unless the error did not come from executing
+    /// one, for example when there was nothing to reverse.
+    fn on_error(&mut self, _interpreter: &Interpreter, error: &RuntimeError) {
+        self.record(Err(error.kind()));
+    }
+}
+
+/// Formats values as a JSON array.
+fn json_array(values: &[i32]) -> String {
+    let values: Vec<String> = values.iter().map(i32::to_string).collect();
+    format!("[{}]", values.join(","))
+}
+
+/// Formats text as a JSON string, escaping quotes, backslashes and control characters.
+fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Quotes a CSV field if it contains a comma, a quote or a line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Runs `instructions` with a tracer registered, returning the tracer.
+    fn trace(instructions: &[Instruction]) -> Tracer {
+        let tracer = Arc::new(Mutex::new(Tracer::new()));
+        let mut interpreter = Interpreter::new();
+        interpreter.add_observer(Arc::clone(&tracer));
+        interpreter.add_instructions(instructions);
+        let _ = interpreter.run();
+        interpreter.clear_observers();
+        Arc::try_unwrap(tracer).unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_tracer_records_steps() {
+        let tracer = trace(&[Instruction::Push(1), Instruction::Push(0), Instruction::Div]);
+        assert_eq!(
+            tracer.steps(),
+            &[
+                TraceStep {
+                    step: 0,
+                    pc: 0,
+                    instruction: Instruction::Push(1),
+                    before: vec![],
+                    after: Ok(vec![1]),
+                },
+                TraceStep {
+                    step: 1,
+                    pc: 1,
+                    instruction: Instruction::Push(0),
+                    before: vec![1],
+                    after: Ok(vec![1, 0]),
+                },
+                TraceStep {
+                    step: 2,
+                    pc: 2,
+                    instruction: Instruction::Div,
+                    before: vec![1, 0],
+                    after: Err(ErrorKind::DivideByZero),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let tracer = trace(&[
+            Instruction::Label("start".to_string()),
+            Instruction::Push(12),
+            Instruction::Pop,
+            Instruction::Pop,
+        ]);
+        assert_eq!(
+            tracer.render(TraceFormat::Text),
+            "STEP  PC  INSTRUCTION  BEFORE  AFTER\n\
+             0     0   start:       []      []\n\
+             1     1   PUSH 12      []      [12]\n\
+             2     2   POP          [12]    []\n\
+             3     3   POP          []      error: stack underflow\n"
+        );
+        assert_eq!(
+            tracer.render(TraceFormat::JsonLines),
+            "{\"step\":0,\"pc\":0,\"instruction\":\"start:\",\"before\":[],\"after\":[]}\n\
+             {\"step\":1,\"pc\":1,\"instruction\":\"PUSH 12\",\"before\":[],\"after\":[12]}\n\
+             {\"step\":2,\"pc\":2,\"instruction\":\"POP\",\"before\":[12],\"after\":[]}\n\
+             {\"step\":3,\"pc\":3,\"instruction\":\"POP\",\"before\":[],\"error\":\"stack underflow\"}\n"
+        );
+        assert_eq!(
+            tracer.render(TraceFormat::Csv),
+            "step,pc,instruction,before,after,error\n\
+             0,0,start:,[],[],\n\
+             1,1,PUSH 12,[],[12],\n\
+             2,2,POP,[12],[],\n\
+             3,3,POP,[],,stack underflow\n"
+        );
+    }
+
+    #[test]
+    fn test_render_escapes() {
+        assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+        assert_eq!(csv_field("[1, 2]"), "\"[1, 2]\"");
+        assert_eq!(csv_field("a\"b"), "\"a\"\"b\"");
+        assert_eq!(csv_field("PUSH 1"), "PUSH 1");
+    }
+
+    #[test]
+    fn test_trace_format_from_str() {
+        assert_eq!("TEXT".parse(), Ok(TraceFormat::Text));
+        assert_eq!("jsonl".parse(), Ok(TraceFormat::JsonLines));
+        assert_eq!("csv".parse(), Ok(TraceFormat::Csv));
+        assert!("json".parse::<TraceFormat>().is_err());
+        assert_eq!(TraceFormat::JsonLines.to_string(), "jsonl");
+    }
+}
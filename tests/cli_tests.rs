@@ -22,7 +22,31 @@ fn test_cli_script_mode_error() {
         .write_stdin("add PUSH 5; DIV\nrun\n")
         .assert()
         .failure()
-        .stdout(predicate::str::contains("Error: StackUnderflow"));
+        .stdout(predicate::str::contains(
+            "Error: DIV at line 1: stack underflow (operands: 5)",
+        ));
+}
+
+#[test]
+fn test_cli_script_missing_file() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["script", "--file", "tests/programs/missing.txt"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::starts_with(
+            "Error: failed to read 'tests/programs/missing.txt': ",
+        ));
+
+    // A script that is not UTF-8 cannot be read either
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["script"])
+        .write_stdin(b"add PUSH 1\n\xff\n".to_vec())
+        .assert()
+        .code(1)
+        .stdout(
+            predicate::str::contains("Instructions added.\n")
+                .and(predicate::str::contains("Error: failed to read script: ")),
+        );
 }
 
 #[test]
@@ -33,7 +57,389 @@ fn test_cli_invalid_command() {
         .assert()
         .success()
         .stdout(
-            predicate::str::contains("Invalid instruction")
-                .and(predicate::str::contains("No valid instructions provided")),
+            predicate::str::contains("error: unknown instruction `INVALID`")
+                .and(predicate::str::contains("1 | INVALID\n  | ^^^^^^^")),
+        );
+}
+
+#[test]
+fn test_cli_overflow_mode() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["--overflow", "wrapping", "script"])
+        .write_stdin("add PUSH 2147483647; PUSH 1; ADD\nrun\nback\n")
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains("All instructions executed. Stack: [-2147483648]").and(
+                predicate::str::contains("Reversed last instruction. Stack: [2147483647, 1]"),
+            ),
+        );
+
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["script", "--overflow", "saturating"])
+        .write_stdin("add PUSH 2147483647; PUSH 1; ADD\nrun\n")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "All instructions executed. Stack: [2147483647]",
+        ));
+}
+
+#[test]
+fn test_cli_memory() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["--memory-size", "4", "script"])
+        .write_stdin("add PUSH 9; PUSH 2; !\nrun\nmem 1 2\nadd PUSH 9; PUSH 4; !\nrun\n")
+        .assert()
+        .failure()
+        .stdout(
+            predicate::str::contains("     1: 0\n     2: 9").and(predicate::str::contains(
+                "Error: ! at line 4: memory address out of bounds (operands: 9, 4)",
+            )),
+        );
+}
+
+#[test]
+fn test_cli_error_reports_script_line() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["script"])
+        .write_stdin("add PUSH 10\nadd PUSH 0\nadd DIV\nrun\n")
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Error: DIV at line 3: divide by zero (10 / 0)",
+        ));
+}
+
+#[test]
+fn test_cli_run_program_file() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["run", "tests/programs/sum.txt"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Program finished. Stack: [55]"));
+}
+
+#[test]
+fn test_cli_run_program_error_reports_source_line() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["run", "tests/programs/divide_by_zero.txt"])
+        .assert()
+        .failure()
+        .stdout(
+            predicate::str::contains("Error: DIV at line 5: divide by zero (10 / 0)")
+                .and(predicate::str::contains("Stack: [10, 0]")),
+        );
+}
+
+#[test]
+fn test_cli_run_program_with_assembly_errors() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["run", "tests/programs/invalid.txt"])
+        .assert()
+        .failure()
+        .stdout(
+            predicate::str::contains("2 | PUSH one\n  |      ^^^")
+                .and(predicate::str::contains("3 | FOO\n  | ^^^"))
+                .and(predicate::str::contains("due to 2 previous error(s)")),
+        );
+}
+
+#[test]
+fn test_cli_assemble_disassemble_and_run_bytecode() {
+    let bytecode = std::env::temp_dir().join(format!("sum-{}.rsbc", std::process::id()));
+    let bytecode = bytecode.to_str().unwrap();
+
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["assemble", "tests/programs/sum.txt", "-o", bytecode])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Assembled 16 instruction(s)"));
+
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["disassemble", bytecode])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            "PUSH 0\nSTORE sum\nPUSH 10\nloop:\n    DUP\n    JZ done\n",
+        ));
+
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["run", bytecode])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Program finished. Stack: [55]"));
+
+    std::fs::remove_file(bytecode).unwrap();
+}
+
+#[test]
+fn test_cli_disassemble_rejects_program_text() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["disassemble", "tests/programs/sum.txt"])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Error: could not decode 'tests/programs/sum.txt': not a bytecode program",
+        ));
+}
+
+#[test]
+fn test_cli_format_program_file() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["format", "tests/programs/sum.txt"])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            ";; Sums the numbers from 1 to 10 into the variable `sum`.\n\n\
+             PUSH 0\nSTORE sum\nPUSH 10 # counter\n\nloop:\n    DUP\n",
+        ));
+}
+
+#[test]
+fn test_cli_shell_prints_instructions_as_source() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["script"])
+        .write_stdin("add PUSH 5; loop:; JMP loop\nforward\nqueue\n")
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains("Executed PUSH 5. Stack: [5]")
+                .and(predicate::str::contains(">    1: loop:"))
+                .and(predicate::str::contains("     2: JMP loop")),
         );
 }
+
+#[cfg(feature = "cli")]
+#[test]
+fn test_cli_shell_resumes_saved_session() {
+    let session = std::env::temp_dir().join(format!("shell-{}.json", std::process::id()));
+    let session = session.to_str().unwrap();
+
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["script"])
+        .write_stdin(format!(
+            "add PUSH 2; PUSH 3; MUL; PUSH 1\nforward\nforward\nforward\nsave {}\n",
+            session
+        ))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "Session saved to '{}'.",
+            session
+        )));
+
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["shell", "--resume", session])
+        .write_stdin("back\nqueue\nexit\n")
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains("Reversed last instruction. Stack: [2, 3]")
+                .and(predicate::str::contains(">    2: MUL\n     3: PUSH 1")),
+        );
+
+    std::fs::remove_file(session).unwrap();
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn test_cli_shell_resume_missing_session() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["shell", "--resume", "tests/programs/missing.json"])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Error: failed to read 'tests/programs/missing.json'",
+        ));
+}
+
+#[test]
+fn test_cli_shell_resume_rejects_configuration() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args([
+        "--overflow",
+        "wrapping",
+        "shell",
+        "--resume",
+        "session.json",
+    ])
+    .assert()
+    .failure()
+    .stdout(predicate::str::contains(
+        "Error: --overflow cannot be combined with --resume",
+    ));
+
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["shell", "--resume", "session.json", "--history-limit", "10"])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Error: --history-limit cannot be combined with --resume",
+        ));
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn test_cli_script_save_failure() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["script"])
+        .write_stdin("add PUSH 1\nsave tests/programs/missing/session.json\nforward\n")
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains(
+                "Error: failed to write 'tests/programs/missing/session.json': ",
+            )
+            .and(predicate::str::contains("invalid command").not())
+            .and(predicate::str::ends_with("Executed PUSH 1. Stack: [1]\n")),
+        );
+}
+
+#[test]
+fn test_cli_continue_and_reverse_continue() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["script"])
+        .write_stdin(
+            "add PUSH 2; loop:; PUSH 1; SUB; DUP; JNZ loop; PUSH 7\n\
+             break loop\nwatch top 7\ncontinue\ncontinue\ncontinue\nreverse-continue\n",
+        )
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains(
+                "Stopped at breakpoint at label loop before instruction 1. Stack: [2]\n",
+            )
+            .and(predicate::str::contains(
+                "Stopped at breakpoint at label loop before instruction 1. Stack: [1]\n",
+            ))
+            .and(predicate::str::contains(
+                "Stopped at watchpoint on top of stack 7 before instruction 7. Stack: [0, 7]\n",
+            ))
+            .and(predicate::str::ends_with(
+                "Stopped at breakpoint at label loop before instruction 1. Stack: [1]\n",
+            )),
+        );
+}
+
+#[test]
+fn test_cli_branches() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["script"])
+        .write_stdin(
+            "add PUSH 6; PUSH 2; MUL\nrun\nback\nreplace ADD\nforward\nbranches\ndiff 0 1\n",
+        )
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains(
+                "  0: initial, at 2 of 3 instruction(s), final stack [12]\n\
+                 * 1: forked from 0 after 2, at 3 of 3 instruction(s), final stack [8]\n",
+            )
+            .and(predicate::str::contains(
+                "Branches 0 and 1 share 2 executed instruction(s).\n  0: [12]\n  1: [8]\n\
+                 Final stacks first differ at index 0.\n",
+            )),
+        );
+}
+
+#[test]
+fn test_cli_history_limit() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["--history-limit", "10", "script"])
+        .write_stdin("add PUSH 100; loop:; PUSH 1; SUB; DUP; JNZ loop\nrun\nback 300\n")
+        .assert()
+        .failure()
+        .stdout(predicate::str::ends_with(
+            "All instructions executed. Stack: [0]\nError: history exhausted\n",
+        ));
+
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["--history-limit", "10x", "script"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid history limit '10x'"));
+
+    // Branch 0 diverges before the retained history and is discarded.
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["--history-limit", "1", "script"])
+        .write_stdin(
+            "add PUSH 100; loop:; PUSH 1; SUB; DUP; JNZ loop\nforward\nback\n\
+             replace PUSH 50\nrun\nback\nreplace PUSH 3\nforward\ndiff 1 2\ndiff 0 2\n",
+        )
+        .assert()
+        .failure()
+        .stdout(predicate::str::ends_with(
+            "Branches 1 and 2 share 250 executed instruction(s).\n  1: [0]\n  2: [0, 0, 3]\n\
+             Final stacks first differ at index 1.\nError: undefined branch\n",
+        ));
+}
+
+#[test]
+fn test_cli_execution_limits() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["script", "--max-steps", "1000"])
+        .write_stdin("add loop:; JMP loop\nrun\n")
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Error: LABEL at line 1: step limit exceeded",
+        ));
+
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["script", "--max-stack", "3"])
+        .write_stdin("add PUSH 1; PUSH 2; PUSH 3; PUSH 4\nrun\nstack\n")
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Error: PUSH at line 1: stack limit exceeded",
+        ));
+}
+
+#[test]
+fn test_cli_trace() {
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["trace", "tests/programs/divide_by_zero.txt"])
+        .assert()
+        .failure()
+        .stdout(
+            predicate::str::starts_with("STEP  PC  INSTRUCTION  BEFORE   AFTER\n")
+                .and(predicate::str::contains(
+                    "2     2   DIV          [10, 0]  error: divide by zero\n",
+                ))
+                .and(predicate::str::contains(
+                    "Error: DIV at line 5: divide by zero (10 / 0)",
+                )),
+        );
+
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["trace", "tests/programs/sum.txt", "--format", "csv"])
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::starts_with(
+                "step,pc,instruction,before,after,error\n0,0,PUSH 0,[],[0],\n",
+            )
+            .and(predicate::str::ends_with("108,15,LOAD sum,[],[55],\n")),
+        );
+
+    // Traces of the same program are identical
+    let trace = |format: &str| {
+        let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+        let output = cmd
+            .args(["trace", "tests/programs/sum.txt", "--format", format])
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert_eq!(trace("jsonl"), trace("jsonl"));
+    assert!(trace("jsonl").starts_with(
+        "{\"step\":0,\"pc\":0,\"instruction\":\"PUSH 0\",\"before\":[],\"after\":[0]}\n"
+    ));
+
+    let mut cmd = Command::cargo_bin("reversible_interpreter").unwrap();
+    cmd.args(["trace", "tests/programs/sum.txt", "--format", "xml"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid trace format 'xml'"));
+}